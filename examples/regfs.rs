@@ -1,12 +1,19 @@
+#[cfg(windows)]
 use std::path::{Path, PathBuf};
+#[cfg(windows)]
 use projfs::*;
+#[cfg(windows)]
 use std::sync::Mutex;
+#[cfg(windows)]
 use winreg::enums::*;
+#[cfg(windows)]
 use winreg::{RegKey, RegValue};
 
+#[cfg(windows)]
 pub struct DirInfo {
   key: Mutex<RegKey>,
 }
+#[cfg(windows)]
 impl DirInfo {
   fn new(root: &RegKey, path: PathBuf) -> std::io::Result<Self> {
     Ok(Self {
@@ -24,6 +31,7 @@ impl DirInfo {
           is_dir: true,
           created: 0, writed: 0, changed: 0, accessed: 0,
           attrs: 0,
+          content_id: None,
         }.into()
       }).collect()
   }
@@ -38,20 +46,21 @@ impl DirInfo {
           is_dir: false,
           created: 0, writed: 0, changed: 0, accessed: 0,
           attrs: 0,
+          content_id: None,
         }.into()
       }).collect()
   }
 }
 
+#[cfg(windows)]
 pub struct MyProjFS {
-  dir_enums: CacheMap<Box<dyn Iterator<Item=FileBasicInfo> + Send + Sync>>,
   reg_root: Mutex<RegKey>,
 }
+#[cfg(windows)]
 impl MyProjFS {
   fn new() -> Self {
     let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
     Self {
-      dir_enums: Default::default(),
       reg_root: Mutex::new(hklm),
     }
   }
@@ -63,21 +72,20 @@ impl MyProjFS {
   }
 }
 
+#[cfg(windows)]
 impl ProjFSDirEnum for MyProjFS {
   type DirIter = Box<dyn Iterator<Item=FileBasicInfo> + Send + Sync>;
-  fn dir_iter(&self, _id: Guid, path: RawPath, _pattern: Option<RawPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
     let dir_info = DirInfo::new(&self.reg_root.lock().unwrap(), path.into())?;
     let keys = dir_info.get_subkeys();
     let values = dir_info.get_subvalues();
     println!("found {} + {} entries", keys.len(), values.len());
     Ok(Box::new(keys.into_iter().chain(values)))
   }
-  fn dir_iter_cache(&self, _version: VersionInfo) -> &CacheMap<Self::DirIter> {
-    &self.dir_enums
-  }
 }
+#[cfg(windows)]
 impl ProjFSRead for MyProjFS {
-  fn get_metadata(&self, path: RawPath, _: VersionInfo) -> std::io::Result<FileBasicInfo> {
+  fn get_metadata(&self, path: &ProjPath, _: VersionInfo) -> std::io::Result<FileBasicInfo> {
     let path = path.to_path_buf();
     println!("read metadata {:?}", path.display());
     let root_reg = self.reg_root.lock().unwrap();
@@ -94,10 +102,11 @@ impl ProjFSRead for MyProjFS {
       is_dir: size.is_none(),
       created: 0, writed: 0, changed: 0, accessed: 0,
       attrs: 0,
+      content_id: None,
     };
     Ok(result)
   }
-  fn read(&self, path: RawPath, _: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+  fn read(&self, path: &ProjPath, _: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
     let path = path.to_path_buf();
     println!("read content {:?} {}-{}", path.display(), offset, offset + buf.len() as u64);
     if let Some(value) = Self::open_subvalue(&self.reg_root.lock().unwrap(), &path) {
@@ -109,9 +118,13 @@ impl ProjFSRead for MyProjFS {
   }
 }
 
+#[cfg(windows)]
 fn main() {
   std::fs::create_dir("test_dir").ok();
   let instance = start_proj_virtualization("test_dir", Box::new(MyProjFS::new())).unwrap();
   std::thread::sleep(std::time::Duration::from_secs(std::u64::MAX));
   drop(instance)
 }
+
+#[cfg(not(windows))]
+fn main() { }
@@ -0,0 +1,235 @@
+use std::iter::Peekable;
+use crate::{CallbackDataFlags, FileBasicInfo, PatternFilter, ProjPath, ERROR_INSUFFICIENT_BUFFER};
+use crate::pattern::SessionPattern;
+
+/// Destination of directory entries during `GetDirectoryEnumeration`,
+/// implemented by [`DirHandle`](crate::DirHandle) on Windows.
+pub trait DirEntryBuffer {
+  /// Adds one entry to the buffer.
+  ///
+  /// Fails with raw os error [`ERROR_INSUFFICIENT_BUFFER`] when the entry does not fit,
+  /// any other error is passed through to ProjFS.
+  fn fill(&mut self, entry: &FileBasicInfo) -> std::io::Result<()>;
+}
+
+/// Session state of enumerations served by [`ProjFSDirEnum`](crate::ProjFSDirEnum).
+pub struct DirEnumSession<I: Iterator<Item=FileBasicInfo>> {
+  pub(crate) iter: Option<Peekable<PatternFilter<I>>>,
+  pub(crate) pattern: SessionPattern,
+}
+
+impl<I: Iterator<Item=FileBasicInfo>> Default for DirEnumSession<I> {
+  fn default() -> Self {
+    Self { iter: None, pattern: SessionPattern::default() }
+  }
+}
+
+impl<I: Iterator<Item=FileBasicInfo>> DirEnumSession<I> {
+  /// Search expression of the session, as given on its first callback.
+  pub fn pattern(&self) -> Option<&ProjPath> {
+    self.pattern.get()
+  }
+}
+
+pub(crate) fn is_insufficient_buffer(e: &std::io::Error) -> bool {
+  e.raw_os_error() == Some(ERROR_INSUFFICIENT_BUFFER)
+}
+
+/// Moves entries from `iter` into `buffer`, returns the number of entries filled.
+///
+/// Entries which did not fit stay in `iter` for the next callback of the session.
+/// With [`CallbackDataFlags::RETURN_SINGLE_ENTRY`] at most one entry is filled.
+/// If not even the first entry fits, `ERROR_INSUFFICIENT_BUFFER` is returned as ProjFS requires,
+/// otherwise a full buffer just ends this batch.
+pub fn fill_entries<I, Iter>(iter: &mut Peekable<Iter>, buffer: &mut dyn DirEntryBuffer, flags: CallbackDataFlags) -> std::io::Result<usize>
+where I: AsRef<FileBasicInfo>, Iter: Iterator<Item=I> {
+  let mut k = 0;
+  while let Some(i) = iter.peek() {
+    match buffer.fill(i.as_ref()) {
+      Ok(()) => {},
+      Err(ref e) if k > 0 && is_insufficient_buffer(e) => break,
+      Err(e) => return Err(e),
+    }
+    k += 1;
+    iter.next();
+    if flags.contains(CallbackDataFlags::RETURN_SINGLE_ENTRY) {
+      break
+    }
+  }
+  Ok(k)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use std::path::PathBuf;
+
+  pub(crate) struct FakeBuffer {
+    capacity: usize,
+    pub names: Vec<PathBuf>,
+    error: Option<std::io::ErrorKind>,
+  }
+
+  impl FakeBuffer {
+    pub fn new(capacity: usize) -> Self {
+      Self { capacity, names: Vec::new(), error: None }
+    }
+  }
+
+  impl DirEntryBuffer for FakeBuffer {
+    fn fill(&mut self, entry: &FileBasicInfo) -> std::io::Result<()> {
+      if let Some(kind) = self.error {
+        return Err(kind.into())
+      }
+      if self.names.len() >= self.capacity {
+        return Err(std::io::Error::from_raw_os_error(ERROR_INSUFFICIENT_BUFFER))
+      }
+      self.names.push(entry.file_name.clone());
+      Ok(())
+    }
+  }
+
+  pub(crate) fn entries(names: &[&str]) -> Peekable<std::vec::IntoIter<FileBasicInfo>> {
+    names.iter().map(|name| FileBasicInfo {
+      file_name: name.into(),
+      is_dir: false,
+      file_size: 0,
+      created: 0, accessed: 0, writed: 0, changed: 0,
+      attrs: 0,
+      content_id: None,
+    }).collect::<Vec<_>>().into_iter().peekable()
+  }
+
+  #[test]
+  fn fills_until_exhausted() {
+    let mut iter = entries(&["a", "b", "c"]);
+    let mut buffer = FakeBuffer::new(10);
+    assert_eq!(fill_entries(&mut iter, &mut buffer, CallbackDataFlags::empty()).unwrap(), 3);
+    assert_eq!(buffer.names, vec![PathBuf::from("a"), "b".into(), "c".into()]);
+    assert!(iter.peek().is_none());
+  }
+
+  #[test]
+  fn empty_directory_is_not_an_error() {
+    let mut iter = entries(&[]);
+    let mut buffer = FakeBuffer::new(0);
+    assert_eq!(fill_entries(&mut iter, &mut buffer, CallbackDataFlags::empty()).unwrap(), 0);
+  }
+
+  #[test]
+  fn single_entry_mode() {
+    let mut iter = entries(&["a", "b"]);
+    let mut buffer = FakeBuffer::new(10);
+    assert_eq!(fill_entries(&mut iter, &mut buffer, CallbackDataFlags::RETURN_SINGLE_ENTRY).unwrap(), 1);
+    assert_eq!(buffer.names, vec![PathBuf::from("a")]);
+    assert_eq!(iter.peek().unwrap().file_name, PathBuf::from("b"));
+  }
+
+  #[test]
+  fn full_buffer_resumes_on_next_call() {
+    let mut iter = entries(&["a", "b", "c"]);
+    let mut buffer = FakeBuffer::new(2);
+    assert_eq!(fill_entries(&mut iter, &mut buffer, CallbackDataFlags::empty()).unwrap(), 2);
+    let mut buffer = FakeBuffer::new(2);
+    assert_eq!(fill_entries(&mut iter, &mut buffer, CallbackDataFlags::empty()).unwrap(), 1);
+    assert_eq!(buffer.names, vec![PathBuf::from("c")]);
+  }
+
+  #[test]
+  fn first_entry_not_fitting_is_insufficient_buffer() {
+    let mut iter = entries(&["a"]);
+    let mut buffer = FakeBuffer::new(0);
+    let e = fill_entries(&mut iter, &mut buffer, CallbackDataFlags::empty()).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ERROR_INSUFFICIENT_BUFFER));
+    assert_eq!(iter.peek().unwrap().file_name, PathBuf::from("a"));
+  }
+
+  #[test]
+  fn other_errors_are_passed_through() {
+    let mut iter = entries(&["a"]);
+    let mut buffer = FakeBuffer::new(10);
+    buffer.error = Some(std::io::ErrorKind::PermissionDenied);
+    let e = fill_entries(&mut iter, &mut buffer, CallbackDataFlags::empty()).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+  }
+
+  struct Listing(Vec<&'static str>, bool);
+
+  impl crate::ProjFSDirEnum for Listing {
+    type DirIter = std::vec::IntoIter<FileBasicInfo>;
+    fn dir_iter(&self, _id: crate::Guid, _path: &crate::ProjPath, _pattern: Option<&crate::ProjPath>, _version: crate::VersionInfo) -> std::io::Result<Self::DirIter> {
+      Ok(entries(&self.0).collect::<Vec<_>>().into_iter())
+    }
+    fn filters_pattern(&self) -> bool {
+      self.1
+    }
+  }
+
+  impl crate::ProjFSRead for Listing {
+    fn get_metadata(&self, _path: &crate::ProjPath, _version: crate::VersionInfo) -> std::io::Result<FileBasicInfo> {
+      Err(std::io::ErrorKind::NotFound.into())
+    }
+    fn read(&self, _path: &crate::ProjPath, _version: crate::VersionInfo, _offset: u64, _buf: &mut [u8]) -> std::io::Result<()> {
+      Err(std::io::ErrorKind::NotFound.into())
+    }
+  }
+
+  #[test]
+  fn get_dir_enum_surfaces_insufficient_buffer() {
+    use crate::ProjFS;
+    let fs = Listing(vec!["a", "b"], false);
+    let id = crate::Guid::new_v4();
+    let path = crate::ProjPath::new(&[]);
+    let mut session = fs.start_dir_enum(id, path, crate::VersionInfo::NONE).unwrap();
+    let e = fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), crate::VersionInfo::NONE, None, &mut FakeBuffer::new(0)).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ERROR_INSUFFICIENT_BUFFER));
+
+    let mut buffer = FakeBuffer::new(1);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), crate::VersionInfo::NONE, None, &mut buffer).unwrap();
+    let mut buffer = FakeBuffer::new(1);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), crate::VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, vec![PathBuf::from("b")]);
+
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::RESTART_SCAN, crate::VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, vec![PathBuf::from("a"), "b".into()]);
+  }
+
+  #[test]
+  fn get_dir_enum_filters_by_remembered_pattern() {
+    use crate::ProjFS;
+    let fs = Listing(vec!["a.txt", "b.rs", "c.TXT", "d.txt"], false);
+    let id = crate::Guid::new_v4();
+    let path = crate::ProjPathBuf::from("");
+    let path = &*path;
+    let mut session = fs.start_dir_enum(id, path, crate::VersionInfo::NONE).unwrap();
+    let pattern = crate::ProjPathBuf::from("*.txt");
+    let mut buffer = FakeBuffer::new(1);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), crate::VersionInfo::NONE, Some(&pattern), &mut buffer).unwrap();
+    assert_eq!(buffer.names, vec![PathBuf::from("a.txt")]);
+    drop(pattern);
+
+    // later callbacks of the session may come without the search expression
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), crate::VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, vec![PathBuf::from("c.TXT"), "d.txt".into()]);
+
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::RESTART_SCAN, crate::VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, vec![PathBuf::from("a.txt"), "c.TXT".into(), "d.txt".into()]);
+  }
+
+  #[test]
+  fn get_dir_enum_leaves_pattern_to_provider() {
+    use crate::ProjFS;
+    let fs = Listing(vec!["a.txt", "b.rs"], true);
+    let id = crate::Guid::new_v4();
+    let path = crate::ProjPathBuf::from("");
+    let path = &*path;
+    let mut session = fs.start_dir_enum(id, path, crate::VersionInfo::NONE).unwrap();
+    let pattern = crate::ProjPathBuf::from("*.txt");
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), crate::VersionInfo::NONE, Some(&pattern), &mut buffer).unwrap();
+    assert_eq!(buffer.names, vec![PathBuf::from("a.txt"), "b.rs".into()]);
+  }
+}
@@ -1,71 +1,129 @@
-#![cfg(windows)]
-
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(windows)]
 pub use projfs_sys as sys;
 
-pub type CacheMap<T> = chashmap::CHashMap<Guid, Option<std::iter::Peekable<T>>>;
-
-pub type VersionInfo = *const sys::PRJ_PLACEHOLDER_VERSION_INFO;
+mod path;
+pub use path::{Components, ProjPath, ProjPathBuf};
+mod session;
+pub use session::{SessionConfig, SessionInfo, Sessions};
+mod dir_enum;
+pub use dir_enum::{DirEntryBuffer, DirEnumSession, fill_entries};
+mod pattern;
+pub use pattern::{Pattern, PatternFilter};
+mod list_dir;
+pub use list_dir::{ListDirSession, Page, Paged, ProjFSListDir};
+pub mod provider;
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use windows::*;
+
+/// Length of the provider and content IDs of a placeholder.
+pub const PLACEHOLDER_ID_LENGTH: usize = 128;
+#[cfg(windows)]
+pub type PlaceholderVersionInfo = sys::PRJ_PLACEHOLDER_VERSION_INFO;
+/// Layout of `PRJ_PLACEHOLDER_VERSION_INFO` outside Windows, so providers reading versions can be tested anywhere.
+#[cfg(not(windows))]
+#[repr(C)]
+#[allow(non_snake_case)]
+#[derive(Clone, Copy)]
+pub struct PlaceholderVersionInfo {
+  pub ProviderID: [u8; PLACEHOLDER_ID_LENGTH],
+  pub ContentID: [u8; PLACEHOLDER_ID_LENGTH],
+}
+/// Version of the placeholder a callback is about, as written from [`FileBasicInfo::content_id`], borrowed for the callback.
+#[derive(Clone, Copy, Default)]
+pub struct VersionInfo<'a>(Option<&'a PlaceholderVersionInfo>);
+
+impl<'a> VersionInfo<'a> {
+  /// No version, the file is not a placeholder yet.
+  pub const NONE: Self = Self(None);
+
+  pub fn new(info: &'a PlaceholderVersionInfo) -> Self {
+    Self(Some(info))
+  }
+
+  /// Borrows the `VersionInfo` pointer of a callback.
+  ///
+  /// # Safety
+  /// `ptr` must be null or valid for `'a`.
+  pub unsafe fn from_ptr(ptr: *const PlaceholderVersionInfo) -> Self {
+    Self(ptr.as_ref())
+  }
+
+  pub fn info(self) -> Option<&'a PlaceholderVersionInfo> {
+    self.0
+  }
+
+  /// Content ID of the placeholder padded with zeros, `None` without version or if the ID is all zeros.
+  pub fn content_id(self) -> Option<&'a [u8; PLACEHOLDER_ID_LENGTH]> {
+    let id = &self.0?.ContentID;
+    if id.iter().all(|&i| i == 0) { None } else { Some(id) }
+  }
+}
+
+/// Version info carrying `content_id`, truncated to [`PLACEHOLDER_ID_LENGTH`] bytes.
+pub fn version_info(content_id: &[u8]) -> PlaceholderVersionInfo {
+  let mut info = PlaceholderVersionInfo { ProviderID: [0; PLACEHOLDER_ID_LENGTH], ContentID: [0; PLACEHOLDER_ID_LENGTH] };
+  let len = content_id.len().min(PLACEHOLDER_ID_LENGTH);
+  info.ContentID[..len].copy_from_slice(&content_id[..len]);
+  info
+}
+
+thread_local! {
+  static DATA_STREAM: std::cell::Cell<Option<Guid>> = const { std::cell::Cell::new(None) };
+}
+
+/// `DataStreamId` of the file data request [`ProjFSRead::read`] is serving on this thread, `None` outside of one.
+///
+/// Every open handle of a file reading data gets its own stream, so consecutive reads of one stream tell how a file is read.
+pub fn data_stream_id() -> Option<Guid> {
+  DATA_STREAM.with(|i| i.get())
+}
+
+/// Runs `f` as part of the file data request of stream `id`, as `GetFileDataCallback` does around [`ProjFSRead::read`].
+pub fn with_data_stream<R>(id: Guid, f: impl FnOnce() -> R) -> R {
+  let outer = DATA_STREAM.with(|i| i.replace(Some(id)));
+  struct Restore(Option<Guid>);
+  impl Drop for Restore {
+    fn drop(&mut self) {
+      DATA_STREAM.with(|i| i.set(self.0));
+    }
+  }
+  let _restore = Restore(outer);
+  f()
+}
+#[cfg(windows)]
 pub type DirHandle = sys::PRJ_DIR_ENTRY_BUFFER_HANDLE;
 pub type Guid = uuid::Uuid;
 
-extern "C" {
-  fn wcslen(ptr: *const std::os::raw::c_ushort) -> usize;
-}
+/// Win32 error reported when a directory entry does not fit in the entry buffer.
+pub const ERROR_INSUFFICIENT_BUFFER: i32 = 122;
+/// Win32 error reported for an enumeration whose session ended or expired.
+pub const ERROR_INVALID_PARAMETER: i32 = 87;
+/// Win32 error reported for [`std::io::ErrorKind::InvalidData`], e.g. corrupt archives.
+pub const ERROR_INVALID_DATA: i32 = 13;
+/// Win32 error reported for [`std::io::ErrorKind::ResourceBusy`].
+pub const ERROR_BUSY: i32 = 170;
+/// Win32 error reported for [`std::io::ErrorKind::TimedOut`].
+pub const ERROR_TIMEOUT: i32 = 1460;
+/// HRESULT reported when a provider panics in a callback.
+pub const E_UNEXPECTED: i32 = 0x8000_FFFFu32 as i32;
 
-pub struct RawPath<'a>(sys::PCWSTR, std::marker::PhantomData<&'a Path>);
-impl From<sys::PCWSTR> for RawPath<'_> {
-  fn from(raw: sys::PCWSTR) -> Self {
-    Self(raw, Default::default())
-  }
-}
-impl Into<PathBuf> for RawPath<'_> {
-  fn into(self) -> PathBuf {
-    use std::os::windows::prelude::*;
-    let ptr = unsafe { std::slice::from_raw_parts(self.0, wcslen(self.0)) };
-    std::ffi::OsString::from_wide(ptr).into()
-  }
-}
-impl<'a> RawPath<'a> {
-  pub fn to_path_buf(self) -> PathBuf {
-    self.into()
-  }
+pub fn hresult_from_win32(code: i32) -> i32 {
+  if code <= 0 { code } else { ((code as u32 & 0xFFFF) | 0x8007_0000) as i32 }
 }
 
 bitflags::bitflags! {
-pub struct CallbackDataFlags: sys::PRJ_CALLBACK_DATA_FLAGS {
-  const RESTART_SCAN = sys::PRJ_CALLBACK_DATA_FLAGS_PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN;
-  const RETURN_SINGLE_ENTRY = sys::PRJ_CALLBACK_DATA_FLAGS_PRJ_CB_DATA_FLAG_ENUM_RETURN_SINGLE_ENTRY;
-}
-}
-
-pub fn guid_from_raw(guid: sys::GUID) -> Guid {
-  Guid::from_fields(guid.Data1, guid.Data2, guid.Data3, &guid.Data4).expect("guid data4 len")
+/// `PRJ_CALLBACK_DATA_FLAGS`
+pub struct CallbackDataFlags: u32 {
+  const RESTART_SCAN = 0x1;
+  const RETURN_SINGLE_ENTRY = 0x2;
 }
-
-pub fn guid_to_raw(guid: Guid) -> sys::GUID {
-  let fields = guid.as_fields();
-  sys::GUID {
-    Data1: fields.0,
-    Data2: fields.1,
-    Data3: fields.2,
-    Data4: fields.3.clone(),
-  }
-}
-
-pub fn io_error_to_raw(e: std::io::Error) -> sys::HRESULT {
-  use std::io::ErrorKind::*;
-  if let Some(i) = e.raw_os_error() {
-    return i
-  }
-  match e.kind() {
-    WouldBlock => sys::IO_ERROR_IO_PENDING as sys::HRESULT,
-    NotFound => sys::IO_ERROR_FILE_NOT_FOUND as sys::HRESULT,
-    InvalidData => sys::IO_ERROR_INSUFFICIENT_BUFFER as sys::HRESULT,
-    _ => -1,
-  }
 }
 
+#[derive(Debug, Clone)]
 pub struct FileBasicInfo {
   pub file_name: PathBuf,
   pub is_dir: bool,
@@ -75,251 +133,87 @@ pub struct FileBasicInfo {
   pub writed: i64,
   pub changed: i64,
   pub attrs: u32,
+  /// Written as the content ID of the placeholder, and handed back to later callbacks through [`VersionInfo`].
+  pub content_id: Option<Vec<u8>>,
 }
 
-impl AsRef<FileBasicInfo> for FileBasicInfo {
-  fn as_ref(&self) -> &Self {
-    self
-  }
-}
+pub const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+pub const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+pub const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+pub const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+pub const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
 
-impl Into<sys::PRJ_FILE_BASIC_INFO> for &FileBasicInfo {
-  fn into(self) -> sys::PRJ_FILE_BASIC_INFO {
-    sys::PRJ_FILE_BASIC_INFO {
-      IsDirectory: if self.is_dir { 1 } else { 0 },
-      ChangeTime: self.changed.into(),
-      CreationTime: self.created.into(),
-      LastAccessTime: self.accessed.into(),
-      LastWriteTime: self.writed.into(),
-      FileSize: self.file_size as i64,
-      FileAttributes: self.attrs,
-    }
+/// Converts `time` to the `FILETIME` ticks the timestamps of [`FileBasicInfo`] are in,
+/// 100ns intervals since 1601-01-01.
+pub fn filetime(time: SystemTime) -> i64 {
+  const UNIX_EPOCH_TICKS: i64 = 116_444_736_000_000_000;
+  match time.duration_since(UNIX_EPOCH) {
+    Ok(d) => UNIX_EPOCH_TICKS + (d.as_nanos() / 100) as i64,
+    Err(e) => UNIX_EPOCH_TICKS - (e.duration().as_nanos() / 100) as i64,
   }
 }
 
-struct AlignedBuffer(*mut std::ffi::c_void, usize);
-impl AlignedBuffer {
-  pub fn new(context: sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, len: usize) -> Self {
-    let raw = unsafe { sys::PrjAllocateAlignedBuffer(context, len as u64) };
-    Self(raw, len)
-  }
-  pub fn as_slice_mut(&mut self) -> &mut [u8] {
-    unsafe { std::slice::from_raw_parts_mut(self.0 as *mut _, self.1) }
-  }
-}
-impl Drop for AlignedBuffer {
-  fn drop(&mut self) {
-    unsafe { sys::PrjFreeAlignedBuffer(self.0) }
+impl AsRef<FileBasicInfo> for FileBasicInfo {
+  fn as_ref(&self) -> &Self {
+    self
   }
 }
 
+/// Directory listing by iterator, the crate keeps one iterator per enumeration session.
 pub trait ProjFSDirEnum {
-  type DirIter: Iterator<Item=FileBasicInfo>;
-  fn dir_iter(&self, id: Guid, path: RawPath, pattern: Option<RawPath>, version: VersionInfo) -> std::io::Result<Self::DirIter>;
-  fn dir_iter_cache(&self, version: VersionInfo) -> &CacheMap<Self::DirIter>;
+  type DirIter: Iterator<Item=FileBasicInfo> + Send;
+  /// `pattern` is the search expression of the session, the crate drops entries not matching it
+  /// unless [`filters_pattern`](Self::filters_pattern) is overridden.
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter>;
+  /// Return true if `dir_iter` already applies `pattern` itself, e.g. by pushing it down to the backend.
+  fn filters_pattern(&self) -> bool { false }
 }
 
 pub trait ProjFSRead {
-  fn get_metadata(&self, path: RawPath, version: VersionInfo) -> std::io::Result<FileBasicInfo>;
-  fn read(&self, path: RawPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo>;
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
 }
 
 impl<T: ProjFSDirEnum + ProjFSRead> ProjFS for T {
-  fn start_dir_enum(&self, id: Guid, _path: RawPath, version: VersionInfo) -> std::io::Result<()> {
-    self.dir_iter_cache(version).insert_new(id, None); Ok(())
-  }
-  fn end_dir_enum(&self, id: Guid, version: VersionInfo) -> std::io::Result<()> {
-    self.dir_iter_cache(version).remove(&id); Ok(())
-  }
-  fn get_dir_enum(&self, id: Guid, path: RawPath, flags: CallbackDataFlags, version: VersionInfo, pattern: Option<RawPath>, handle: DirHandle) -> std::io::Result<()> {
-    let cache = self.dir_iter_cache(version);
-    let mut dir_iter = &mut cache.get_mut(&id).ok_or(std::io::ErrorKind::InvalidData)?;
-    let dir_iter: &mut Option<_> = &mut dir_iter;
-    if dir_iter.is_none() || flags.contains(CallbackDataFlags::RESTART_SCAN) {
-      dir_iter.replace(self.dir_iter(id, path, pattern, version)?.peekable());
+  type Session = DirEnumSession<T::DirIter>;
+  fn start_dir_enum(&self, _id: Guid, _path: &ProjPath, _version: VersionInfo) -> std::io::Result<Self::Session> {
+    Ok(DirEnumSession::default())
+  }
+  #[allow(clippy::too_many_arguments)]
+  fn get_dir_enum(&self, session: &mut Self::Session, id: Guid, path: &ProjPath, flags: CallbackDataFlags, version: VersionInfo, pattern: Option<&ProjPath>, buffer: &mut dyn DirEntryBuffer) -> std::io::Result<()> {
+    session.pattern.update(pattern);
+    if session.iter.is_none() || flags.contains(CallbackDataFlags::RESTART_SCAN) {
+      let filter = if self.filters_pattern() { Pattern::any() } else { session.pattern.to_pattern() };
+      let dir_iter = self.dir_iter(id, path, session.pattern.get(), version)?;
+      session.iter.replace(PatternFilter::new(dir_iter, filter).peekable());
     }
-    if let Some(ref mut dir_iter) = dir_iter {
-      Self::fill_entries(dir_iter, handle);
+    if let Some(ref mut dir_iter) = session.iter {
+      fill_entries(dir_iter, buffer, flags)?;
     }
     Ok(())
   }
 
-  fn get_metadata(&self, path: RawPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
     ProjFSRead::get_metadata(self, path, version)
   }
 
-  fn read(&self, path: RawPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
     ProjFSRead::read(self, path, version, offset, buf)
   }
 }
 
 pub trait ProjFS {
-  fn start_dir_enum(&self, id: Guid, path: RawPath, version: VersionInfo) -> std::io::Result<()>;
-  fn end_dir_enum(&self, id: Guid, version: VersionInfo) -> std::io::Result<()>;
-  fn get_dir_enum(&self, id: Guid, path: RawPath, flags: CallbackDataFlags, version: VersionInfo, pattern: Option<RawPath>, handle: DirHandle) -> std::io::Result<()>;
-
-  fn fill_entries<'a, I: AsRef<FileBasicInfo>, Iter: Iterator<Item=I>>(iter: &mut std::iter::Peekable<Iter>, handle: DirHandle) -> usize {
-    let mut k = 0;
-    while let Some(i) = iter.peek() {
-      use std::os::windows::ffi::OsStrExt;
-      let i = i.as_ref();
-      let mut basic_info = i.into();
-      let file_name: Vec<u16> = i.file_name.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
-      let hr = unsafe { sys::PrjFillDirEntryBuffer(file_name.as_ptr(), &mut basic_info, handle) };
-      if hr == 0 { k += 1; } else { return k }
-      iter.next();
-    }
-    k
-  }
+  /// State of one directory enumeration, stored in the instance's [`Sessions`]
+  /// from `start_dir_enum` until `EndDirectoryEnumeration` or until it expires.
+  type Session: Send;
+  fn start_dir_enum(&self, id: Guid, path: &ProjPath, version: VersionInfo) -> std::io::Result<Self::Session>;
+  /// Called once the session of `id` has been dropped.
+  fn end_dir_enum(&self, _id: Guid, _version: VersionInfo) -> std::io::Result<()> { Ok(()) }
+  #[allow(clippy::too_many_arguments)]
+  fn get_dir_enum(&self, session: &mut Self::Session, id: Guid, path: &ProjPath, flags: CallbackDataFlags, version: VersionInfo, pattern: Option<&ProjPath>, buffer: &mut dyn DirEntryBuffer) -> std::io::Result<()>;
 
-  fn get_metadata(&self, path: RawPath, version: VersionInfo) -> std::io::Result<FileBasicInfo>;
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo>;
 
-  fn read(&self, path: RawPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
 }
 
-mod helper {
-  #![allow(non_snake_case)]
-  use super::sys::*;
-  use super::*;
-  pub trait RawProjFS: ProjFS + Sized {
-    unsafe extern "C" fn StartDirectoryEnumerationCallback(arg1: *const PRJ_CALLBACK_DATA, arg2: *const GUID) -> HRESULT {
-      let data = arg1.as_ref().unwrap();
-      let this = (data.InstanceContext as *mut Self).as_ref().unwrap();
-      let result = this.start_dir_enum(guid_from_raw(*arg2), data.FilePathName.into(), data.VersionInfo);
-      match result {
-        Ok(()) => 0,
-        Err(e) => io_error_to_raw(e)
-      }
-      // ERROR_FILE_NOT_FOUND
-    }
-    unsafe extern "C" fn EndDirectoryEnumerationCallback(arg1: *const PRJ_CALLBACK_DATA, arg2: *const GUID) -> HRESULT {
-      let data = arg1.as_ref().unwrap();
-      let this = (data.InstanceContext as *mut Self).as_ref().unwrap();
-      let result = this.end_dir_enum(guid_from_raw(*arg2), data.VersionInfo);
-      match result {
-        Ok(()) => 0,
-        Err(e) => io_error_to_raw(e)
-      }
-    }
-    unsafe extern "C" fn GetDirectoryEnumerationCallback(
-      arg1: *const PRJ_CALLBACK_DATA,
-      arg2: *const GUID,
-      arg3: PCWSTR,
-      arg4: PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> HRESULT {
-      let data = arg1.as_ref().unwrap();
-      let this = (data.InstanceContext as *mut Self).as_ref().unwrap();
-      let result = this.get_dir_enum(
-        guid_from_raw(*arg2),
-        data.FilePathName.into(),
-        CallbackDataFlags::from_bits(data.Flags).unwrap(),
-        data.VersionInfo,
-        if arg3 == std::ptr::null() { None } else { Some(arg3.into()) },
-        arg4
-      );
-      match result {
-        Ok(()) => 0,
-        Err(e) => io_error_to_raw(e)
-      }
-      // ERROR_INSUFFICIENT_BUFFER
-    }
-    unsafe extern "C" fn GetPlaceholderInfoCallback(arg1: *const PRJ_CALLBACK_DATA) -> HRESULT {
-      let data = arg1.as_ref().unwrap();
-      let this = (data.InstanceContext as *mut Self).as_ref().unwrap();
-      match this.get_metadata(data.FilePathName.into(), data.VersionInfo) {
-        Ok(result) => {
-          let mut placeholder_info: sys::PRJ_PLACEHOLDER_INFO = std::mem::zeroed();
-          placeholder_info.FileBasicInfo = (&result).into();
-          PrjWritePlaceholderInfo(data.NamespaceVirtualizationContext, data.FilePathName, &placeholder_info, std::mem::size_of_val(&placeholder_info) as u32)
-        },
-        Err(e) => io_error_to_raw(e),
-      }
-      // ERROR_FILE_NOT_FOUND
-    }
-    unsafe extern "C" fn GetFileDataCallback(arg1: *const PRJ_CALLBACK_DATA, arg2: UINT64, arg3: UINT32) -> HRESULT {
-      let data = arg1.as_ref().unwrap();
-      let this = (data.InstanceContext as *mut Self).as_ref().unwrap();
-      let mut buf = AlignedBuffer::new(data.NamespaceVirtualizationContext, arg3 as usize);
-      let result = this.read(data.FilePathName.into(), data.VersionInfo, arg2, buf.as_slice_mut());
-      match result {
-        Ok(()) => {
-          sys::PrjWriteFileData(data.NamespaceVirtualizationContext, &data.DataStreamId, buf.0, arg2, arg3)
-        },
-        Err(e) => io_error_to_raw(e)
-      }
-      // S_OK, ERROR_IO_PENDING
-    }
-    // unsafe extern "C" fn QueryFileNameCallback(arg1: *const PRJ_CALLBACK_DATA) -> HRESULT; // ERROR_FILE_NOT_FOUND
-    // unsafe extern "C" fn NotificationCallback(
-    //   arg1: *const PRJ_CALLBACK_DATA,
-    //   arg2: BOOLEAN,
-    //   arg3: PRJ_NOTIFICATION,
-    //   arg4: PCWSTR,
-    //   arg5: *mut PRJ_NOTIFICATION_PARAMETERS,
-    // ) -> HRESULT;
-    // unsafe extern "C" fn CancelCommandCallback(arg1: *const PRJ_CALLBACK_DATA);
-  }
-  impl<T: ProjFS + Sync> RawProjFS for T { }
-}
-
-fn trait_to_table<T: helper::RawProjFS>() -> sys::PRJ_CALLBACKS {
-  let cb = sys::PRJ_CALLBACKS {
-    StartDirectoryEnumerationCallback: Some(T::StartDirectoryEnumerationCallback),
-    EndDirectoryEnumerationCallback: Some(T::EndDirectoryEnumerationCallback),
-    GetDirectoryEnumerationCallback: Some(T::GetDirectoryEnumerationCallback),
-    GetPlaceholderInfoCallback: Some(T::GetPlaceholderInfoCallback),
-    GetFileDataCallback: Some(T::GetFileDataCallback),
-    QueryFileNameCallback: None, //Some(T::QueryFileNameCallback),
-    NotificationCallback: None, //Some(T::NotificationCallback),
-    CancelCommandCallback: None, //Some(T::CancelCommandCallback),
-  };
-  cb
-}
-
-pub struct Instance<T> {
-  raw: sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
-  this: *mut T,
-  cb: sys::PRJ_CALLBACKS,
-}
-
-pub fn start_proj_virtualization<P: AsRef<Path>, T: ProjFS + Sync>(path: P, this: Box<T>) -> Result<Instance<T>, sys::HRESULT> {
-  use std::os::windows::prelude::*;
-  let mut instance = Instance {
-    raw: std::ptr::null_mut(),
-    this: Box::leak(this),
-    cb: trait_to_table::<T>()
-  };
-  let path = path.as_ref().canonicalize().unwrap();
-  let path_str: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
-  let result = unsafe {
-    // let id = uuid::Uuid::new_v5(uuid::Uuid::NAMESPACE_URL, std::slice::from_raw_parts(path_str.as_ptr(), path_str.len()*2));
-    let id = uuid::Uuid::new_v4();
-    sys::PrjMarkDirectoryAsPlaceholder(
-      path_str.as_ptr(),
-      std::ptr::null(),
-      std::ptr::null(),
-      &guid_to_raw(id),
-    );
-    sys::PrjStartVirtualizing(
-      path_str.as_ptr(),
-      &instance.cb,
-      instance.this as *const std::ffi::c_void,
-      std::ptr::null(),
-      &mut instance.raw
-    )
-  };
-  if result == 0 {
-    Ok(instance)
-  } else {
-    Err(result)
-  }
-}
-
-impl<T> Drop for Instance<T> {
-  fn drop(&mut self) {
-    unsafe { Box::from_raw(self.this) };
-    if self.raw != std::ptr::null_mut() {
-      unsafe { sys::PrjStopVirtualizing(self.raw) }
-    }
-  }
-}
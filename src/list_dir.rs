@@ -0,0 +1,213 @@
+use std::ffi::{OsStr, OsString};
+use crate::*;
+use crate::dir_enum::is_insufficient_buffer;
+use crate::pattern::SessionPattern;
+
+/// One page of a directory listing.
+pub struct Page {
+  /// Entries sorted in ProjFS collation order, all after the requested cursor.
+  pub entries: Vec<FileBasicInfo>,
+  /// Whether entries after the last one of this page remain.
+  pub more: bool,
+}
+
+/// Directory listing by name cursor, for backends with paged APIs.
+///
+/// Unlike [`ProjFSDirEnum`] the provider keeps no per-enumeration state,
+/// wrap it in [`Paged`] to get a [`ProjFS`].
+pub trait ProjFSListDir {
+  /// Lists at most `limit` entries of `path` sorting after `after`, or from the start if `after` is `None`.
+  ///
+  /// `pattern` is the search expression of the session, the crate drops entries not matching it
+  /// unless [`filters_pattern`](Self::filters_pattern) is overridden.
+  fn list_dir(&self, path: &ProjPath, pattern: Option<&ProjPath>, after: Option<&OsStr>, limit: usize, version: VersionInfo) -> std::io::Result<Page>;
+  /// Return true if `list_dir` already applies `pattern` itself.
+  fn filters_pattern(&self) -> bool { false }
+}
+
+/// Session state of enumerations served by [`ProjFSListDir`].
+pub struct ListDirSession {
+  /// Name of the last entry handed out or skipped by the pattern, the next page starts after it.
+  cursor: Option<OsString>,
+  done: bool,
+  pattern: SessionPattern,
+}
+
+impl ListDirSession {
+  fn restart(&mut self) {
+    self.cursor = None;
+    self.done = false;
+  }
+}
+
+/// [`ProjFS`] over a [`ProjFSListDir`] + [`ProjFSRead`] provider.
+pub struct Paged<T> {
+  inner: T,
+  page_size: usize,
+}
+
+impl<T> Paged<T> {
+  pub fn new(inner: T) -> Self {
+    Self::with_page_size(inner, 256)
+  }
+
+  pub fn with_page_size(inner: T, page_size: usize) -> Self {
+    Self { inner, page_size: page_size.max(1) }
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+}
+
+impl<T: ProjFSListDir + ProjFSRead> ProjFS for Paged<T> {
+  type Session = ListDirSession;
+  fn start_dir_enum(&self, _id: Guid, _path: &ProjPath, _version: VersionInfo) -> std::io::Result<Self::Session> {
+    Ok(ListDirSession { cursor: None, done: false, pattern: SessionPattern::default() })
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  fn get_dir_enum(&self, session: &mut Self::Session, _id: Guid, path: &ProjPath, flags: CallbackDataFlags, version: VersionInfo, pattern: Option<&ProjPath>, buffer: &mut dyn DirEntryBuffer) -> std::io::Result<()> {
+    session.pattern.update(pattern);
+    if flags.contains(CallbackDataFlags::RESTART_SCAN) {
+      session.restart();
+    }
+    let filter = if self.inner.filters_pattern() { Pattern::any() } else { session.pattern.to_pattern() };
+    let mut filled = 0;
+    while !session.done {
+      let page = self.inner.list_dir(path, session.pattern.get(), session.cursor.as_deref(), self.page_size, version)?;
+      let matching: Vec<_> = page.entries.iter().filter(|i| filter.matches(&i.file_name.to_string_lossy())).collect();
+      let k = match fill_entries(&mut matching.iter().peekable(), buffer, flags) {
+        Ok(k) => k,
+        // the buffer filled up right at a page boundary
+        Err(ref e) if filled > 0 && is_insufficient_buffer(e) => break,
+        Err(e) => return Err(e),
+      };
+      filled += k;
+      if k < matching.len() {
+        // the rest of the page is asked for again by the next call
+        session.cursor = Some(matching[k - 1].file_name.clone().into_os_string());
+        break
+      }
+      session.done = !page.more || page.entries.is_empty();
+      if let Some(last) = page.entries.last() {
+        session.cursor = Some(last.file_name.clone().into_os_string());
+      }
+      if k > 0 && flags.contains(CallbackDataFlags::RETURN_SINGLE_ENTRY) {
+        break
+      }
+    }
+    Ok(())
+  }
+
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.inner.get_metadata(path, version)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    self.inner.read(path, version, offset, buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+  use std::sync::Mutex;
+  use crate::dir_enum::tests::{entries, FakeBuffer};
+
+  struct Backend {
+    names: Vec<&'static str>,
+    calls: Mutex<Vec<Option<OsString>>>,
+  }
+
+  impl ProjFSListDir for Backend {
+    fn list_dir(&self, _path: &ProjPath, _pattern: Option<&ProjPath>, after: Option<&OsStr>, limit: usize, _version: VersionInfo) -> std::io::Result<Page> {
+      self.calls.lock().unwrap().push(after.map(|i| i.to_owned()));
+      let rest: Vec<_> = self.names.iter().copied().filter(|&n| after.is_none_or(|a| ProjPathBuf::from(n) > ProjPathBuf::from(a))).collect();
+      let entries: Vec<_> = entries(&rest[..rest.len().min(limit)]).collect();
+      Ok(Page { more: rest.len() > limit, entries })
+    }
+  }
+
+  impl ProjFSRead for Backend {
+    fn get_metadata(&self, _path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      Err(std::io::ErrorKind::NotFound.into())
+    }
+    fn read(&self, _path: &ProjPath, _version: VersionInfo, _offset: u64, _buf: &mut [u8]) -> std::io::Result<()> {
+      Err(std::io::ErrorKind::NotFound.into())
+    }
+  }
+
+  fn paged(names: Vec<&'static str>, page_size: usize) -> Paged<Backend> {
+    Paged::with_page_size(Backend { names, calls: Mutex::new(Vec::new()) }, page_size)
+  }
+
+  fn names(names: &[&str]) -> Vec<PathBuf> {
+    names.iter().map(PathBuf::from).collect()
+  }
+
+  #[test]
+  fn resumes_after_the_last_filled_entry() {
+    let fs = paged(vec!["a", "B", "c", "D", "e"], 2);
+    let id = Guid::new_v4();
+    let path = ProjPathBuf::from("");
+    let path = &*path;
+    let mut session = fs.start_dir_enum(id, path, VersionInfo::NONE).unwrap();
+    let mut buffer = FakeBuffer::new(3);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["a", "B", "c"]));
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["D", "e"]));
+    // "D" came with "c" but did not fit, so it is asked for again after "c"
+    let calls = fs.inner().calls.lock().unwrap().clone();
+    assert_eq!(calls, vec![None, Some("B".into()), Some("c".into())]);
+  }
+
+  #[test]
+  fn full_buffer_at_page_boundary() {
+    let fs = paged(vec!["a", "b", "c"], 2);
+    let id = Guid::new_v4();
+    let path = ProjPathBuf::from("");
+    let path = &*path;
+    let mut session = fs.start_dir_enum(id, path, VersionInfo::NONE).unwrap();
+    let mut buffer = FakeBuffer::new(2);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["a", "b"]));
+    let e = fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), VersionInfo::NONE, None, &mut FakeBuffer::new(0)).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(ERROR_INSUFFICIENT_BUFFER));
+    let mut buffer = FakeBuffer::new(1);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["c"]));
+  }
+
+  #[test]
+  fn restart_scan_clears_cursor() {
+    let fs = paged(vec!["a", "b", "c"], 2);
+    let id = Guid::new_v4();
+    let path = ProjPathBuf::from("");
+    let path = &*path;
+    let mut session = fs.start_dir_enum(id, path, VersionInfo::NONE).unwrap();
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::RETURN_SINGLE_ENTRY, VersionInfo::NONE, None, &mut buffer).unwrap();
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::RETURN_SINGLE_ENTRY, VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["a", "b"]));
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::RESTART_SCAN, VersionInfo::NONE, None, &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["a", "b", "c"]));
+  }
+
+  #[test]
+  fn pattern_spans_pages() {
+    let fs = paged(vec!["a.rs", "b.rs", "c.txt", "d.rs", "e.txt"], 2);
+    let id = Guid::new_v4();
+    let path = ProjPathBuf::from("");
+    let path = &*path;
+    let pattern = ProjPathBuf::from("*.txt");
+    let mut session = fs.start_dir_enum(id, path, VersionInfo::NONE).unwrap();
+    let mut buffer = FakeBuffer::new(10);
+    fs.get_dir_enum(&mut session, id, path, CallbackDataFlags::empty(), VersionInfo::NONE, Some(&pattern), &mut buffer).unwrap();
+    assert_eq!(buffer.names, names(&["c.txt", "e.txt"]));
+  }
+}
@@ -0,0 +1,497 @@
+use std::borrow::{Borrow, Cow};
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+/// Separator of ProjFS relative paths.
+pub const SEPARATOR: u16 = b'\\' as u16;
+
+/// Upcases one UTF-16 code unit the way ProjFS collates names,
+/// surrogates and characters without a single BMP uppercase form are kept.
+pub fn upcase(unit: u16) -> u16 {
+  let c = match char::from_u32(unit as u32) {
+    Some(c) => c,
+    None => return unit,
+  };
+  let mut upper = c.to_uppercase();
+  match (upper.next(), upper.next()) {
+    (Some(u), None) if (u as u32) <= 0xFFFF => u as u32 as u16,
+    _ => unit,
+  }
+}
+
+/// Borrowed path relative to the virtualization root, as ProjFS hands it to callbacks.
+///
+/// Paths are UTF-16 and separated by `\`, equality, ordering and hashing ignore case
+/// the way ProjFS compares file names.
+#[repr(transparent)]
+pub struct ProjPath([u16]);
+
+impl ProjPath {
+  pub fn new(units: &[u16]) -> &Self {
+    unsafe { &*(units as *const [u16] as *const Self) }
+  }
+
+  /// Borrows a nul terminated string.
+  ///
+  /// # Safety
+  /// `ptr` must be non-null, nul terminated and stay valid for `'a`.
+  pub unsafe fn from_ptr<'a>(ptr: *const u16) -> &'a Self {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+      len += 1;
+    }
+    Self::new(std::slice::from_raw_parts(ptr, len))
+  }
+
+  pub fn as_wide(&self) -> &[u16] {
+    &self.0
+  }
+
+  /// Whether this is the virtualization root.
+  pub fn is_empty(&self) -> bool {
+    self.components().next().is_none()
+  }
+
+  pub fn components(&self) -> Components<'_> {
+    Components(&self.0)
+  }
+
+  /// Last component of the path, `None` for the root.
+  pub fn file_name(&self) -> Option<&ProjPath> {
+    self.components().next_back()
+  }
+
+  /// Path without its last component, `None` for the root.
+  pub fn parent(&self) -> Option<&ProjPath> {
+    let trimmed = trim_end(&self.0);
+    if trimmed.is_empty() {
+      return None
+    }
+    let end = trimmed.iter().rposition(|&c| c == SEPARATOR).unwrap_or(0);
+    Some(ProjPath::new(trim_end(&trimmed[..end])))
+  }
+
+  /// Appends `name` after a separator, unless either side is empty.
+  pub fn join<P: AsRef<ProjPath>>(&self, name: P) -> ProjPathBuf {
+    let name = name.as_ref().as_wide();
+    let base = trim_end(&self.0);
+    let mut units = Vec::with_capacity(base.len() + name.len() + 1);
+    units.extend_from_slice(base);
+    if !base.is_empty() && !name.is_empty() {
+      units.push(SEPARATOR);
+    }
+    units.extend_from_slice(name);
+    ProjPathBuf(units)
+  }
+
+  /// Whether the leading components equal `base`'s, ignoring case.
+  pub fn starts_with<P: AsRef<ProjPath>>(&self, base: P) -> bool {
+    self.strip_prefix(base).is_some()
+  }
+
+  /// Remainder after the leading components `base`, compared ignoring case.
+  pub fn strip_prefix<P: AsRef<ProjPath>>(&self, base: P) -> Option<&ProjPath> {
+    let mut rest = self.components();
+    for component in base.as_ref().components() {
+      if rest.next()? != component {
+        return None
+      }
+    }
+    Some(ProjPath::new(trim_start(rest.0)))
+  }
+
+  pub fn to_proj_path_buf(&self) -> ProjPathBuf {
+    ProjPathBuf(self.0.to_vec())
+  }
+
+  /// Lossless on Windows, elsewhere unpaired surrogates are replaced.
+  pub fn to_os_string(&self) -> OsString {
+    #[cfg(windows)] {
+      use std::os::windows::ffi::OsStringExt;
+      OsString::from_wide(&self.0)
+    }
+    #[cfg(not(windows))] {
+      self.to_string_lossy().into_owned().into()
+    }
+  }
+
+  pub fn to_path_buf(&self) -> PathBuf {
+    self.to_os_string().into()
+  }
+
+  pub fn to_string_lossy(&self) -> Cow<'_, str> {
+    Cow::Owned(String::from_utf16_lossy(&self.0))
+  }
+
+  fn upcased(&self) -> impl Iterator<Item=u16> + '_ {
+    self.0.iter().map(|&c| upcase(c))
+  }
+}
+
+fn trim_start(units: &[u16]) -> &[u16] {
+  let start = units.iter().position(|&c| c != SEPARATOR).unwrap_or(units.len());
+  &units[start..]
+}
+
+fn trim_end(units: &[u16]) -> &[u16] {
+  let end = units.iter().rposition(|&c| c != SEPARATOR).map_or(0, |i| i + 1);
+  &units[..end]
+}
+
+/// Components of a [`ProjPath`], borrowed from it.
+#[derive(Clone)]
+pub struct Components<'a>(&'a [u16]);
+
+impl<'a> Iterator for Components<'a> {
+  type Item = &'a ProjPath;
+  fn next(&mut self) -> Option<Self::Item> {
+    let units = trim_start(self.0);
+    if units.is_empty() {
+      self.0 = units;
+      return None
+    }
+    let end = units.iter().position(|&c| c == SEPARATOR).unwrap_or(units.len());
+    self.0 = &units[end..];
+    Some(ProjPath::new(&units[..end]))
+  }
+}
+
+impl DoubleEndedIterator for Components<'_> {
+  fn next_back(&mut self) -> Option<Self::Item> {
+    let units = trim_end(self.0);
+    if units.is_empty() {
+      self.0 = units;
+      return None
+    }
+    let start = units.iter().rposition(|&c| c == SEPARATOR).map_or(0, |i| i + 1);
+    self.0 = &units[..start];
+    Some(ProjPath::new(&units[start..]))
+  }
+}
+
+impl PartialEq for ProjPath {
+  fn eq(&self, other: &Self) -> bool {
+    self.0.len() == other.0.len() && self.upcased().eq(other.upcased())
+  }
+}
+impl Eq for ProjPath { }
+
+impl PartialOrd for ProjPath {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+impl Ord for ProjPath {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    self.upcased().cmp(other.upcased())
+  }
+}
+
+impl Hash for ProjPath {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    for c in self.upcased() {
+      state.write_u16(c);
+    }
+    state.write_usize(self.0.len());
+  }
+}
+
+impl std::fmt::Debug for ProjPath {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Debug::fmt(&self.to_string_lossy(), f)
+  }
+}
+
+impl std::fmt::Display for ProjPath {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Display::fmt(&self.to_string_lossy(), f)
+  }
+}
+
+impl AsRef<ProjPath> for ProjPath {
+  fn as_ref(&self) -> &ProjPath {
+    self
+  }
+}
+
+impl ToOwned for ProjPath {
+  type Owned = ProjPathBuf;
+  fn to_owned(&self) -> ProjPathBuf {
+    self.to_proj_path_buf()
+  }
+}
+
+impl From<&ProjPath> for OsString {
+  fn from(path: &ProjPath) -> Self {
+    path.to_os_string()
+  }
+}
+
+impl From<&ProjPath> for PathBuf {
+  fn from(path: &ProjPath) -> Self {
+    path.to_path_buf()
+  }
+}
+
+impl std::convert::TryFrom<&ProjPath> for String {
+  type Error = std::string::FromUtf16Error;
+  fn try_from(path: &ProjPath) -> Result<Self, Self::Error> {
+    String::from_utf16(&path.0)
+  }
+}
+
+/// Owned [`ProjPath`].
+#[derive(Clone, Default)]
+pub struct ProjPathBuf(Vec<u16>);
+
+impl PartialEq for ProjPathBuf {
+  fn eq(&self, other: &Self) -> bool {
+    self.as_proj_path() == other.as_proj_path()
+  }
+}
+impl Eq for ProjPathBuf { }
+
+impl PartialOrd for ProjPathBuf {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+impl Ord for ProjPathBuf {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    self.as_proj_path().cmp(other.as_proj_path())
+  }
+}
+
+impl Hash for ProjPathBuf {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.as_proj_path().hash(state)
+  }
+}
+
+impl ProjPathBuf {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn from_wide(units: Vec<u16>) -> Self {
+    Self(units)
+  }
+
+  pub fn as_proj_path(&self) -> &ProjPath {
+    ProjPath::new(&self.0)
+  }
+
+  pub fn push<P: AsRef<ProjPath>>(&mut self, name: P) {
+    *self = self.join(name);
+  }
+
+  pub fn into_wide(self) -> Vec<u16> {
+    self.0
+  }
+}
+
+impl std::ops::Deref for ProjPathBuf {
+  type Target = ProjPath;
+  fn deref(&self) -> &ProjPath {
+    self.as_proj_path()
+  }
+}
+
+impl AsRef<ProjPath> for ProjPathBuf {
+  fn as_ref(&self) -> &ProjPath {
+    self
+  }
+}
+
+impl Borrow<ProjPath> for ProjPathBuf {
+  fn borrow(&self) -> &ProjPath {
+    self
+  }
+}
+
+impl std::fmt::Debug for ProjPathBuf {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Debug::fmt(self.as_proj_path(), f)
+  }
+}
+
+impl std::fmt::Display for ProjPathBuf {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Display::fmt(self.as_proj_path(), f)
+  }
+}
+
+impl From<&str> for ProjPathBuf {
+  fn from(s: &str) -> Self {
+    Self(s.encode_utf16().collect())
+  }
+}
+
+impl From<String> for ProjPathBuf {
+  fn from(s: String) -> Self {
+    s.as_str().into()
+  }
+}
+
+/// Lossless on Windows, elsewhere non UTF-8 names are replaced lossily.
+impl From<&OsStr> for ProjPathBuf {
+  fn from(s: &OsStr) -> Self {
+    #[cfg(windows)] {
+      use std::os::windows::ffi::OsStrExt;
+      Self(s.encode_wide().collect())
+    }
+    #[cfg(not(windows))] {
+      s.to_string_lossy().as_ref().into()
+    }
+  }
+}
+
+impl From<&std::path::Path> for ProjPathBuf {
+  fn from(path: &std::path::Path) -> Self {
+    let mut result = ProjPathBuf::new();
+    for component in path.components() {
+      if let std::path::Component::Normal(name) = component {
+        result.push(ProjPathBuf::from(name));
+      }
+    }
+    result
+  }
+}
+
+impl From<&ProjPath> for ProjPathBuf {
+  fn from(path: &ProjPath) -> Self {
+    path.to_proj_path_buf()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashSet;
+  use std::convert::TryFrom;
+
+  fn path(s: &str) -> ProjPathBuf {
+    s.into()
+  }
+
+  fn strings<'a, I: Iterator<Item=&'a ProjPath>>(iter: I) -> Vec<String> {
+    iter.map(|i| i.to_string_lossy().into_owned()).collect()
+  }
+
+  #[test]
+  fn from_ptr_stops_at_nul() {
+    let units: Vec<u16> = "a\\b\0junk".encode_utf16().collect();
+    let p = unsafe { ProjPath::from_ptr(units.as_ptr()) };
+    assert_eq!(p.as_wide(), &units[..3]);
+    let empty = [0u16];
+    assert!(unsafe { ProjPath::from_ptr(empty.as_ptr()) }.is_empty());
+  }
+
+  #[test]
+  fn components_borrow_the_path() {
+    let p = path("dir\\sub\\\\file.txt\\");
+    assert_eq!(strings(p.components()), vec!["dir", "sub", "file.txt"]);
+    assert_eq!(strings(p.components().rev()), vec!["file.txt", "sub", "dir"]);
+    let first = p.components().next().unwrap();
+    assert_eq!(first.as_wide().as_ptr(), p.as_wide().as_ptr());
+    assert_eq!(p.components().count(), 3);
+    assert_eq!(path("").components().count(), 0);
+    assert_eq!(path("\\").components().count(), 0);
+  }
+
+  #[test]
+  fn components_from_both_ends() {
+    let p = path("a\\b\\c");
+    let mut components = p.components();
+    assert_eq!(components.next().unwrap(), &*path("a"));
+    assert_eq!(components.next_back().unwrap(), &*path("c"));
+    assert_eq!(components.next().unwrap(), &*path("b"));
+    assert!(components.next_back().is_none());
+    assert!(components.next().is_none());
+  }
+
+  #[test]
+  fn parent_and_file_name() {
+    let p = path("dir\\sub\\file.txt");
+    assert_eq!(p.file_name().unwrap().to_string_lossy(), "file.txt");
+    assert_eq!(p.parent().unwrap().to_string_lossy(), "dir\\sub");
+    assert_eq!(p.parent().unwrap().parent().unwrap().to_string_lossy(), "dir");
+    let top = path("dir");
+    assert_eq!(top.file_name().unwrap().to_string_lossy(), "dir");
+    assert!(top.parent().unwrap().is_empty());
+    assert!(path("").parent().is_none());
+    assert!(path("").file_name().is_none());
+    assert_eq!(path("dir\\sub\\").parent().unwrap().to_string_lossy(), "dir");
+  }
+
+  #[test]
+  fn join_and_strip_prefix() {
+    assert_eq!(path("").join(path("a")).to_string_lossy(), "a");
+    assert_eq!(path("a").join(path("b")).to_string_lossy(), "a\\b");
+    assert_eq!(path("a\\").join(path("b")).to_string_lossy(), "a\\b");
+    assert_eq!(path("a").join(path("")).to_string_lossy(), "a");
+    let mut p = path("a");
+    p.push(path("b"));
+    assert_eq!(p.to_string_lossy(), "a\\b");
+
+    let p = path("Build\\Out\\x.o");
+    assert_eq!(p.strip_prefix(path("build")).unwrap().to_string_lossy(), "Out\\x.o");
+    assert_eq!(p.strip_prefix(path("build\\out")).unwrap().to_string_lossy(), "x.o");
+    assert!(p.strip_prefix(path("Build\\Out\\x.o")).unwrap().is_empty());
+    assert!(p.strip_prefix(path("")).unwrap() == &*p);
+    assert!(p.strip_prefix(path("buil")).is_none());
+    assert!(p.starts_with(path("BUILD")));
+    assert!(!p.starts_with(path("out")));
+  }
+
+  #[test]
+  fn equality_ignores_case() {
+    assert_eq!(path("Dir\\File.TXT"), path("dir\\file.txt"));
+    assert_eq!(&*path("ÄÖ"), &*path("äö"));
+    assert_ne!(path("a"), path("ab"));
+    assert_ne!(path("a\\b"), path("a\\c"));
+  }
+
+  #[test]
+  fn hashing_matches_equality() {
+    let mut set = HashSet::new();
+    set.insert(path("README.md"));
+    assert!(set.contains(&*path("readme.MD")));
+    assert!(!set.insert(path("Readme.md")));
+    assert!(!set.contains(&*path("readme")));
+  }
+
+  #[test]
+  fn ordering_ignores_case() {
+    let mut names = [path("b"), path("A"), path("_"), path("c"), path("a1")];
+    names.sort();
+    // `_` sorts after letters once they are upcased, as in ProjFS collation
+    assert_eq!(strings(names.iter().map(|i| &**i)), vec!["A", "a1", "b", "c", "_"]);
+  }
+
+  #[test]
+  fn upcase_keeps_surrogates() {
+    assert_eq!(upcase(b'a' as u16), b'A' as u16);
+    assert_eq!(upcase(0xD83D), 0xD83D);
+    // 'ß' upcases to "SS", which is not a single unit
+    assert_eq!(upcase(0xDF), 0xDF);
+  }
+
+  #[test]
+  fn conversions() {
+    let p = path("dir\\naïve 🦀.txt");
+    assert_eq!(String::try_from(&*p).unwrap(), "dir\\naïve 🦀.txt");
+    assert_eq!(p.to_os_string(), OsString::from("dir\\naïve 🦀.txt"));
+    assert_eq!(ProjPathBuf::from(p.to_os_string().as_os_str()), p);
+    assert_eq!(format!("{}", p), "dir\\naïve 🦀.txt");
+    assert_eq!(format!("{:?}", p), "\"dir\\\\naïve 🦀.txt\"");
+
+    let unpaired = ProjPathBuf::from_wide(vec![b'a' as u16, 0xD800]);
+    assert!(String::try_from(&*unpaired).is_err());
+    assert_eq!(unpaired.to_string_lossy(), "a\u{FFFD}");
+
+    let from_path = ProjPathBuf::from(std::path::Path::new("dir/sub/file"));
+    assert_eq!(from_path.to_string_lossy(), "dir\\sub\\file");
+  }
+}
@@ -0,0 +1,196 @@
+use crate::{FileBasicInfo, ProjPath, ProjPathBuf};
+
+/// Search expression of a directory enumeration, matched with the rules of `PrjFileNameMatch`.
+///
+/// Besides `*` and `?` this understands the DOS wildcards `<`, `>` and `"`,
+/// comparison is case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(Option<Vec<char>>);
+
+const DOS_STAR: char = '<';
+const DOS_QM: char = '>';
+const DOS_DOT: char = '"';
+
+fn upcase(c: char) -> char {
+  let mut upper = c.to_uppercase();
+  match (upper.next(), upper.next()) {
+    (Some(u), None) => u,
+    _ => c,
+  }
+}
+
+impl Pattern {
+  pub fn new(pattern: &str) -> Self {
+    if pattern.is_empty() || pattern == "*" {
+      Self(None)
+    } else {
+      Self(Some(pattern.chars().map(upcase).collect()))
+    }
+  }
+
+  /// Pattern matching every name.
+  pub fn any() -> Self {
+    Self(None)
+  }
+
+  pub fn is_any(&self) -> bool {
+    self.0.is_none()
+  }
+
+  pub fn matches(&self, name: &str) -> bool {
+    let pattern = match &self.0 {
+      Some(pattern) => pattern,
+      None => return true,
+    };
+    let name: Vec<char> = name.chars().map(upcase).collect();
+    let last_dot = name.iter().rposition(|&c| c == '.');
+    let mut memo = vec![None; (pattern.len() + 1) * (name.len() + 1)];
+    Matcher { pattern, name: &name, last_dot, memo: &mut memo }.matches(0, 0)
+  }
+}
+
+struct Matcher<'a> {
+  pattern: &'a [char],
+  name: &'a [char],
+  last_dot: Option<usize>,
+  memo: &'a mut [Option<bool>],
+}
+
+impl Matcher<'_> {
+  fn matches(&mut self, p: usize, n: usize) -> bool {
+    let key = p * (self.name.len() + 1) + n;
+    if let Some(result) = self.memo[key] {
+      return result
+    }
+    let result = self.matches_uncached(p, n);
+    self.memo[key] = Some(result);
+    result
+  }
+
+  fn matches_uncached(&mut self, p: usize, n: usize) -> bool {
+    let end = n == self.name.len();
+    let c = match self.pattern.get(p) {
+      Some(&c) => c,
+      None => return end,
+    };
+    match c {
+      '*' => self.matches(p + 1, n) || (!end && self.matches(p, n + 1)),
+      '?' => !end && self.matches(p + 1, n + 1),
+      // any single character, but matches nothing at a period or at the end of the name
+      DOS_QM => {
+        if end || self.name[n] == '.' {
+          let next = p + self.pattern[p..].iter().take_while(|&&c| c == DOS_QM).count();
+          self.matches(next, n)
+        } else {
+          self.matches(p + 1, n + 1)
+        }
+      },
+      // zero or more characters up to the final period
+      DOS_STAR => self.matches(p + 1, n) || (!end && Some(n) != self.last_dot && self.matches(p, n + 1)),
+      // a period, or nothing at the end of the name
+      DOS_DOT => {
+        if end {
+          self.matches(p + 1, n)
+        } else {
+          self.name[n] == '.' && self.matches(p + 1, n + 1)
+        }
+      },
+      c => !end && self.name[n] == c && self.matches(p + 1, n + 1),
+    }
+  }
+}
+
+/// Search expression of an enumeration session.
+///
+/// ProjFS passes it on the first callback of a session and may omit it on later ones.
+#[derive(Default)]
+pub(crate) struct SessionPattern(Option<ProjPathBuf>);
+
+impl SessionPattern {
+  pub fn update(&mut self, pattern: Option<&ProjPath>) {
+    if let Some(pattern) = pattern {
+      self.0 = Some(pattern.to_owned());
+    }
+  }
+
+  pub fn get(&self) -> Option<&ProjPath> {
+    self.0.as_deref()
+  }
+
+  pub fn to_pattern(&self) -> Pattern {
+    match &self.0 {
+      Some(p) => Pattern::new(&p.to_string_lossy()),
+      None => Pattern::any(),
+    }
+  }
+}
+
+/// Iterator adaptor dropping entries whose name does not match a [`Pattern`].
+pub struct PatternFilter<I> {
+  iter: I,
+  pattern: Pattern,
+}
+
+impl<I> PatternFilter<I> {
+  pub fn new(iter: I, pattern: Pattern) -> Self {
+    Self { iter, pattern }
+  }
+}
+
+impl<I: Iterator<Item=FileBasicInfo>> Iterator for PatternFilter<I> {
+  type Item = FileBasicInfo;
+  fn next(&mut self) -> Option<Self::Item> {
+    let pattern = &self.pattern;
+    if pattern.is_any() {
+      return self.iter.next()
+    }
+    self.iter.find(|i| pattern.matches(&i.file_name.to_string_lossy()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Pattern;
+
+  fn matches(pattern: &str, name: &str) -> bool {
+    Pattern::new(pattern).matches(name)
+  }
+
+  #[test]
+  fn star_and_question_mark() {
+    assert!(matches("*", "anything.txt"));
+    assert!(matches("", "anything.txt"));
+    assert!(matches("*.txt", "notes.txt"));
+    assert!(matches("*.txt", ".txt"));
+    assert!(!matches("*.txt", "notes.txt.bak"));
+    assert!(matches("a?c", "abc"));
+    assert!(!matches("a?c", "ac"));
+    assert!(matches("a*b*c", "aXXbYYc"));
+    assert!(!matches("a*b*c", "aXXbYY"));
+  }
+
+  #[test]
+  fn case_insensitive() {
+    assert!(matches("*.TXT", "notes.txt"));
+    assert!(matches("readme", "README"));
+    assert!(matches("ÄBC", "äbc"));
+  }
+
+  #[test]
+  fn dos_wildcards() {
+    // `<` stops before the final period
+    assert!(matches("<.txt", "a.b.txt"));
+    assert!(!matches("<", "a.txt"));
+    assert!(matches("<", "abc"));
+    // `>` matches nothing at a period or at the end
+    assert!(matches(">>>.txt", "a.txt"));
+    assert!(matches("abc>>", "abc"));
+    assert!(!matches("a>c", "a.c"));
+    // `"` matches a period, or nothing at the end
+    assert!(matches("abc\"", "abc"));
+    assert!(matches("abc\"txt", "abc.txt"));
+    // what `dir *.` sends to list names without extension
+    assert!(matches("<\"", "abc"));
+    assert!(!matches("<\"", "abc.txt"));
+  }
+}
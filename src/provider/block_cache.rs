@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{check_range, trim_id};
+
+/// Version of a file the cached blocks belong to.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Version {
+  /// Content ID without its zero padding.
+  Id(Vec<u8>),
+  /// Size and write times, for files without content ID.
+  Stamp(u64, i64, i64),
+}
+
+fn version_of(info: &FileBasicInfo) -> Version {
+  match &info.content_id {
+    Some(id) if id.iter().any(|&i| i != 0) => Version::Id(trim_id(id)),
+    _ => Version::Stamp(info.file_size, info.writed, info.changed),
+  }
+}
+
+type Key = (ProjPathBuf, Version, u64);
+
+#[derive(Default)]
+struct Blocks {
+  /// Block data and its last use.
+  map: HashMap<Key, (Arc<[u8]>, u64)>,
+  /// Keys by last use, least recently used first.
+  order: BTreeMap<u64, Key>,
+  bytes: usize,
+  tick: u64,
+}
+
+impl Blocks {
+  fn get(&mut self, key: &Key) -> Option<Arc<[u8]>> {
+    self.tick += 1;
+    let (data, used) = self.map.get_mut(key)?;
+    let key = self.order.remove(used).unwrap();
+    *used = self.tick;
+    self.order.insert(self.tick, key);
+    Some(data.clone())
+  }
+
+  fn insert(&mut self, key: Key, data: Arc<[u8]>, budget: usize) {
+    if data.len() > budget || self.map.contains_key(&key) {
+      return
+    }
+    self.tick += 1;
+    self.bytes += data.len();
+    self.order.insert(self.tick, key.clone());
+    self.map.insert(key, (data, self.tick));
+    while self.bytes > budget {
+      let (_, key) = self.order.pop_first().unwrap();
+      let (data, _) = self.map.remove(&key).unwrap();
+      self.bytes -= data.len();
+    }
+  }
+}
+
+/// Decorator caching the file data of a provider in memory, as aligned blocks.
+///
+/// Reads are served from whole blocks of `block_size` bytes, missing ones are fetched from the inner provider,
+/// runs of them in a single read. Blocks are kept within `budget` bytes, least recently used blocks go first.
+///
+/// Blocks are keyed by the placeholder content ID, or by the content ID, size and write times of the current
+/// metadata if the placeholder has none, so data of another version of a file is never served.
+/// Without placeholder content ID every read asks the inner provider for the metadata.
+pub struct BlockCache<T> {
+  inner: T,
+  block_size: u64,
+  budget: usize,
+  blocks: Mutex<Blocks>,
+}
+
+impl<T> BlockCache<T> {
+  /// Cache of 64 KiB blocks within 64 MiB.
+  pub fn new(inner: T) -> Self {
+    Self::with_sizes(inner, 64 << 10, 64 << 20)
+  }
+
+  pub fn with_sizes(inner: T, block_size: usize, budget: usize) -> Self {
+    Self { inner, block_size: block_size.max(1) as u64, budget, blocks: Mutex::new(Blocks::default()) }
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  /// Bytes of file data held.
+  pub fn cached_bytes(&self) -> usize {
+    self.blocks.lock().unwrap().bytes
+  }
+
+  /// Drops every cached block.
+  pub fn clear(&self) {
+    *self.blocks.lock().unwrap() = Blocks::default();
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for BlockCache<T> {
+  type DirIter = T::DirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.inner.dir_iter(id, path, pattern, version)
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for BlockCache<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.inner.get_metadata(path, version)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let end = offset.checked_add(buf.len() as u64).ok_or(std::io::ErrorKind::InvalidInput)?;
+    if buf.is_empty() || self.block_size as usize > self.budget {
+      return self.inner.read(path, version, offset, buf)
+    }
+    let placeholder = version.content_id().map(|id| Version::Id(trim_id(id)));
+    let mut info = None;
+    let key_version = match placeholder {
+      Some(v) => v,
+      None => version_of(info.insert(self.inner.get_metadata(path, version)?)),
+    };
+    let first = offset / self.block_size;
+    let last = (end - 1) / self.block_size;
+    let mut found: Vec<Option<Arc<[u8]>>> = {
+      let mut blocks = self.blocks.lock().unwrap();
+      (first..=last).map(|i| blocks.get(&(path.to_owned(), key_version.clone(), i))).collect()
+    };
+    if found.iter().any(Option::is_none) {
+      let info = match info {
+        Some(info) => info,
+        None => self.inner.get_metadata(path, version)?,
+      };
+      if version_of(&info) != key_version {
+        // the placeholder is of another version, leave it to the inner provider
+        return self.inner.read(path, version, offset, buf)
+      }
+      check_range(info.file_size, offset, buf.len())?;
+      let mut i = 0;
+      while i < found.len() {
+        if found[i].is_some() {
+          i += 1;
+          continue
+        }
+        let run = found[i..].iter().take_while(|b| b.is_none()).count();
+        let start = (first + i as u64) * self.block_size;
+        let run_end = ((first + (i + run) as u64) * self.block_size).min(info.file_size);
+        let mut data = vec![0; (run_end - start) as usize];
+        self.inner.read(path, version, start, &mut data)?;
+        let mut blocks = self.blocks.lock().unwrap();
+        for (j, chunk) in data.chunks(self.block_size as usize).enumerate() {
+          let block: Arc<[u8]> = chunk.into();
+          blocks.insert((path.to_owned(), key_version.clone(), first + (i + j) as u64), block.clone(), self.budget);
+          found[i + j] = Some(block);
+        }
+        i += run;
+      }
+    }
+    for (i, block) in found.into_iter().enumerate() {
+      let block = block.unwrap();
+      let start = (first + i as u64) * self.block_size;
+      // a short block ends the file
+      if (block.len() as u64) < self.block_size {
+        check_range(start + block.len() as u64, offset, buf.len())?;
+      }
+      let (from, to) = (offset.max(start), end.min(start + block.len() as u64));
+      buf[(from - offset) as usize..(to - offset) as usize].copy_from_slice(&block[(from - start) as usize..(to - start) as usize]);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::{CallKind, MemFs};
+  use crate::provider::testing::*;
+
+  fn cache(block_size: usize, budget: usize) -> BlockCache<Counting<Tree>> {
+    BlockCache::with_sizes(Counting::new(Tree::new(&[("file", "0123456789abcdefghij")])), block_size, budget)
+  }
+
+  fn read(fs: &impl ProjFSRead, offset: u64, len: usize) -> std::io::Result<String> {
+    let mut buf = vec![0; len];
+    fs.read(&ProjPathBuf::from("file"), VersionInfo::NONE, offset, &mut buf)?;
+    Ok(String::from_utf8(buf).unwrap())
+  }
+
+  #[test]
+  fn serves_overlapping_reads_from_blocks() {
+    let fs = cache(8, 1024);
+    assert_eq!(read(&fs, 3, 7).unwrap(), "3456789");
+    assert_eq!(fs.inner().calls(CallKind::Read), 1);
+    assert_eq!(read(&fs, 0, 10).unwrap(), "0123456789");
+    assert_eq!(read(&fs, 5, 2).unwrap(), "56");
+    assert_eq!(fs.inner().calls(CallKind::Read), 1);
+    // the last block is short, the file ends within it
+    assert_eq!(read(&fs, 14, 6).unwrap(), "efghij");
+    assert_eq!(read(&fs, 0, 20).unwrap(), "0123456789abcdefghij");
+    assert_eq!(fs.inner().calls(CallKind::Read), 2);
+    assert_eq!(fs.cached_bytes(), 20);
+    assert_eq!(read(&fs, 16, 5).err().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+    assert_eq!(read(&fs, 24, 1).err().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn evicts_least_recently_used_blocks() {
+    let fs = cache(4, 8);
+    read(&fs, 0, 4).unwrap();
+    read(&fs, 4, 4).unwrap();
+    read(&fs, 0, 1).unwrap();
+    read(&fs, 8, 4).unwrap();
+    assert_eq!(fs.cached_bytes(), 8);
+    assert_eq!(fs.inner().calls(CallKind::Read), 3);
+    // block 1 went, blocks 0 and 2 stayed
+    read(&fs, 0, 4).unwrap();
+    read(&fs, 8, 4).unwrap();
+    assert_eq!(fs.inner().calls(CallKind::Read), 3);
+    assert_eq!(read(&fs, 4, 4).unwrap(), "4567");
+    assert_eq!(fs.inner().calls(CallKind::Read), 4);
+    fs.clear();
+    assert_eq!(fs.cached_bytes(), 0);
+  }
+
+  #[test]
+  fn never_serves_another_version() {
+    let fs = BlockCache::with_sizes(MemFs::new(), 4, 1024);
+    let path = ProjPathBuf::from("file");
+    fs.inner().add_file(&path, "old contents").unwrap();
+    let old = crate::version_info(fs.get_metadata(&path, VersionInfo::NONE).unwrap().content_id.as_ref().unwrap());
+    assert_eq!(read(&fs, 0, 3).unwrap(), "old");
+    let mut buf = [0; 3];
+    fs.read(&path, VersionInfo::new(&old), 0, &mut buf).unwrap();
+    fs.inner().overwrite(&path, "new contents").unwrap();
+    // without placeholder version the current metadata decides
+    assert_eq!(read(&fs, 0, 3).unwrap(), "new");
+    let new = crate::version_info(fs.get_metadata(&path, VersionInfo::NONE).unwrap().content_id.as_ref().unwrap());
+    fs.read(&path, VersionInfo::new(&new), 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"new");
+    // cached blocks of the old version still serve its placeholder, others are left to the provider
+    fs.read(&path, VersionInfo::new(&old), 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"old");
+    assert!(fs.read(&path, VersionInfo::new(&old), 8, &mut buf).is_err());
+  }
+}
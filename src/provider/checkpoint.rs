@@ -0,0 +1,112 @@
+use std::io::Read;
+use std::sync::Mutex;
+
+pub(crate) type Stream = Box<dyn Read + Send>;
+
+/// Recorded state of a decompressor, resumed from as often as needed.
+#[cfg(feature = "tar")]
+pub(crate) trait Resume: Send + Sync {
+  fn resume(&self) -> std::io::Result<Stream>;
+}
+
+/// Decompressed stream telling where it can be resumed from.
+#[cfg(feature = "tar")]
+pub(crate) trait Resumable: Read + Send {
+  /// Last position at or before the current one the stream can be resumed from.
+  fn checkpoint(&self) -> Option<(u64, Box<dyn Resume>)>;
+}
+
+/// Reader recording where its stream can be resumed from every `interval` bytes, as far as the stream allows.
+#[cfg(feature = "tar")]
+pub(crate) struct Recorder {
+  stream: Box<dyn Resumable>,
+  interval: u64,
+  position: u64,
+  /// Position the next point is recorded at.
+  due: u64,
+  points: Vec<(u64, Box<dyn Resume>)>,
+}
+
+#[cfg(feature = "tar")]
+impl Recorder {
+  pub fn new(stream: Box<dyn Resumable>, interval: u64) -> Self {
+    let mut this = Self { stream, interval: interval.max(1), position: 0, due: 0, points: Vec::new() };
+    this.record();
+    this
+  }
+
+  fn record(&mut self) {
+    if let Some((at, point)) = self.stream.checkpoint() {
+      if self.points.last().is_none_or(|(last, _)| *last < at) {
+        self.points.push((at, point));
+      }
+    }
+    self.due = self.position + self.interval;
+  }
+
+  /// Recorded points by position.
+  pub fn into_points(self) -> Vec<(u64, Box<dyn Resume>)> {
+    self.points
+  }
+}
+
+#[cfg(feature = "tar")]
+impl Read for Recorder {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = buf.len().min((self.due - self.position) as usize);
+    let n = self.stream.read(&mut buf[..len])?;
+    self.position += n as u64;
+    if self.position == self.due {
+      self.record();
+    }
+    Ok(n)
+  }
+}
+
+/// Decompressors left positioned after a read, so reading on from there
+/// does not decompress the stream from its beginning again.
+pub(crate) struct Checkpoints<K> {
+  limit: usize,
+  /// Stream key, position in the decompressed stream and the decompressor, most recently used last.
+  entries: Mutex<Vec<(K, u64, Stream)>>,
+}
+
+impl<K: PartialEq> Checkpoints<K> {
+  pub fn new(limit: usize) -> Self {
+    Self { limit: limit.max(1), entries: Mutex::new(Vec::new()) }
+  }
+
+  /// Fills `buf` from `position` of the stream `key`, resuming the nearest decompressor at or before `position`,
+  /// or the stream `open` returns positioned at `start` if none is past it.
+  pub fn read<F: FnOnce() -> std::io::Result<Stream>>(&self, key: K, position: u64, buf: &mut [u8], start: u64, open: F) -> std::io::Result<()> {
+    let nearest = {
+      let mut entries = self.entries.lock().unwrap();
+      let nearest = entries.iter().enumerate()
+        .filter(|(_, (k, p, _))| *k == key && *p <= position && *p >= start)
+        .max_by_key(|(_, (_, p, _))| *p)
+        .map(|(i, _)| i);
+      nearest.map(|i| entries.remove(i))
+    };
+    // decompressors are used outside the lock, a failed one is dropped
+    let (start, mut stream) = match nearest {
+      Some((_, start, stream)) => (start, stream),
+      None => (start, open()?),
+    };
+    let skip = position - start;
+    if std::io::copy(&mut (&mut stream).take(skip), &mut std::io::sink())? < skip {
+      return Err(std::io::ErrorKind::UnexpectedEof.into())
+    }
+    stream.read_exact(buf)?;
+    let mut entries = self.entries.lock().unwrap();
+    if entries.len() >= self.limit {
+      entries.remove(0);
+    }
+    entries.push((key, position + buf.len() as u64, stream));
+    Ok(())
+  }
+
+  #[cfg(test)]
+  pub fn len(&self) -> usize {
+    self.entries.lock().unwrap().len()
+  }
+}
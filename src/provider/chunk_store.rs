@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use sha2::Digest;
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, VersionInfo};
+use super::index::Index;
+use super::{check_range, dir_info, relative_path};
+
+/// Verified chunks kept for reads continuing into them.
+const CHUNK_CACHE: usize = 8;
+
+/// Hash function naming the chunks of a [`ChunkStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkHash {
+  Blake3,
+  Sha256,
+}
+
+impl ChunkHash {
+  pub fn hash(self, data: &[u8]) -> [u8; 32] {
+    match self {
+      ChunkHash::Blake3 => *blake3::hash(data).as_bytes(),
+      ChunkHash::Sha256 => sha2::Sha256::digest(data).into(),
+    }
+  }
+}
+
+/// Chunk of a file, by hash and length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chunk {
+  pub hash: [u8; 32],
+  pub len: u64,
+}
+
+fn hex(hash: &[u8]) -> String {
+  hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+  let mut hash = [0; 32];
+  if hex.len() != 64 || !hex.is_ascii() {
+    return None
+  }
+  for (i, byte) in hash.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+  }
+  Some(hash)
+}
+
+/// Directory of chunks named by the hex hash of their content, under a subdirectory of the first two digits,
+/// so identical chunks are stored once.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+  dir: PathBuf,
+  hash: ChunkHash,
+}
+
+impl ChunkStore {
+  pub fn new<P: Into<PathBuf>>(dir: P, hash: ChunkHash) -> Self {
+    Self { dir: dir.into(), hash }
+  }
+
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  pub fn chunk_path(&self, hash: &[u8; 32]) -> PathBuf {
+    let hex = hex(hash);
+    self.dir.join(&hex[..2]).join(hex)
+  }
+
+  /// Stores `data` as one chunk unless the store has it already.
+  pub fn put(&self, data: &[u8]) -> std::io::Result<Chunk> {
+    let chunk = Chunk { hash: self.hash.hash(data), len: data.len() as u64 };
+    let path = self.chunk_path(&chunk.hash);
+    if !path.exists() {
+      std::fs::create_dir_all(path.parent().unwrap())?;
+      // readers never see a partly written chunk
+      let temp = path.with_extension(format!("tmp-{}", Guid::new_v4()));
+      std::fs::write(&temp, data)?;
+      std::fs::rename(&temp, &path).inspect_err(|_| { let _ = std::fs::remove_file(&temp); })?;
+    }
+    Ok(chunk)
+  }
+
+  /// Stores everything `reader` yields in chunks of `chunk_size` bytes, the last one shorter.
+  pub fn put_all<R: Read>(&self, mut reader: R, chunk_size: usize) -> std::io::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut buf = vec![0; chunk_size.max(1)];
+    loop {
+      let mut len = 0;
+      while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+          Ok(0) => break,
+          Ok(n) => len += n,
+          Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+          Err(e) => return Err(e),
+        }
+      }
+      if len == 0 {
+        return Ok(chunks)
+      }
+      chunks.push(self.put(&buf[..len])?);
+    }
+  }
+
+  /// Content of `chunk`, `InvalidData` if it does not hash to its name.
+  pub fn get(&self, chunk: &Chunk) -> std::io::Result<Vec<u8>> {
+    let path = self.chunk_path(&chunk.hash);
+    let data = std::fs::read(&path).map_err(|e| std::io::Error::new(e.kind(), format!("chunk {}: {}", hex(&chunk.hash), e)))?;
+    if data.len() as u64 != chunk.len || self.hash.hash(&data) != chunk.hash {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("chunk {} is corrupt", hex(&chunk.hash))))
+    }
+    Ok(data)
+  }
+
+  /// Hash of a file made of `chunks`, the hash of their hashes in order.
+  pub fn root_hash(&self, chunks: &[Chunk]) -> [u8; 32] {
+    self.hash.hash(&chunks.iter().flat_map(|chunk| chunk.hash).collect::<Vec<_>>())
+  }
+}
+
+/// Chunks of a file, with the offset each one starts at.
+struct ChunkList {
+  chunks: Vec<Chunk>,
+  starts: Vec<u64>,
+}
+
+/// Provider projecting a tree of files made of chunks in a [`ChunkStore`].
+///
+/// The tree comes from a manifest, or is built with [`ChunkTree::add_file`]. Manifests list one entry per line
+/// as the chunks, a tab and the path with `/` separators: chunks as space separated `hash:length` pairs with
+/// the hash in hex, `-` for empty files and for directories, whose path ends with a `/`.
+///
+/// Reads load the chunks overlapping the range and check them against their hash, a few verified chunks
+/// are kept for reads continuing into them. Files carry their root hash as placeholder content ID.
+pub struct ChunkTree {
+  store: ChunkStore,
+  index: Index<ChunkList>,
+  /// Recently verified chunks, most recently used last.
+  cache: Mutex<VecDeque<(Chunk, Arc<Vec<u8>>)>>,
+}
+
+impl ChunkTree {
+  pub fn new(store: ChunkStore) -> Self {
+    Self { store, index: Index::new(), cache: Mutex::new(VecDeque::new()) }
+  }
+
+  /// Tree listed in the manifest at `manifest`.
+  pub fn open<P: AsRef<Path>>(store: ChunkStore, manifest: P) -> std::io::Result<Self> {
+    let invalid = |line: usize, reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("manifest line {}: {}", line + 1, reason));
+    let mut tree = Self::new(store);
+    for (n, line) in std::fs::read_to_string(manifest)?.lines().enumerate() {
+      if line.is_empty() {
+        continue
+      }
+      let (chunks, path) = line.split_once('\t').ok_or_else(|| invalid(n, "expected chunks and path"))?;
+      let (path, is_dir) = match path.strip_suffix('/') {
+        Some(path) => (path, true),
+        None => (path, false),
+      };
+      let path = relative_path(path).ok_or_else(|| invalid(n, "invalid path"))?;
+      if is_dir {
+        tree.add_dir(&path);
+        continue
+      }
+      let chunks = match chunks {
+        "-" => Vec::new(),
+        chunks => chunks.split(' ').map(|chunk| {
+          let (hash, len) = chunk.split_once(':')?;
+          Some(Chunk { hash: parse_hex(hash)?, len: len.parse().ok()? })
+        }).collect::<Option<Vec<_>>>().ok_or_else(|| invalid(n, "invalid chunk"))?,
+      };
+      tree.add_file(&path, chunks);
+    }
+    Ok(tree)
+  }
+
+  pub fn store(&self) -> &ChunkStore {
+    &self.store
+  }
+
+  pub fn add_dir(&mut self, path: &ProjPath) {
+    self.index.insert_dir(path, dir_info(path));
+  }
+
+  /// Adds a file made of `chunks`, replacing an earlier one. Directories in the way win.
+  pub fn add_file(&mut self, path: &ProjPath, chunks: Vec<Chunk>) {
+    let mut starts = Vec::with_capacity(chunks.len());
+    let mut size = 0;
+    for chunk in &chunks {
+      starts.push(size);
+      size += chunk.len;
+    }
+    let info = FileBasicInfo { is_dir: false, file_size: size, content_id: Some(self.store.root_hash(&chunks).to_vec()), ..dir_info(path) };
+    self.index.insert_file(path, info, ChunkList { chunks, starts });
+  }
+
+  /// Verified content of `chunk`, from the cache if it was read lately.
+  fn chunk(&self, chunk: &Chunk) -> std::io::Result<Arc<Vec<u8>>> {
+    {
+      let mut cache = self.cache.lock().unwrap();
+      if let Some(i) = cache.iter().position(|(c, _)| c == chunk) {
+        let entry = cache.remove(i).unwrap();
+        let data = entry.1.clone();
+        cache.push_back(entry);
+        return Ok(data)
+      }
+    }
+    // loaded and hashed outside the lock
+    let data = Arc::new(self.store.get(chunk)?);
+    let mut cache = self.cache.lock().unwrap();
+    if cache.len() >= CHUNK_CACHE {
+      cache.pop_front();
+    }
+    cache.push_back((*chunk, data.clone()));
+    Ok(data)
+  }
+}
+
+impl ProjFSDirEnum for ChunkTree {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.index.dir_iter(path)
+  }
+}
+
+impl ProjFSRead for ChunkTree {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.index.metadata(path)
+  }
+
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let (info, list) = self.index.file(path)?;
+    check_range(info.file_size, offset, buf.len())?;
+    // last chunk starting at or before the offset, empty chunks are skipped over by the loop
+    let mut i = list.starts.partition_point(|&start| start <= offset).saturating_sub(1);
+    let mut done = 0;
+    while done < buf.len() {
+      let (chunk, start) = (&list.chunks[i], list.starts[i]);
+      let at = (offset + done as u64 - start) as usize;
+      if at < chunk.len as usize {
+        let data = self.chunk(chunk)?;
+        let len = (data.len() - at).min(buf.len() - done);
+        buf[done..done + len].copy_from_slice(&data[at..at + len]);
+        done += len;
+      }
+      i += 1;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ProjPathBuf;
+  use crate::provider::testing::*;
+
+  fn manifest(entries: &[(&str, &[Chunk])]) -> String {
+    entries.iter().map(|(path, chunks)| {
+      let chunks = chunks.iter().map(|chunk| format!("{}:{}", hex(&chunk.hash), chunk.len)).collect::<Vec<_>>();
+      format!("{}\t{}\n", if chunks.is_empty() { "-".to_string() } else { chunks.join(" ") }, path)
+    }).collect()
+  }
+
+  fn chunk_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir).unwrap().map(|sub| std::fs::read_dir(sub.unwrap().path()).unwrap().count()).sum()
+  }
+
+  #[test]
+  fn dedups_and_reads() {
+    for hash in [ChunkHash::Blake3, ChunkHash::Sha256] {
+      let dir = TempDir::new();
+      let store = ChunkStore::new(dir.path().join("chunks"), hash);
+      let data = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+      let a = store.put_all(&data[..], 1000).unwrap();
+      let b = store.put_all(&data[..5500], 1000).unwrap();
+      // `b` starts with the chunks of `a`, only its short tail is new
+      assert_eq!((a.len(), b.len()), (10, 6));
+      assert_eq!(chunk_files(store.dir()), 11);
+      std::fs::write(dir.path().join("manifest"), manifest(&[("a.bin", &a), ("sub/b.bin", &b), ("empty", &[]), ("dir/", &[])])).unwrap();
+      let fs = ChunkTree::open(store, dir.path().join("manifest")).unwrap();
+      assert_eq!(list(&fs, "").unwrap(), vec!["a.bin", "dir", "empty", "sub"]);
+      assert_eq!(read_all(&fs, "a.bin").unwrap(), data);
+      assert_eq!(read_all(&fs, "SUB\\b.bin").unwrap(), &data[..5500]);
+      assert_eq!(read_all(&fs, "empty").unwrap(), b"");
+      let mut buf = [0; 1500];
+      fs.read(&ProjPathBuf::from("a.bin"), VersionInfo::NONE, 2999, &mut buf).unwrap();
+      assert_eq!(&buf[..], &data[2999..4499]);
+      let info = fs.get_metadata(&ProjPathBuf::from("a.bin"), VersionInfo::NONE).unwrap();
+      assert_eq!(info.content_id, Some(fs.store().root_hash(&a).to_vec()));
+      assert_ne!(info.content_id, fs.get_metadata(&ProjPathBuf::from("sub\\b.bin"), VersionInfo::NONE).unwrap().content_id);
+    }
+  }
+
+  #[test]
+  fn detects_corrupt_chunks() {
+    let dir = TempDir::new();
+    let store = ChunkStore::new(dir.path().join("chunks"), ChunkHash::Blake3);
+    let chunks = store.put_all(&b"hello world"[..], 6).unwrap();
+    std::fs::write(store.chunk_path(&chunks[1].hash), b"WORLD").unwrap();
+    let mut fs = ChunkTree::new(store);
+    fs.add_file(&ProjPathBuf::from("hello"), chunks.clone());
+    let mut buf = [0; 6];
+    fs.read(&ProjPathBuf::from("hello"), VersionInfo::NONE, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello ");
+    assert_eq!(fs.read(&ProjPathBuf::from("hello"), VersionInfo::NONE, 5, &mut buf).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    std::fs::remove_file(fs.store().chunk_path(&chunks[1].hash)).unwrap();
+    assert_eq!(fs.read(&ProjPathBuf::from("hello"), VersionInfo::NONE, 6, &mut [0; 5]).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+  }
+
+  #[test]
+  fn invalid_manifests() {
+    let dir = TempDir::new();
+    for manifest in ["a.bin", "zz:5\ta.bin", "-\t../a", &format!("{}:x\ta", "00".repeat(32))] {
+      std::fs::write(dir.path().join("manifest"), manifest).unwrap();
+      let err = ChunkTree::open(ChunkStore::new(dir.path(), ChunkHash::Sha256), dir.path().join("manifest")).err().unwrap();
+      assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}", manifest);
+    }
+  }
+}
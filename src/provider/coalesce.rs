@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{trim_id, CallKind};
+
+/// Error of a call handed to every caller waiting for it.
+#[derive(Clone)]
+struct SharedError {
+  kind: std::io::ErrorKind,
+  raw: Option<i32>,
+  message: String,
+}
+
+impl SharedError {
+  fn of(e: &std::io::Error) -> Self {
+    Self { kind: e.kind(), raw: e.raw_os_error(), message: e.to_string() }
+  }
+
+  fn to_error(&self) -> std::io::Error {
+    match self.raw {
+      Some(raw) => std::io::Error::from_raw_os_error(raw),
+      None => std::io::Error::new(self.kind, self.message.clone()),
+    }
+  }
+}
+
+struct Flight<V> {
+  result: Mutex<Option<Result<V, SharedError>>>,
+  done: Condvar,
+}
+
+/// Calls in flight by key.
+struct Flights<K, V> {
+  map: Mutex<HashMap<K, Arc<Flight<V>>>>,
+  /// Calls that waited for another.
+  followers: AtomicU64,
+}
+
+enum Role<'a, K: Eq + Hash, V> {
+  Leader(Lead<'a, K, V>),
+  Follower(std::io::Result<V>),
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Flights<K, V> {
+  fn new() -> Self {
+    Self { map: Mutex::new(HashMap::new()), followers: AtomicU64::new(0) }
+  }
+
+  /// Leads the call of `key`, or waits for the result of the one in flight.
+  fn join(&self, key: K) -> Role<'_, K, V> {
+    let mut map = self.map.lock().unwrap();
+    if let Some(flight) = map.get(&key).cloned() {
+      drop(map);
+      self.followers.fetch_add(1, Ordering::Relaxed);
+      let result = flight.done.wait_while(flight.result.lock().unwrap(), |i| i.is_none()).unwrap();
+      return Role::Follower(result.as_ref().unwrap().clone().map_err(|e| e.to_error()))
+    }
+    let flight = Arc::new(Flight { result: Mutex::new(None), done: Condvar::new() });
+    map.insert(key.clone(), flight.clone());
+    Role::Leader(Lead { flights: self, key: Some(key), flight })
+  }
+}
+
+/// Duty of the caller actually making a call, handing its result to those who joined.
+struct Lead<'a, K: Eq + Hash, V> {
+  flights: &'a Flights<K, V>,
+  key: Option<K>,
+  flight: Arc<Flight<V>>,
+}
+
+impl<K: Eq + Hash, V> Lead<'_, K, V> {
+  /// Ends the call, `share` builds the result for the callers waiting, if any.
+  fn finish(mut self, share: impl FnOnce() -> Result<V, SharedError>) {
+    self.flights.map.lock().unwrap().remove(self.key.as_ref().unwrap());
+    self.key = None;
+    // nobody joins once the call is out of the map
+    if Arc::strong_count(&self.flight) > 1 {
+      self.publish(share());
+    }
+  }
+
+  fn publish(&self, result: Result<V, SharedError>) {
+    *self.flight.result.lock().unwrap() = Some(result);
+    self.flight.done.notify_all();
+  }
+}
+
+impl<K: Eq + Hash, V> Drop for Lead<'_, K, V> {
+  fn drop(&mut self) {
+    // the call panicked
+    if let Some(key) = self.key.take() {
+      self.flights.map.lock().unwrap().remove(&key);
+      self.publish(Err(SharedError { kind: std::io::ErrorKind::Other, raw: None, message: "the provider call failed".into() }));
+    }
+  }
+}
+
+type MetadataKey = (ProjPathBuf, Option<Vec<u8>>);
+type ReadKey = (ProjPathBuf, Option<Vec<u8>>, u64, usize);
+
+/// Decorator merging identical calls running at the same time, so the provider serves them once.
+///
+/// `get_metadata` calls for the same path and placeholder version, and `read` calls for the same range of it,
+/// wait for the one already in flight and get a copy of its result, errors included. Enumerations are not merged.
+pub struct Coalesce<T> {
+  inner: T,
+  metadata: Flights<MetadataKey, FileBasicInfo>,
+  reads: Flights<ReadKey, Arc<[u8]>>,
+}
+
+impl<T> Coalesce<T> {
+  pub fn new(inner: T) -> Self {
+    Self { inner, metadata: Flights::new(), reads: Flights::new() }
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  /// Calls of `kind` served by a call already in flight, counted as they start waiting.
+  pub fn coalesced(&self, kind: CallKind) -> u64 {
+    match kind {
+      CallKind::DirIter => 0,
+      CallKind::GetMetadata => self.metadata.followers.load(Ordering::Relaxed),
+      CallKind::Read => self.reads.followers.load(Ordering::Relaxed),
+    }
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for Coalesce<T> {
+  type DirIter = T::DirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.inner.dir_iter(id, path, pattern, version)
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for Coalesce<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    let lead = match self.metadata.join((path.to_owned(), version.content_id().map(|id| trim_id(id)))) {
+      Role::Leader(lead) => lead,
+      Role::Follower(result) => return result,
+    };
+    let result = self.inner.get_metadata(path, version);
+    lead.finish(|| result.as_ref().cloned().map_err(SharedError::of));
+    result
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let lead = match self.reads.join((path.to_owned(), version.content_id().map(|id| trim_id(id)), offset, buf.len())) {
+      Role::Leader(lead) => lead,
+      Role::Follower(result) => {
+        buf.copy_from_slice(&result?);
+        return Ok(())
+      },
+    };
+    let result = self.inner.read(path, version, offset, buf);
+    lead.finish(|| result.as_ref().map(|_| buf[..].into()).map_err(SharedError::of));
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  /// Tree whose reads of `panic` panic.
+  struct Panicking(Tree);
+
+  impl ProjFSRead for Panicking {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.0.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      if path.to_string_lossy() == "panic" {
+        panic!("provider bug")
+      }
+      self.0.read(path, version, offset, buf)
+    }
+  }
+
+  type Provider = Held<Panicking>;
+
+  fn coalesce() -> Coalesce<Provider> {
+    let tree = Tree::new(&[("file", "0123456789"), ("panic", "")]);
+    Coalesce::new(Held::new(Panicking(tree)))
+  }
+
+  fn read(fs: &Coalesce<Provider>, path: &str, version: VersionInfo, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    fs.read(&ProjPathBuf::from(path), version, offset, &mut buf)?;
+    Ok(buf)
+  }
+
+  /// Waits until `n` calls waited for another of `kind`.
+  fn followers(fs: &Coalesce<Provider>, kind: CallKind, n: u64) {
+    while fs.coalesced(kind) < n {
+      std::thread::yield_now();
+    }
+  }
+
+  #[test]
+  fn identical_calls_hit_the_provider_once() {
+    let fs = coalesce();
+    std::thread::scope(|scope| {
+      let reads: Vec<_> = (0..4).map(|_| scope.spawn(|| read(&fs, "file", VersionInfo::NONE, 2, 4))).collect();
+      let metadata: Vec<_> = (0..3).map(|_| scope.spawn(|| fs.get_metadata(&ProjPathBuf::from("FILE"), VersionInfo::NONE))).collect();
+      followers(&fs, CallKind::Read, 3);
+      followers(&fs, CallKind::GetMetadata, 2);
+      fs.inner().release();
+      for read in reads {
+        assert_eq!(read.join().unwrap().unwrap(), b"2345");
+      }
+      for info in metadata {
+        assert_eq!(info.join().unwrap().unwrap().file_size, 10);
+      }
+    });
+    assert_eq!(fs.inner().calls(), 2);
+    // calls made after the first one ended are not merged
+    fs.get_metadata(&ProjPathBuf::from("file"), VersionInfo::NONE).unwrap();
+    assert_eq!(fs.inner().calls(), 3);
+  }
+
+  #[test]
+  fn errors_reach_every_caller() {
+    let fs = coalesce();
+    std::thread::scope(|scope| {
+      let reads: Vec<_> = (0..3).map(|_| scope.spawn(|| read(&fs, "file", VersionInfo::NONE, 8, 4))).collect();
+      followers(&fs, CallKind::Read, 2);
+      fs.inner().release();
+      for read in reads {
+        assert_eq!(read.join().unwrap().err().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+      }
+    });
+    // callers waiting for a call that panicked fail instead of hanging
+    let fs = coalesce();
+    std::thread::scope(|scope| {
+      let leader = scope.spawn(|| read(&fs, "panic", VersionInfo::NONE, 0, 1));
+      while fs.inner().calls() == 0 {
+        std::thread::yield_now();
+      }
+      let follower = scope.spawn(|| read(&fs, "panic", VersionInfo::NONE, 0, 1));
+      followers(&fs, CallKind::Read, 1);
+      fs.inner().release();
+      assert!(leader.join().is_err());
+      assert!(follower.join().unwrap().is_err());
+    });
+  }
+
+  #[test]
+  fn different_calls_are_not_merged() {
+    let fs = coalesce();
+    let (old, new) = (crate::version_info(b"old"), crate::version_info(b"new"));
+    let fs = &fs;
+    std::thread::scope(|scope| {
+      let calls = [(2, 4, &old), (2, 3, &old), (3, 4, &old), (2, 4, &new)];
+      let reads: Vec<_> = calls.iter().map(|&(offset, len, version)| scope.spawn(move || read(fs, "file", VersionInfo::new(version), offset, len))).collect();
+      while fs.inner().calls() < 4 {
+        std::thread::yield_now();
+      }
+      fs.inner().release();
+      let data: Vec<_> = reads.into_iter().map(|i| i.join().unwrap().unwrap()).collect();
+      assert_eq!(data, vec![b"2345".to_vec(), b"234".to_vec(), b"3456".to_vec(), b"2345".to_vec()]);
+    });
+    assert_eq!(fs.coalesced(CallKind::Read), 0);
+  }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::CallKind;
+
+#[derive(Default)]
+struct Counts {
+  running: usize,
+  waiting: usize,
+}
+
+/// Counting semaphore calls wait on.
+struct Gate {
+  limit: usize,
+  counts: Mutex<Counts>,
+  freed: Condvar,
+}
+
+impl Gate {
+  fn new(limit: usize) -> Self {
+    Self { limit: limit.max(1), counts: Mutex::default(), freed: Condvar::new() }
+  }
+
+  fn acquire(&self, deadline: Instant, max_queued: usize) -> std::io::Result<Permit<'_>> {
+    let mut counts = self.counts.lock().unwrap();
+    if counts.running < self.limit {
+      counts.running += 1;
+      return Ok(Permit(self))
+    }
+    if counts.waiting >= max_queued {
+      return Err(std::io::Error::new(std::io::ErrorKind::ResourceBusy, "too many calls waiting for the provider"))
+    }
+    counts.waiting += 1;
+    while counts.running >= self.limit {
+      let now = Instant::now();
+      if now >= deadline {
+        counts.waiting -= 1;
+        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out waiting for the provider"))
+      }
+      counts = self.freed.wait_timeout(counts, deadline - now).unwrap().0;
+    }
+    counts.waiting -= 1;
+    counts.running += 1;
+    Ok(Permit(self))
+  }
+}
+
+struct Permit<'a>(&'a Gate);
+
+impl Drop for Permit<'_> {
+  fn drop(&mut self) {
+    self.0.counts.lock().unwrap().running -= 1;
+    self.0.freed.notify_one();
+  }
+}
+
+/// Decorator capping the calls running concurrently in a provider.
+///
+/// Calls are capped per [`CallKind`] and per path prefix, a call counting against every limit it falls under.
+/// Calls over a limit wait in a queue of at most `max_queued` calls, for at most `max_wait`, and then fail with
+/// `ResourceBusy` or `TimedOut`. Only `dir_iter` itself is capped for enumerations, not the iteration after it.
+pub struct ConcurrencyLimit<T> {
+  inner: T,
+  kinds: HashMap<CallKind, Gate>,
+  prefixes: Vec<(ProjPathBuf, Gate)>,
+  max_wait: Duration,
+  max_queued: usize,
+}
+
+impl<T> ConcurrencyLimit<T> {
+  /// No limit yet, calls wait up to 30 seconds in unbounded queues.
+  pub fn new(inner: T) -> Self {
+    Self { inner, kinds: HashMap::new(), prefixes: Vec::new(), max_wait: Duration::from_secs(30), max_queued: usize::MAX }
+  }
+
+  /// Caps calls of `kind` to `limit` at a time.
+  pub fn limit(mut self, kind: CallKind, limit: usize) -> Self {
+    self.kinds.insert(kind, Gate::new(limit));
+    self
+  }
+
+  /// Caps calls on `prefix` or below, of any kind, to `limit` at a time.
+  pub fn limit_prefix<P: Into<ProjPathBuf>>(mut self, prefix: P, limit: usize) -> Self {
+    self.prefixes.push((prefix.into(), Gate::new(limit)));
+    self
+  }
+
+  pub fn max_wait(mut self, max_wait: Duration) -> Self {
+    self.max_wait = max_wait;
+    self
+  }
+
+  /// Calls waiting for each limit beyond which further calls fail at once.
+  pub fn max_queued(mut self, max_queued: usize) -> Self {
+    self.max_queued = max_queued;
+    self
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  /// Runs `f` once the call of `kind` on `path` fits within every limit.
+  fn run<R>(&self, kind: CallKind, path: &ProjPath, f: impl FnOnce() -> std::io::Result<R>) -> std::io::Result<R> {
+    let deadline = Instant::now() + self.max_wait;
+    // prefixes first, calls queued behind a busy prefix must not hold permits of their kind other paths need
+    let gates = self.prefixes.iter().filter(|(prefix, _)| path.starts_with(prefix)).map(|(_, gate)| gate).chain(self.kinds.get(&kind));
+    let _permits = gates.map(|gate| gate.acquire(deadline, self.max_queued)).collect::<std::io::Result<Vec<_>>>()?;
+    f()
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for ConcurrencyLimit<T> {
+  type DirIter = T::DirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.run(CallKind::DirIter, path, || self.inner.dir_iter(id, path, pattern, version))
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for ConcurrencyLimit<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.run(CallKind::GetMetadata, path, || self.inner.get_metadata(path, version))
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    self.run(CallKind::Read, path, || self.inner.read(path, version, offset, buf))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn blocking() -> Held<Tree> {
+    Held::new(Tree::new(&[("fast\\file", "fast"), ("slow\\file", "slow")]))
+  }
+
+  fn metadata<T: ProjFSRead>(fs: &T, path: &str) -> std::io::Result<FileBasicInfo> {
+    fs.get_metadata(&ProjPathBuf::from(path), VersionInfo::NONE)
+  }
+
+  #[test]
+  fn caps_calls_per_kind() {
+    let fs = ConcurrencyLimit::new(blocking()).limit(CallKind::Read, 2);
+    std::thread::scope(|scope| {
+      let reads: Vec<_> = (0..6).map(|_| scope.spawn(|| {
+        let mut buf = [0; 4];
+        fs.read(&ProjPathBuf::from("slow\\file"), VersionInfo::NONE, 0, &mut buf).map(|_| buf)
+      })).collect();
+      fs.inner().held(2);
+      // other kinds are not capped
+      let calls: Vec<_> = (0..3).map(|_| scope.spawn(|| metadata(&fs, "slow"))).collect();
+      fs.inner().held(5);
+      std::thread::sleep(Duration::from_millis(20));
+      assert_eq!(fs.inner().running(), 5);
+      fs.inner().release();
+      for read in reads {
+        assert_eq!(&read.join().unwrap().unwrap(), b"slow");
+      }
+      for call in calls {
+        call.join().unwrap().unwrap();
+      }
+    });
+    assert_eq!(fs.inner().most(), 5);
+  }
+
+  #[test]
+  fn caps_calls_per_prefix() {
+    let fs = ConcurrencyLimit::new(blocking()).limit_prefix("slow", 1).max_wait(Duration::from_millis(20));
+    std::thread::scope(|scope| {
+      let held = scope.spawn(|| metadata(&fs, "SLOW\\file"));
+      fs.inner().held(1);
+      assert_eq!(metadata(&fs, "slow").err().unwrap().kind(), std::io::ErrorKind::TimedOut);
+      // other paths are not held up
+      let fast = scope.spawn(|| metadata(&fs, "fast\\file"));
+      fs.inner().held(2);
+      fs.inner().release();
+      held.join().unwrap().unwrap();
+      fast.join().unwrap().unwrap();
+    });
+  }
+
+  #[test]
+  fn busy_prefixes_do_not_hold_up_other_paths_of_a_kind() {
+    let fs = ConcurrencyLimit::new(blocking()).limit(CallKind::Read, 2).limit_prefix("slow", 1).max_wait(Duration::from_secs(5));
+    let read = |path: &str| {
+      let mut buf = [0; 4];
+      fs.read(&ProjPathBuf::from(path), VersionInfo::NONE, 0, &mut buf).map(|_| buf)
+    };
+    std::thread::scope(|scope| {
+      let slow: Vec<_> = (0..3).map(|_| scope.spawn(|| read("slow\\file"))).collect();
+      fs.inner().held(1);
+      let gates = fs.kinds.values().chain(fs.prefixes.iter().map(|(_, gate)| gate));
+      while gates.clone().map(|gate| gate.counts.lock().unwrap().waiting).sum::<usize>() < 2 {
+        std::thread::yield_now();
+      }
+      // the reads queued on `slow` leave the second read permit to `fast`
+      let fast = scope.spawn(|| read("fast\\file"));
+      while fs.inner().running() < 2 && !fast.is_finished() {
+        std::thread::yield_now();
+      }
+      assert_eq!(fs.inner().running(), 2);
+      fs.inner().release();
+      assert_eq!(&fast.join().unwrap().unwrap(), b"fast");
+      for read in slow {
+        assert_eq!(&read.join().unwrap().unwrap(), b"slow");
+      }
+    });
+  }
+
+  #[test]
+  fn fails_fast_once_the_queue_is_full() {
+    let fs = ConcurrencyLimit::new(blocking()).limit(CallKind::GetMetadata, 1).max_queued(1);
+    std::thread::scope(|scope| {
+      let held = scope.spawn(|| metadata(&fs, "fast"));
+      fs.inner().held(1);
+      let queued = scope.spawn(|| metadata(&fs, "slow"));
+      while fs.kinds[&CallKind::GetMetadata].counts.lock().unwrap().waiting == 0 {
+        std::thread::yield_now();
+      }
+      assert_eq!(metadata(&fs, "slow").err().unwrap().kind(), std::io::ErrorKind::ResourceBusy);
+      fs.inner().release();
+      held.join().unwrap().unwrap();
+      queued.join().unwrap().unwrap();
+    });
+  }
+}
@@ -0,0 +1,212 @@
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{read_exact_at, sort_entries};
+
+/// Provider projecting a local directory tree through `std::fs`.
+///
+/// Lookups are case-insensitive like ProjFS: a component is tried as given first,
+/// then matched against the entries of its parent. Symbolic links are followed,
+/// names that cannot be projected (containing `\`, or not UTF-8 outside Windows) are left out.
+pub struct DirMirror {
+  root: PathBuf,
+}
+
+impl DirMirror {
+  pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+    Self { root: root.into() }
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  /// Local path of `path`, resolving the case of every component.
+  fn locate(&self, path: &ProjPath) -> std::io::Result<PathBuf> {
+    let mut local = self.root.clone();
+    for component in path.components() {
+      let name = component.to_os_string();
+      // `/` separates components outside Windows, so names like `x/../..` are no single component there
+      let mut parts = Path::new(&name).components();
+      let single = matches!((parts.next(), parts.next()), (Some(std::path::Component::Normal(_)), None));
+      if !single || name.to_string_lossy().contains(['/', '\\']) {
+        return Err(std::io::ErrorKind::NotFound.into())
+      }
+      let exact = local.join(&name);
+      if std::fs::symlink_metadata(&exact).is_ok() {
+        local = exact;
+        continue
+      }
+      let found = std::fs::read_dir(&local)?
+        .filter_map(Result::ok)
+        .find(|i| &*ProjPathBuf::from(i.file_name().as_os_str()) == component)
+        .ok_or(std::io::ErrorKind::NotFound)?;
+      local = found.path();
+    }
+    Ok(local)
+  }
+}
+
+fn projectable(name: &std::ffi::OsStr) -> bool {
+  #[cfg(windows)] {
+    !name.is_empty()
+  }
+  #[cfg(not(windows))] {
+    name.to_str().is_some_and(|i| !i.is_empty() && !i.contains('\\'))
+  }
+}
+
+fn basic_info(name: PathBuf, meta: &Metadata) -> FileBasicInfo {
+  let is_dir = meta.is_dir();
+  #[cfg(windows)] {
+    use std::os::windows::fs::MetadataExt;
+    FileBasicInfo {
+      file_name: name,
+      is_dir,
+      file_size: if is_dir { 0 } else { meta.len() },
+      created: meta.creation_time() as i64,
+      accessed: meta.last_access_time() as i64,
+      writed: meta.last_write_time() as i64,
+      changed: meta.last_write_time() as i64,
+      attrs: meta.file_attributes(),
+      content_id: None,
+    }
+  }
+  #[cfg(not(windows))] {
+    use std::os::unix::fs::MetadataExt;
+    let modified = meta.modified().map(crate::filetime).unwrap_or(0);
+    let changed = crate::filetime(std::time::UNIX_EPOCH + std::time::Duration::new(meta.ctime().max(0) as u64, meta.ctime_nsec() as u32));
+    let mut attrs = 0;
+    if is_dir {
+      attrs |= crate::FILE_ATTRIBUTE_DIRECTORY;
+    }
+    if meta.permissions().readonly() {
+      attrs |= crate::FILE_ATTRIBUTE_READONLY;
+    }
+    if name.to_str().is_some_and(|i| i.starts_with('.')) {
+      attrs |= crate::FILE_ATTRIBUTE_HIDDEN;
+    }
+    FileBasicInfo {
+      file_name: name,
+      is_dir,
+      file_size: if is_dir { 0 } else { meta.len() },
+      created: meta.created().map(crate::filetime).unwrap_or(modified),
+      accessed: meta.accessed().map(crate::filetime).unwrap_or(modified),
+      writed: modified,
+      changed,
+      attrs,
+      content_id: None,
+    }
+  }
+}
+
+impl ProjFSDirEnum for DirMirror {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(self.locate(path)?)? {
+      let entry = entry?;
+      let name = entry.file_name();
+      if !projectable(&name) {
+        continue
+      }
+      // entries removed meanwhile and dangling links are left out
+      match std::fs::metadata(entry.path()) {
+        Ok(meta) => entries.push(basic_info(name.into(), &meta)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+      }
+    }
+    sort_entries(&mut entries);
+    Ok(entries.into_iter())
+  }
+}
+
+impl ProjFSRead for DirMirror {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    let local = self.locate(path)?;
+    let name = local.file_name().map(PathBuf::from).unwrap_or_default();
+    Ok(basic_info(name, &std::fs::metadata(&local)?))
+  }
+
+  /// Fails with `UnexpectedEof` if the file became shorter than the range ProjFS asks for,
+  /// rather than projecting a zero-padded mix of old and new content.
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let file = File::open(self.locate(path)?)?;
+    read_exact_at(&file, offset, buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn mirror() -> (TempDir, DirMirror) {
+    let dir = TempDir::new();
+    dir.write("readme.txt", b"hello");
+    dir.write("src/Main.rs", b"fn main() {}");
+    dir.write("src/lib.rs", b"");
+    dir.write(".hidden", b"x");
+    let fs = DirMirror::new(dir.path());
+    (dir, fs)
+  }
+
+  #[test]
+  fn lists_sorted_with_metadata() {
+    let (_dir, fs) = mirror();
+    assert_eq!(list(&fs, "").unwrap(), vec![".hidden", "readme.txt", "src"]);
+    assert_eq!(list(&fs, "src").unwrap(), vec!["lib.rs", "Main.rs"]);
+    let entries: Vec<_> = fs.dir_iter(Guid::nil(), &ProjPathBuf::from(""), None, VersionInfo::NONE).unwrap().collect();
+    let src = entries.iter().find(|i| i.is_dir).unwrap();
+    assert_eq!(src.attrs & crate::FILE_ATTRIBUTE_DIRECTORY, crate::FILE_ATTRIBUTE_DIRECTORY);
+    let readme = entries.iter().find(|i| i.file_name == Path::new("readme.txt")).unwrap();
+    assert_eq!(readme.file_size, 5);
+    let now = crate::filetime(std::time::SystemTime::now());
+    assert!(readme.writed > 0 && readme.writed <= now, "{}", readme.writed);
+    assert_ne!(entries[0].attrs & crate::FILE_ATTRIBUTE_HIDDEN, 0);
+  }
+
+  #[test]
+  fn lookups_ignore_case() {
+    let (_dir, fs) = mirror();
+    assert_eq!(read_all(&fs, "SRC\\main.RS").unwrap(), b"fn main() {}");
+    let info = fs.get_metadata(&ProjPathBuf::from("Src\\MAIN.rs"), VersionInfo::NONE).unwrap();
+    assert_eq!(info.file_name, Path::new("Main.rs"));
+    let e = fs.get_metadata(&ProjPathBuf::from("src\\missing.rs"), VersionInfo::NONE).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+  }
+
+  #[test]
+  fn positional_reads() {
+    let (_dir, fs) = mirror();
+    let mut buf = [0; 3];
+    fs.read(&ProjPathBuf::from("readme.txt"), VersionInfo::NONE, 2, &mut buf).unwrap();
+    assert_eq!(&buf, b"llo");
+  }
+
+  #[test]
+  fn file_changing_size_after_metadata() {
+    let (dir, fs) = mirror();
+    let path = ProjPathBuf::from("readme.txt");
+    let info = fs.get_metadata(&path, VersionInfo::NONE).unwrap();
+    dir.write("readme.txt", b"hi");
+    let mut buf = vec![0; info.file_size as usize];
+    let e = fs.read(&path, VersionInfo::NONE, 0, &mut buf).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    // growing is fine, the requested range is still there
+    dir.write("readme.txt", b"hello, world");
+    fs.read(&path, VersionInfo::NONE, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+  }
+
+  #[test]
+  fn does_not_escape_root() {
+    let (_dir, fs) = mirror();
+    assert!(fs.get_metadata(&ProjPathBuf::from("src\\..\\..\\etc"), VersionInfo::NONE).is_err());
+    for path in ["x/../../outside", "src/../readme.txt", "src/", "/etc"] {
+      let e = fs.get_metadata(&ProjPathBuf::from(path), VersionInfo::NONE).unwrap_err();
+      assert_eq!(e.kind(), std::io::ErrorKind::NotFound, "{}", path);
+    }
+  }
+}
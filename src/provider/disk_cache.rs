@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{check_range, read_exact_at, trim_id};
+
+/// First bytes of every blob.
+const MAGIC: &[u8; 8] = b"PJFSBLOB";
+/// Size of the reads filling a blob from the inner provider.
+const FETCH_SIZE: usize = 1 << 20;
+
+/// Path and content ID of a file.
+type Key = (ProjPathBuf, Vec<u8>);
+
+fn encode_key((path, id): &Key) -> Vec<u8> {
+  let units = path.as_wide();
+  let mut out = Vec::with_capacity(8 + units.len() * 2 + id.len());
+  out.extend_from_slice(&(units.len() as u32).to_le_bytes());
+  out.extend(units.iter().flat_map(|i| i.to_le_bytes()));
+  out.extend_from_slice(&(id.len() as u32).to_le_bytes());
+  out.extend_from_slice(id);
+  out
+}
+
+fn decode_key(data: &[u8]) -> Option<Key> {
+  let (len, rest) = data.split_at_checked(4)?;
+  let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+  let (units, rest) = rest.split_at_checked(len.checked_mul(2)?)?;
+  let units = units.chunks(2).map(|i| u16::from_le_bytes([i[0], i[1]])).collect();
+  let (len, id) = rest.split_at_checked(4)?;
+  if u32::from_le_bytes(len.try_into().unwrap()) as usize != id.len() {
+    return None
+  }
+  Some((ProjPathBuf::from_wide(units), id.to_vec()))
+}
+
+/// FNV-1a, stable across releases unlike the hasher of std.
+fn fnv1a(data: &[u8]) -> u64 {
+  data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// Whether a file named `name` was written by the cache: `Some(false)` for a blob, named by the hash of its key,
+/// `Some(true)` for a blob being written, named `<hash>.tmp-<uuid>`.
+fn cache_file(name: &OsStr) -> Option<bool> {
+  let (hash, rest) = name.to_str()?.split_at_checked(16)?;
+  if !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+    return None
+  }
+  match rest.strip_prefix(".tmp-") {
+    Some(id) => Guid::parse_str(id).ok().map(|_| true),
+    None => Some(false).filter(|_| rest.is_empty()),
+  }
+}
+
+/// Key and data size of the blob at `path`, and where its data starts.
+fn read_header(path: &Path) -> std::io::Result<(Key, u64, u64)> {
+  let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not a cache blob", path.display()));
+  let mut file = std::fs::File::open(path)?;
+  let mut head = [0; 12];
+  file.read_exact(&mut head)?;
+  let len = u32::from_le_bytes(head[8..].try_into().unwrap()) as usize;
+  if &head[..8] != MAGIC || len > 1 << 20 {
+    return Err(invalid())
+  }
+  let mut key = vec![0; len];
+  file.read_exact(&mut key)?;
+  let mut size = [0; 8];
+  file.read_exact(&mut size)?;
+  let size = u64::from_le_bytes(size);
+  let start = 20 + key.len() as u64;
+  if file.metadata()?.len() != start + size {
+    return Err(invalid())
+  }
+  Ok((decode_key(&key).ok_or_else(invalid)?, size, start))
+}
+
+struct Blob {
+  file: PathBuf,
+  /// Offset of the data in the file.
+  start: u64,
+  size: u64,
+  used: u64,
+}
+
+#[derive(Default)]
+struct Blobs {
+  map: HashMap<Key, Blob>,
+  /// Keys by last use, least recently used first.
+  order: BTreeMap<u64, Key>,
+  bytes: u64,
+  tick: u64,
+}
+
+impl Blobs {
+  fn touch(&mut self, key: &Key) -> Option<&Blob> {
+    self.tick += 1;
+    let blob = self.map.get_mut(key)?;
+    let key = self.order.remove(&blob.used).unwrap();
+    blob.used = self.tick;
+    self.order.insert(self.tick, key);
+    Some(blob)
+  }
+
+  fn insert(&mut self, key: Key, mut blob: Blob) {
+    self.tick += 1;
+    blob.used = self.tick;
+    self.bytes += blob.size;
+    self.order.insert(self.tick, key.clone());
+    self.map.insert(key, blob);
+  }
+
+  fn remove(&mut self, key: &Key) {
+    if let Some(blob) = self.map.remove(key) {
+      self.order.remove(&blob.used);
+      self.bytes -= blob.size;
+      let _ = std::fs::remove_file(&blob.file);
+    }
+  }
+
+  /// Removes the least recently used blobs until `extra` more bytes fit within `quota`.
+  fn evict(&mut self, quota: u64, extra: u64) {
+    while self.bytes + extra > quota {
+      let key = match self.order.values().next() {
+        Some(key) => key.clone(),
+        None => break,
+      };
+      self.remove(&key);
+    }
+  }
+}
+
+/// Decorator keeping the files read through a provider on disk, so they stay readable while it fails.
+///
+/// The first read of a file fetches it whole from the inner provider into a blob of the cache directory,
+/// reads of the same path and content version are served from the blob from then on. Only files with a content ID
+/// are cached, taken from the placeholder or else from the current metadata. Blobs are kept within `quota` bytes,
+/// least recently used ones go first, and are found again when the cache directory is reopened.
+/// Files of the directory not named like blobs are left alone.
+pub struct DiskCache<T> {
+  inner: T,
+  dir: PathBuf,
+  quota: u64,
+  blobs: Mutex<Blobs>,
+}
+
+impl<T> DiskCache<T> {
+  /// Cache in `dir`, created if missing, picking up the blobs stored there before.
+  pub fn open<P: Into<PathBuf>>(inner: T, dir: P, quota: u64) -> std::io::Result<Self> {
+    let dir = dir.into();
+    std::fs::create_dir_all(&dir)?;
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+      let file = entry?.path();
+      let temp = match file.file_name().and_then(cache_file) {
+        Some(temp) => temp,
+        None => continue,
+      };
+      match read_header(&file) {
+        Ok((key, size, start)) if !temp => {
+          let modified = std::fs::metadata(&file)?.modified()?;
+          found.push((modified, key, Blob { file, start, size, used: 0 }));
+        },
+        // left over by a process stopped while writing it
+        _ => { let _ = std::fs::remove_file(&file); },
+      }
+    }
+    found.sort_by_key(|(modified, _, _)| *modified);
+    let mut blobs = Blobs::default();
+    for (_, key, blob) in found {
+      blobs.insert(key, blob);
+    }
+    blobs.evict(quota, 0);
+    Ok(Self { inner, dir, quota, blobs: Mutex::new(blobs) })
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Bytes of file data held.
+  pub fn cached_bytes(&self) -> u64 {
+    self.blobs.lock().unwrap().bytes
+  }
+
+  /// Reads from the blob of `key`, `None` if there is none.
+  fn read_blob(&self, key: &Key, offset: u64, buf: &mut [u8]) -> Option<std::io::Result<()>> {
+    let (file, start, size) = {
+      let mut blobs = self.blobs.lock().unwrap();
+      let blob = blobs.touch(key)?;
+      (blob.file.clone(), blob.start, blob.size)
+    };
+    if let Err(e) = check_range(size, offset, buf.len()) {
+      return Some(Err(e))
+    }
+    match std::fs::File::open(&file) {
+      Ok(f) => {
+        // the order of use outlives the process through the modification time
+        let _ = std::fs::OpenOptions::new().write(true).open(&file).and_then(|f| f.set_modified(SystemTime::now()));
+        Some(read_exact_at(&f, start + offset, buf))
+      },
+      Err(_) => {
+        self.blobs.lock().unwrap().remove(key);
+        None
+      },
+    }
+  }
+}
+
+impl<T: ProjFSRead> DiskCache<T> {
+  /// Fetches the file of `key`, `size` bytes, into a new blob.
+  fn fetch(&self, key: Key, version: VersionInfo, size: u64) -> std::io::Result<()> {
+    let encoded = encode_key(&key);
+    let file = self.dir.join(format!("{:016x}", fnv1a(&encoded)));
+    let temp = file.with_extension(format!("tmp-{}", Guid::new_v4()));
+    let result = (|| {
+      let mut out = std::io::BufWriter::new(std::fs::File::create(&temp)?);
+      out.write_all(MAGIC)?;
+      out.write_all(&(encoded.len() as u32).to_le_bytes())?;
+      out.write_all(&encoded)?;
+      out.write_all(&size.to_le_bytes())?;
+      let mut buf = vec![0; FETCH_SIZE.min(size as usize)];
+      let mut offset = 0;
+      while offset < size {
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        self.inner.read(&key.0, version, offset, &mut buf[..len])?;
+        out.write_all(&buf[..len])?;
+        offset += len as u64;
+      }
+      out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    })();
+    let mut blobs = self.blobs.lock().unwrap();
+    // another read fetched it meanwhile, or another key hashes to the same name
+    if result.is_err() || blobs.map.contains_key(&key) || blobs.map.values().any(|blob| blob.file == file) {
+      let _ = std::fs::remove_file(&temp);
+      return result
+    }
+    blobs.evict(self.quota, size);
+    std::fs::rename(&temp, &file).inspect_err(|_| { let _ = std::fs::remove_file(&temp); })?;
+    blobs.insert(key, Blob { file, start: 20 + encoded.len() as u64, size, used: 0 });
+    Ok(())
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for DiskCache<T> {
+  type DirIter = T::DirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.inner.dir_iter(id, path, pattern, version)
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for DiskCache<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.inner.get_metadata(path, version)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let current_id = |info: &FileBasicInfo| info.content_id.as_deref().map(trim_id).filter(|id| !id.is_empty());
+    let mut info = None;
+    let id = match version.content_id() {
+      Some(id) => trim_id(id),
+      None => match current_id(info.insert(self.inner.get_metadata(path, version)?)) {
+        Some(id) => id,
+        None => return self.inner.read(path, version, offset, buf),
+      },
+    };
+    let key = (path.to_owned(), id);
+    if let Some(result) = self.read_blob(&key, offset, buf) {
+      return result
+    }
+    let info = match info {
+      Some(info) => info,
+      None => self.inner.get_metadata(path, version)?,
+    };
+    if info.is_dir || current_id(&info).as_ref() != Some(&key.1) || info.file_size > self.quota {
+      return self.inner.read(path, version, offset, buf)
+    }
+    // a failed fetch leaves the read to the inner provider, which reports the error
+    if self.fetch(key.clone(), version, info.file_size).is_ok() {
+      if let Some(result) = self.read_blob(&key, offset, buf) {
+        return result
+      }
+    }
+    self.inner.read(path, version, offset, buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::MemFs;
+  use crate::provider::testing::*;
+
+  /// Provider failing every call while told to, as when its backend is down.
+  fn flaky(files: &[(&str, &str)]) -> Failing<MemFs> {
+    let fs = MemFs::new();
+    for (path, data) in files {
+      fs.add_file(&ProjPathBuf::from(*path), *data).unwrap();
+    }
+    Failing::new(fs, || std::io::Error::other("backend unavailable"))
+  }
+
+  fn placeholder<T: ProjFSRead>(fs: &T, path: &str) -> crate::PlaceholderVersionInfo {
+    crate::version_info(fs.get_metadata(&ProjPathBuf::from(path), VersionInfo::NONE).unwrap().content_id.as_ref().unwrap())
+  }
+
+  fn read<T: ProjFSRead>(fs: &T, path: &str, version: &crate::PlaceholderVersionInfo, offset: u64, len: usize) -> std::io::Result<String> {
+    let mut buf = vec![0; len];
+    fs.read(&ProjPathBuf::from(path), VersionInfo::new(version), offset, &mut buf)?;
+    Ok(String::from_utf8(buf).unwrap())
+  }
+
+  #[test]
+  fn serves_cached_files_while_the_provider_fails() {
+    let dir = TempDir::new();
+    let fs = DiskCache::open(flaky(&[("a", "first file"), ("b", "second file")]), dir.path(), 1024).unwrap();
+    let (a, b) = (placeholder(&fs, "a"), placeholder(&fs, "b"));
+    assert_eq!(read(&fs, "a", &a, 6, 4).unwrap(), "file");
+    assert_eq!(fs.cached_bytes(), 10);
+    fs.inner().fail(usize::MAX);
+    assert_eq!(read(&fs, "a", &a, 0, 5).unwrap(), "first");
+    assert!(read(&fs, "a", &a, 6, 5).is_err());
+    assert!(read(&fs, "b", &b, 0, 6).is_err());
+    fs.inner().fail(0);
+    // a new version of the file is fetched again
+    fs.inner().inner().overwrite(&ProjPathBuf::from("a"), "changed").unwrap();
+    let changed = placeholder(&fs, "a");
+    assert_eq!(read(&fs, "a", &changed, 0, 7).unwrap(), "changed");
+    assert_eq!(read(&fs, "a", &a, 0, 5).unwrap(), "first");
+    assert_eq!(fs.cached_bytes(), 17);
+  }
+
+  #[test]
+  fn survives_restarts() {
+    let dir = TempDir::new();
+    let inner = flaky(&[("dir\\file", "cached content")]);
+    let version = placeholder(&inner, "dir\\file");
+    let fs = DiskCache::open(inner, dir.path(), 1024).unwrap();
+    read(&fs, "dir\\file", &version, 0, 1).unwrap();
+    let inner = fs.inner;
+    inner.fail(usize::MAX);
+    dir.write(&format!("0123456789abcdef.tmp-{}", Guid::new_v4()), b"partial");
+    dir.write("fedcba9876543210", b"broken blob");
+    // files the cache did not write are kept
+    dir.write("notes.txt", b"not a blob");
+    dir.write("0123456789abcdef.txt", b"not a blob either");
+    let fs = DiskCache::open(inner, dir.path(), 1024).unwrap();
+    assert_eq!(read(&fs, "DIR\\FILE", &version, 0, 14).unwrap(), "cached content");
+    let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|i| i.unwrap().file_name().into_string().unwrap()).collect();
+    assert_eq!(names.len(), 3);
+    assert!(names.iter().any(|i| i == "notes.txt") && names.iter().any(|i| i == "0123456789abcdef.txt"));
+  }
+
+  #[test]
+  fn evicts_least_recently_used_within_quota() {
+    let dir = TempDir::new();
+    let fs = DiskCache::open(flaky(&[("a", "aaaa"), ("b", "bbbb"), ("c", "cccc"), ("big", "too big to cache")]), dir.path(), 8).unwrap();
+    let versions: Vec<_> = ["a", "b", "c", "big"].iter().map(|i| placeholder(&fs, i)).collect();
+    read(&fs, "a", &versions[0], 0, 4).unwrap();
+    read(&fs, "b", &versions[1], 0, 4).unwrap();
+    read(&fs, "a", &versions[0], 0, 4).unwrap();
+    read(&fs, "c", &versions[2], 0, 4).unwrap();
+    read(&fs, "big", &versions[3], 0, 3).unwrap();
+    assert_eq!(fs.cached_bytes(), 8);
+    fs.inner().fail(usize::MAX);
+    assert_eq!(read(&fs, "a", &versions[0], 0, 4).unwrap(), "aaaa");
+    assert_eq!(read(&fs, "c", &versions[2], 0, 4).unwrap(), "cccc");
+    assert!(read(&fs, "b", &versions[1], 0, 4).is_err());
+    assert!(read(&fs, "big", &versions[3], 0, 3).is_err());
+    // the order of use is found again on reopening, with a smaller quota the least recent blob goes
+    drop(fs);
+    let fs = DiskCache::open(flaky(&[]), dir.path(), 4).unwrap();
+    fs.inner().fail(usize::MAX);
+    assert_eq!(read(&fs, "c", &versions[2], 0, 4).unwrap(), "cccc");
+    assert!(read(&fs, "a", &versions[0], 0, 4).is_err());
+  }
+}
@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::{FileBasicInfo, Guid, Pattern, ProjFSDirEnum, ProjFSRead, ProjPath, VersionInfo};
+use super::{CallKind, Rng};
+
+/// Misbehavior injected in a call.
+#[derive(Debug, Clone)]
+pub enum Fault {
+  /// Fails the call with an error of this kind.
+  Error(std::io::ErrorKind),
+  /// Delays the call.
+  Latency(Duration),
+  /// Reads succeed with only a random part of the buffer filled, the rest zeroed.
+  ShortRead,
+  /// Enumerations end after this many entries.
+  Truncate(usize),
+  /// Panics in the call. The callbacks report it to ProjFS as [`E_UNEXPECTED`](crate::E_UNEXPECTED).
+  Panic,
+}
+
+struct Rule {
+  kind: CallKind,
+  glob: Pattern,
+  probability: f64,
+  fault: Fault,
+}
+
+/// Decorator making a provider misbehave on purpose, to test how its clients cope.
+///
+/// Rules are checked in the order they were added. A rule applies to calls of its kind on paths matching its glob,
+/// matched like a [`Pattern`] against the whole path so `*` spans separators, and fires with its probability.
+/// Every rule firing takes effect, latencies first delaying the call before a later rule fails it.
+/// Decisions come from a generator seeded by the caller, so the same calls made in the same order fail the same way.
+pub struct FaultInjection<T> {
+  inner: T,
+  rules: Vec<Rule>,
+  rng: Mutex<Rng>,
+  injected: [AtomicU64; 3],
+}
+
+impl<T> FaultInjection<T> {
+  pub fn new(inner: T, seed: u64) -> Self {
+    Self { inner, rules: Vec::new(), rng: Mutex::new(Rng::new(seed)), injected: Default::default() }
+  }
+
+  /// Injects `fault` in calls of `kind` on paths matching `glob`, with `probability` from 0 to 1.
+  ///
+  /// Short reads only apply to reads and truncation only to enumerations.
+  pub fn inject(mut self, kind: CallKind, glob: &str, probability: f64, fault: Fault) -> Self {
+    self.rules.push(Rule { kind, glob: Pattern::new(glob), probability, fault });
+    self
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  /// Faults injected in calls of `kind`.
+  pub fn injected(&self, kind: CallKind) -> u64 {
+    self.injected[kind.index()].load(Ordering::Relaxed)
+  }
+
+  /// Applies the rules firing for the call of `kind` on `path`, returning those left to the call itself.
+  fn faults(&self, kind: CallKind, path: &ProjPath) -> std::io::Result<Vec<&Fault>> {
+    let path = path.to_string_lossy();
+    let fired: Vec<&Fault> = {
+      let mut rng = self.rng.lock().unwrap();
+      self.rules.iter()
+        .filter(|rule| rule.kind == kind && rule.glob.matches(&path))
+        .filter(|rule| rng.next_f64() < rule.probability)
+        .map(|rule| &rule.fault)
+        .collect()
+    };
+    self.injected[kind.index()].fetch_add(fired.len() as u64, Ordering::Relaxed);
+    let mut left = Vec::new();
+    for fault in fired {
+      match fault {
+        Fault::Error(error) => return Err(std::io::Error::new(*error, format!("injected fault on {}", path))),
+        Fault::Latency(delay) => std::thread::sleep(*delay),
+        Fault::Panic => panic!("injected fault on {}", path),
+        fault => left.push(fault),
+      }
+    }
+    Ok(left)
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for FaultInjection<T> {
+  type DirIter = std::iter::Take<T::DirIter>;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let limit = self.faults(CallKind::DirIter, path)?.into_iter().filter_map(|fault| match fault {
+      Fault::Truncate(n) => Some(*n),
+      _ => None,
+    }).min();
+    Ok(self.inner.dir_iter(id, path, pattern, version)?.take(limit.unwrap_or(usize::MAX)))
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for FaultInjection<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.faults(CallKind::GetMetadata, path)?;
+    self.inner.get_metadata(path, version)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let short = self.faults(CallKind::Read, path)?.into_iter().any(|fault| matches!(fault, Fault::ShortRead));
+    self.inner.read(path, version, offset, buf)?;
+    if short {
+      let len = (self.rng.lock().unwrap().next_u64() % buf.len().max(1) as u64) as usize;
+      buf[len..].fill(0);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ProjPathBuf;
+  use crate::provider::testing::*;
+
+  fn tree() -> Tree {
+    Tree::new(&[("bin\\tool.exe", "tool"), ("lib\\a.dll", "aaaa"), ("lib\\b.dll", "bbbb"), ("lib\\readme", "read me")])
+  }
+
+  fn outcomes(seed: u64) -> Vec<bool> {
+    let fs = FaultInjection::new(tree(), seed).inject(CallKind::GetMetadata, "*.dll", 0.5, Fault::Error(std::io::ErrorKind::TimedOut));
+    (0..64).map(|i| fs.get_metadata(&ProjPathBuf::from(if i % 2 == 0 { "lib\\a.dll" } else { "LIB\\B.DLL" }), VersionInfo::NONE).is_ok()).collect()
+  }
+
+  #[test]
+  fn seeded_faults_are_reproducible() {
+    assert_eq!(outcomes(7), outcomes(7));
+    assert_ne!(outcomes(7), outcomes(8));
+    let failed = outcomes(7).iter().filter(|ok| !**ok).count();
+    assert!((16..=48).contains(&failed), "{} failures", failed);
+  }
+
+  #[test]
+  fn injects_errors_and_latency_by_glob() {
+    let fs = FaultInjection::new(tree(), 1)
+      .inject(CallKind::Read, "lib\\*", 1.0, Fault::Latency(Duration::from_millis(20)))
+      .inject(CallKind::Read, "*.dll", 1.0, Fault::Error(std::io::ErrorKind::PermissionDenied))
+      .inject(CallKind::Read, "bin\\*", 0.0, Fault::Panic);
+    let start = std::time::Instant::now();
+    assert_eq!(read_all(&fs, "lib\\a.dll").err().unwrap().kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(read_all(&fs, "lib\\readme").unwrap(), b"read me");
+    assert!(start.elapsed() >= Duration::from_millis(40));
+    assert_eq!(read_all(&fs, "bin\\tool.exe").unwrap(), b"tool");
+    assert_eq!(fs.injected(CallKind::Read), 3);
+    assert_eq!(fs.injected(CallKind::GetMetadata), 0);
+  }
+
+  #[test]
+  fn short_reads_truncation_and_panics() {
+    let fs = FaultInjection::new(tree(), 3)
+      .inject(CallKind::Read, "lib\\readme", 1.0, Fault::ShortRead)
+      .inject(CallKind::DirIter, "lib", 1.0, Fault::Truncate(2))
+      .inject(CallKind::GetMetadata, "bin\\*", 1.0, Fault::Panic);
+    let data = read_all(&fs, "lib\\readme").unwrap();
+    let len = data.iter().position(|&i| i == 0).unwrap();
+    assert_eq!(&data[..len], &b"read me"[..len]);
+    assert!(data[len..].iter().all(|&i| i == 0));
+    assert_eq!(list(&fs, "lib").unwrap(), vec!["a.dll", "b.dll"]);
+    assert_eq!(list(&fs, "").unwrap(), vec!["bin", "lib"]);
+    assert!(std::panic::catch_unwind(|| read_all(&fs, "bin\\tool.exe")).is_err());
+  }
+}
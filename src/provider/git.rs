@@ -0,0 +1,698 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use flate2::bufread::ZlibDecoder;
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{check_range, dir_info, sort_entries, unix_filetime, ReadAt};
+
+/// SHA-1 object ID.
+type Oid = [u8; 20];
+
+const COMMIT: u8 = 1;
+const TREE: u8 = 2;
+const BLOB: u8 = 3;
+const TAG: u8 = 4;
+const OFS_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+
+/// Bytes of blobs kept decompressed, for reads of a file in several chunks.
+const BLOB_CACHE: usize = 32 << 20;
+
+fn invalid(msg: String) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, format!("git: {}", msg))
+}
+
+fn not_found(what: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::NotFound, format!("git: {} not found", what))
+}
+
+fn parse_oid(hex: &str) -> Option<Oid> {
+  let hex = hex.as_bytes();
+  if hex.len() != 40 {
+    return None
+  }
+  let mut oid = [0; 20];
+  for (i, pair) in hex.chunks(2).enumerate() {
+    oid[i] = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+  }
+  Some(oid)
+}
+
+fn to_hex(oid: &Oid) -> String {
+  oid.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Little endian base 128 size, as in delta headers.
+fn read_varint<R: Read>(r: &mut R) -> std::io::Result<u64> {
+  let (mut value, mut shift) = (0u64, 0);
+  loop {
+    let mut b = [0];
+    r.read_exact(&mut b)?;
+    value |= ((b[0] & 0x7f) as u64) << shift;
+    shift += 7;
+    if b[0] & 0x80 == 0 || shift > 63 {
+      return Ok(value)
+    }
+  }
+}
+
+/// Applies a pack delta to `base`.
+fn apply_delta(base: &[u8], delta: &[u8]) -> std::io::Result<Vec<u8>> {
+  let mut d = delta;
+  let base_size = read_varint(&mut d)?;
+  let size = read_varint(&mut d)?;
+  if base_size != base.len() as u64 {
+    return Err(invalid("delta base size mismatch".into()))
+  }
+  let mut out = Vec::with_capacity(size as usize);
+  while let Some((&op, rest)) = d.split_first() {
+    d = rest;
+    if op & 0x80 != 0 {
+      let mut field = |bits: std::ops::Range<u8>| {
+        let mut value = 0usize;
+        for (i, bit) in bits.enumerate() {
+          if op & (1 << bit) != 0 {
+            let (&b, rest) = d.split_first().ok_or_else(|| invalid("truncated delta".into()))?;
+            d = rest;
+            value |= (b as usize) << (8 * i);
+          }
+        }
+        Ok::<_, std::io::Error>(value)
+      };
+      let offset = field(0..4)?;
+      let len = match field(4..7)? { 0 => 0x10000, len => len };
+      out.extend_from_slice(base.get(offset..offset + len).ok_or_else(|| invalid("delta copy out of range".into()))?);
+    } else if op != 0 {
+      let data = d.get(..op as usize).ok_or_else(|| invalid("truncated delta".into()))?;
+      out.extend_from_slice(data);
+      d = &d[op as usize..];
+    } else {
+      return Err(invalid("bad delta opcode".into()))
+    }
+  }
+  if out.len() as u64 != size {
+    return Err(invalid("delta result size mismatch".into()))
+  }
+  Ok(out)
+}
+
+/// Pack file with its version 2 index.
+struct Pack {
+  idx: Vec<u8>,
+  pack: File,
+  count: usize,
+}
+
+impl Pack {
+  fn open(idx_path: &Path) -> std::io::Result<Self> {
+    let idx = std::fs::read(idx_path)?;
+    if idx.len() < 8 + 256 * 4 || idx[..8] != [0xff, b't', b'O', b'c', 0, 0, 0, 2] {
+      return Err(invalid(format!("unsupported pack index {}", idx_path.display())))
+    }
+    let count = u32::from_be_bytes(idx[8 + 255 * 4..8 + 256 * 4].try_into().unwrap()) as usize;
+    if idx.len() < 8 + 256 * 4 + count * (20 + 4 + 4) {
+      return Err(invalid(format!("truncated pack index {}", idx_path.display())))
+    }
+    let pack = File::open(idx_path.with_extension("pack"))?;
+    Ok(Self { idx, pack, count })
+  }
+
+  fn be32(&self, at: usize) -> u32 {
+    u32::from_be_bytes(self.idx[at..at + 4].try_into().unwrap())
+  }
+
+  /// Offset of `oid` in the pack.
+  fn find(&self, oid: &Oid) -> Option<u64> {
+    let fanout = |i: usize| self.be32(8 + i * 4) as usize;
+    let mut lo = if oid[0] == 0 { 0 } else { fanout(oid[0] as usize - 1) };
+    let mut hi = fanout(oid[0] as usize);
+    let oids = 8 + 256 * 4;
+    let i = loop {
+      if lo >= hi {
+        return None
+      }
+      let mid = (lo + hi) / 2;
+      match self.idx[oids + mid * 20..oids + mid * 20 + 20].cmp(&oid[..]) {
+        std::cmp::Ordering::Less => lo = mid + 1,
+        std::cmp::Ordering::Greater => hi = mid,
+        std::cmp::Ordering::Equal => break mid,
+      }
+    };
+    let offsets = oids + self.count * 24;
+    let offset = self.be32(offsets + i * 4);
+    if offset & 0x8000_0000 == 0 {
+      return Some(offset as u64)
+    }
+    let at = offsets + self.count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+    Some(u64::from_be_bytes(self.idx.get(at..at + 8)?.try_into().unwrap()))
+  }
+
+  /// Type, inflated size, delta base and a reader of the compressed data of the entry at `offset`.
+  fn entry(&self, offset: u64) -> std::io::Result<(u8, u64, Option<Base>, BufReader<ReadAt<'_>>)> {
+    let mut r = BufReader::new(ReadAt { file: &self.pack, offset });
+    let mut b = [0];
+    r.read_exact(&mut b)?;
+    let kind = (b[0] >> 4) & 7;
+    let mut size = (b[0] & 0x0f) as u64;
+    let mut shift = 4;
+    while b[0] & 0x80 != 0 {
+      r.read_exact(&mut b)?;
+      size |= ((b[0] & 0x7f) as u64) << shift;
+      shift += 7;
+    }
+    let base = match kind {
+      OFS_DELTA => {
+        r.read_exact(&mut b)?;
+        let mut distance = (b[0] & 0x7f) as u64;
+        while b[0] & 0x80 != 0 {
+          r.read_exact(&mut b)?;
+          distance = ((distance + 1) << 7) | (b[0] & 0x7f) as u64;
+        }
+        Some(Base::Offset(offset.checked_sub(distance).ok_or_else(|| invalid("bad delta base".into()))?))
+      },
+      REF_DELTA => {
+        let mut oid = [0; 20];
+        r.read_exact(&mut oid)?;
+        Some(Base::Oid(oid))
+      },
+      _ => None,
+    };
+    Ok((kind, size, base, r))
+  }
+}
+
+enum Base {
+  Offset(u64),
+  Oid(Oid),
+}
+
+/// Object database of a repository: loose objects and packs.
+struct Odb {
+  objects: PathBuf,
+  packs: Vec<Pack>,
+}
+
+impl Odb {
+  fn open(objects: PathBuf) -> std::io::Result<Self> {
+    let mut packs = Vec::new();
+    if let Ok(dir) = std::fs::read_dir(objects.join("pack")) {
+      for entry in dir {
+        let path = entry?.path();
+        if path.extension().is_some_and(|i| i == "idx") {
+          packs.push(Pack::open(&path)?);
+        }
+      }
+    }
+    Ok(Self { objects, packs })
+  }
+
+  fn loose(&self, oid: &Oid) -> std::io::Result<(u8, u64, ZlibDecoder<BufReader<File>>)> {
+    let hex = to_hex(oid);
+    let file = File::open(self.objects.join(&hex[..2]).join(&hex[2..]))?;
+    let mut r = ZlibDecoder::new(BufReader::new(file));
+    let mut header = Vec::new();
+    let mut b = [0];
+    while { r.read_exact(&mut b)?; b[0] != 0 } {
+      header.push(b[0]);
+      if header.len() > 32 {
+        return Err(invalid(format!("bad object header of {}", hex)))
+      }
+    }
+    let header = String::from_utf8_lossy(&header);
+    let (kind, size) = header.split_once(' ').ok_or_else(|| invalid(format!("bad object header of {}", hex)))?;
+    let kind = match kind { "commit" => COMMIT, "tree" => TREE, "blob" => BLOB, "tag" => TAG, _ => 0 };
+    let size = size.parse().map_err(|_| invalid(format!("bad object header of {}", hex)))?;
+    Ok((kind, size, r))
+  }
+
+  fn contains(&self, oid: &Oid) -> bool {
+    let hex = to_hex(oid);
+    self.find(oid).is_some() || self.objects.join(&hex[..2]).join(&hex[2..]).is_file()
+  }
+
+  fn find(&self, oid: &Oid) -> Option<(&Pack, u64)> {
+    self.packs.iter().find_map(|pack| Some((pack, pack.find(oid)?)))
+  }
+
+  fn read(&self, oid: &Oid) -> std::io::Result<(u8, Vec<u8>)> {
+    if let Some((pack, offset)) = self.find(oid) {
+      return self.read_packed(pack, offset)
+    }
+    let (kind, size, mut r) = self.loose(oid).map_err(|e| match e.kind() {
+      std::io::ErrorKind::NotFound => not_found(&format!("object {}", to_hex(oid))),
+      _ => e,
+    })?;
+    let mut data = Vec::with_capacity(size as usize);
+    r.read_to_end(&mut data)?;
+    Ok((kind, data))
+  }
+
+  fn read_packed(&self, pack: &Pack, offset: u64) -> std::io::Result<(u8, Vec<u8>)> {
+    let (kind, size, base, r) = pack.entry(offset)?;
+    let mut data = Vec::with_capacity(size as usize);
+    ZlibDecoder::new(r).take(size).read_to_end(&mut data)?;
+    let (kind, base) = match base {
+      None => return Ok((kind, data)),
+      Some(Base::Offset(offset)) => self.read_packed(pack, offset)?,
+      Some(Base::Oid(oid)) => self.read(&oid)?,
+    };
+    Ok((kind, apply_delta(&base, &data)?))
+  }
+
+  /// Size of the object, without inflating more than its header.
+  fn size(&self, oid: &Oid) -> std::io::Result<u64> {
+    if let Some((pack, offset)) = self.find(oid) {
+      let (_, size, base, r) = pack.entry(offset)?;
+      if base.is_none() {
+        return Ok(size)
+      }
+      let mut delta = ZlibDecoder::new(r);
+      read_varint(&mut delta)?;
+      return read_varint(&mut delta)
+    }
+    Ok(self.loose(oid)?.1)
+  }
+}
+
+#[derive(Clone)]
+struct TreeEntry {
+  name: ProjPathBuf,
+  mode: u32,
+  oid: Oid,
+}
+
+impl TreeEntry {
+  fn is_tree(&self) -> bool {
+    self.mode == 0o40000
+  }
+
+  /// Submodules are projected as empty directories, like a checkout without them initialized.
+  fn is_dir(&self) -> bool {
+    self.is_tree() || self.mode == 0o160000
+  }
+}
+
+/// Commit the projection currently shows.
+#[derive(Clone, Copy)]
+struct Head {
+  commit: Oid,
+  tree: Oid,
+  time: i64,
+}
+
+/// Provider projecting the tree of a commit of a local git repository, like a checkout.
+///
+/// Objects are read straight from the repository, loose or packed. Blob and tree IDs are the
+/// content IDs of the placeholders, so reads serve the blob a placeholder was created from,
+/// and [`checkout`](Self::checkout) tells which placeholders differ in another commit.
+/// All entries carry the commit time, symbolic links are projected as files holding their target,
+/// submodules as empty directories.
+pub struct GitTree {
+  git_dir: PathBuf,
+  odb: Odb,
+  head: RwLock<Head>,
+  trees: Mutex<HashMap<Oid, Arc<Vec<TreeEntry>>>>,
+  /// Most recently used last, within [`BLOB_CACHE`] bytes.
+  blobs: Mutex<Vec<(Oid, Arc<Vec<u8>>)>>,
+  /// Last blob read too big for `blobs`, so reading it in chunks decompresses it once.
+  large: Mutex<Option<(Oid, Arc<Vec<u8>>)>>,
+}
+
+impl GitTree {
+  /// Opens the repository at `path`, a bare repository or the `.git` directory or work tree of one,
+  /// and projects the commit `rev` names: a full object ID, a branch, a tag or `HEAD`.
+  pub fn open<P: Into<PathBuf>>(path: P, rev: &str) -> std::io::Result<Self> {
+    let mut git_dir = path.into();
+    if git_dir.join(".git").is_dir() {
+      git_dir.push(".git");
+    }
+    if !git_dir.join("objects").is_dir() {
+      return Err(not_found(&format!("repository at {}", git_dir.display())))
+    }
+    let odb = Odb::open(git_dir.join("objects"))?;
+    let this = Self {
+      git_dir,
+      odb,
+      head: RwLock::new(Head { commit: [0; 20], tree: [0; 20], time: 0 }),
+      trees: Mutex::new(HashMap::new()),
+      blobs: Mutex::new(Vec::new()),
+      large: Mutex::new(None),
+    };
+    let head = this.resolve(rev)?;
+    *this.head.write().unwrap() = head;
+    Ok(this)
+  }
+
+  /// Hex ID of the projected commit.
+  pub fn commit(&self) -> String {
+    to_hex(&self.head.read().unwrap().commit)
+  }
+
+  /// Switches the projection to the commit `rev` names, returning the paths whose entries
+  /// were added, removed or changed, sorted. Unchanged placeholders keep their content ID.
+  pub fn checkout(&self, rev: &str) -> std::io::Result<Vec<ProjPathBuf>> {
+    let new = self.resolve(rev)?;
+    let old = *self.head.read().unwrap();
+    let mut changed = Vec::new();
+    self.diff(&old.tree, &new.tree, ProjPathBuf::new(), &mut changed)?;
+    *self.head.write().unwrap() = new;
+    changed.sort();
+    Ok(changed)
+  }
+
+  /// Walks two trees side by side, their entries are sorted by name.
+  fn diff(&self, old: &Oid, new: &Oid, path: ProjPathBuf, changed: &mut Vec<ProjPathBuf>) -> std::io::Result<()> {
+    use std::cmp::Ordering::*;
+    let (old, new) = (self.tree(old)?, self.tree(new)?);
+    let (mut old, mut new) = (old.iter().peekable(), new.iter().peekable());
+    loop {
+      let order = match (old.peek(), new.peek()) {
+        (None, None) => return Ok(()),
+        (Some(_), None) => Less,
+        (None, Some(_)) => Greater,
+        (Some(o), Some(n)) => o.name.cmp(&n.name),
+      };
+      match order {
+        Less => changed.push(path.join(&old.next().unwrap().name)),
+        Greater => changed.push(path.join(&new.next().unwrap().name)),
+        Equal => {
+          let (o, n) = (old.next().unwrap(), new.next().unwrap());
+          if o.oid == n.oid && o.mode == n.mode {
+            continue
+          }
+          let child = path.join(&n.name);
+          if o.is_tree() && n.is_tree() {
+            self.diff(&o.oid, &n.oid, child, changed)?;
+          } else {
+            changed.push(child);
+          }
+        },
+      }
+    }
+  }
+
+  /// Commit, tree and commit time of `rev`.
+  fn resolve(&self, rev: &str) -> std::io::Result<Head> {
+    let mut oid = match parse_oid(rev) {
+      Some(oid) => oid,
+      None => self.resolve_ref(rev, 0)?,
+    };
+    loop {
+      let (kind, data) = self.odb.read(&oid)?;
+      let text = String::from_utf8_lossy(&data);
+      let field = |name: &str| text.lines().take_while(|i| !i.is_empty()).find_map(|i| i.strip_prefix(name)?.strip_prefix(' '));
+      match kind {
+        TAG => oid = field("object").and_then(parse_oid).ok_or_else(|| invalid(format!("bad tag {}", to_hex(&oid))))?,
+        COMMIT => {
+          let tree = field("tree").and_then(parse_oid).ok_or_else(|| invalid(format!("bad commit {}", to_hex(&oid))))?;
+          // committer name <email> timestamp timezone
+          let time = field("committer").and_then(|i| i.rsplit(' ').nth(1)?.parse().ok()).unwrap_or(0);
+          return Ok(Head { commit: oid, tree, time: unix_filetime(time) })
+        },
+        _ => return Err(invalid(format!("{} is not a commit", rev))),
+      }
+    }
+  }
+
+  /// Object ID a ref points to, trying the usual `refs/` prefixes like `git rev-parse`.
+  fn resolve_ref(&self, name: &str, depth: usize) -> std::io::Result<Oid> {
+    if depth > 8 {
+      return Err(invalid(format!("symbolic ref loop at {}", name)))
+    }
+    let packed = std::fs::read_to_string(self.git_dir.join("packed-refs")).unwrap_or_default();
+    let candidates = [
+      name.to_string(),
+      format!("refs/{}", name),
+      format!("refs/tags/{}", name),
+      format!("refs/heads/{}", name),
+      format!("refs/remotes/{}", name),
+      format!("refs/remotes/{}/HEAD", name),
+    ];
+    for candidate in &candidates {
+      if let Ok(content) = std::fs::read_to_string(self.git_dir.join(candidate)) {
+        let content = content.trim();
+        if let Some(target) = content.strip_prefix("ref:") {
+          return self.resolve_ref(target.trim(), depth + 1)
+        }
+        if let Some(oid) = parse_oid(content) {
+          return Ok(oid)
+        }
+      }
+      let found = packed.lines()
+        .filter(|i| !i.starts_with('#') && !i.starts_with('^'))
+        .find_map(|i| if i.get(41..)? == candidate { parse_oid(&i[..40]) } else { None });
+      if let Some(oid) = found {
+        return Ok(oid)
+      }
+    }
+    Err(not_found(&format!("revision {}", name)))
+  }
+
+  fn tree(&self, oid: &Oid) -> std::io::Result<Arc<Vec<TreeEntry>>> {
+    if let Some(tree) = self.trees.lock().unwrap().get(oid) {
+      return Ok(tree.clone())
+    }
+    let (kind, data) = self.odb.read(oid)?;
+    if kind != TREE {
+      return Err(invalid(format!("{} is not a tree", to_hex(oid))))
+    }
+    let mut entries = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+      let bad = || invalid(format!("bad tree {}", to_hex(oid)));
+      let space = rest.iter().position(|&b| b == b' ').ok_or_else(bad)?;
+      let nul = rest.iter().position(|&b| b == 0).ok_or_else(bad)?;
+      let mode = u32::from_str_radix(std::str::from_utf8(&rest[..space]).map_err(|_| bad())?, 8).map_err(|_| bad())?;
+      let name = String::from_utf8_lossy(&rest[space + 1..nul]);
+      let oid = rest.get(nul + 1..nul + 21).ok_or_else(bad)?.try_into().unwrap();
+      rest = &rest[nul + 21..];
+      let name = ProjPathBuf::from(&*name);
+      if name.components().count() == 1 {
+        entries.push(TreeEntry { name, mode, oid });
+      }
+    }
+    // names differing only in case cannot all be projected, the first one wins
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries.dedup_by(|a, b| a.name == b.name);
+    let entries = Arc::new(entries);
+    self.trees.lock().unwrap().insert(*oid, entries.clone());
+    Ok(entries)
+  }
+
+  /// Entry at `path`, `None` for the root.
+  fn entry(&self, path: &ProjPath) -> std::io::Result<Option<TreeEntry>> {
+    let mut tree = Some(self.head.read().unwrap().tree);
+    let mut found = None;
+    for component in path.components() {
+      // nothing below files and submodules
+      let entries = self.tree(&tree.ok_or_else(|| not_found(&path.to_string_lossy()))?)?;
+      let entry = entries.binary_search_by(|i| (*i.name).cmp(component)).ok().map(|i| &entries[i]).ok_or_else(|| not_found(&path.to_string_lossy()))?;
+      tree = if entry.is_tree() { Some(entry.oid) } else { None };
+      found = Some(entry.clone());
+    }
+    Ok(found)
+  }
+
+  fn info(&self, entry: &TreeEntry, time: i64) -> std::io::Result<FileBasicInfo> {
+    let mut info = dir_info(&entry.name);
+    info.is_dir = entry.is_dir();
+    if !info.is_dir {
+      info.file_size = self.odb.size(&entry.oid)?;
+    }
+    info.created = time;
+    info.accessed = time;
+    info.writed = time;
+    info.changed = time;
+    info.content_id = Some(entry.oid.to_vec());
+    Ok(info)
+  }
+
+  fn blob(&self, oid: &Oid) -> std::io::Result<Arc<Vec<u8>>> {
+    let mut blobs = self.blobs.lock().unwrap();
+    if let Some(i) = blobs.iter().position(|(k, _)| k == oid) {
+      let hit = blobs.remove(i);
+      let data = hit.1.clone();
+      blobs.push(hit);
+      return Ok(data)
+    }
+    drop(blobs);
+    if let Some((_, data)) = self.large.lock().unwrap().as_ref().filter(|(k, _)| k == oid) {
+      return Ok(data.clone())
+    }
+    let (kind, data) = self.odb.read(oid)?;
+    if kind != BLOB {
+      return Err(invalid(format!("{} is not a blob", to_hex(oid))))
+    }
+    let data = Arc::new(data);
+    if data.len() > BLOB_CACHE {
+      *self.large.lock().unwrap() = Some((*oid, data.clone()));
+      return Ok(data)
+    }
+    let mut blobs = self.blobs.lock().unwrap();
+    let mut bytes = blobs.iter().map(|(_, i)| i.len()).sum::<usize>();
+    while bytes + data.len() > BLOB_CACHE {
+      bytes -= blobs.remove(0).1.len();
+    }
+    blobs.push((*oid, data.clone()));
+    Ok(data)
+  }
+}
+
+impl ProjFSDirEnum for GitTree {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let head = *self.head.read().unwrap();
+    let tree = match self.entry(path)? {
+      None => head.tree,
+      Some(entry) if entry.is_tree() => entry.oid,
+      Some(entry) if entry.is_dir() => return Ok(Vec::new().into_iter()),
+      Some(_) => return Err(std::io::Error::other("not a directory")),
+    };
+    let mut entries = self.tree(&tree)?.iter().map(|i| self.info(i, head.time)).collect::<std::io::Result<Vec<_>>>()?;
+    sort_entries(&mut entries);
+    Ok(entries.into_iter())
+  }
+}
+
+impl ProjFSRead for GitTree {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    let head = *self.head.read().unwrap();
+    match self.entry(path)? {
+      Some(entry) => self.info(&entry, head.time),
+      None => Ok(FileBasicInfo { content_id: Some(head.tree.to_vec()), ..dir_info(path) }),
+    }
+  }
+
+  /// Reads the blob the placeholder was created from if `version` has the ID of an object of the repository,
+  /// otherwise the blob at `path` in the projected commit.
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let placeholder = version.content_id()
+      .filter(|id| id[20..].iter().all(|&i| i == 0))
+      .map(|id| id[..20].try_into().unwrap())
+      .filter(|oid| self.odb.contains(oid));
+    let oid = match placeholder {
+      Some(oid) => oid,
+      None => match self.entry(path)? {
+        Some(entry) if !entry.is_dir() => entry.oid,
+        _ => return Err(not_found(&path.to_string_lossy())),
+      },
+    };
+    let data = self.blob(&oid)?;
+    check_range(data.len() as u64, offset, buf.len())?;
+    buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::process::Command;
+  use crate::provider::testing::*;
+
+  fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git").current_dir(dir)
+      .args(["-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "core.autocrlf=false"])
+      .args(args)
+      .env("GIT_COMMITTER_DATE", "1600000000 +0000")
+      .env("GIT_AUTHOR_DATE", "1600000000 +0000")
+      .output().unwrap();
+    assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+  }
+
+  /// Work tree with two commits tagged `v1` and `v2` on `main`, the tests need git installed.
+  fn repo() -> TempDir {
+    Command::new("git").arg("--version").output().expect("git is needed to build the test repositories");
+    let dir = TempDir::new();
+    git(dir.path(), &["init", "-q"]);
+    git(dir.path(), &["symbolic-ref", "HEAD", "refs/heads/main"]);
+    let big: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+    dir.write("README.md", b"# readme\n");
+    dir.write("src/main.rs", b"fn main() {}\n");
+    dir.write("src/big.txt", big.as_bytes());
+    dir.write("docs/guide.md", b"guide\n");
+    git(dir.path(), &["add", "-A"]);
+    git(dir.path(), &["commit", "-q", "-m", "one"]);
+    git(dir.path(), &["tag", "-a", "v1", "-m", "v1"]);
+    dir.write("src/big.txt", format!("{}one more line\n", big).as_bytes());
+    dir.write("src/new.rs", b"// new\n");
+    std::fs::remove_dir_all(dir.path().join("docs")).unwrap();
+    git(dir.path(), &["add", "-A"]);
+    git(dir.path(), &["commit", "-q", "-m", "two"]);
+    git(dir.path(), &["tag", "v2"]);
+    dir
+  }
+
+  fn check_v1(fs: &GitTree) {
+    assert_eq!(list(fs, "").unwrap(), vec!["docs", "README.md", "src"]);
+    assert_eq!(list(fs, "src").unwrap(), vec!["big.txt", "main.rs"]);
+    assert_eq!(read_all(fs, "SRC\\Main.rs").unwrap(), b"fn main() {}\n");
+    let big = read_all(fs, "src\\big.txt").unwrap();
+    assert!(big.starts_with(b"line 0\n") && big.ends_with(b"line 1999\n"));
+    let info = fs.get_metadata(&ProjPathBuf::from("README.md"), VersionInfo::NONE).unwrap();
+    assert_eq!(info.file_size, 9);
+    assert_eq!(info.writed, unix_filetime(1_600_000_000));
+    assert_eq!(info.content_id.unwrap().len(), 20);
+  }
+
+  #[test]
+  fn loose_objects() {
+    let dir = repo();
+    let fs = GitTree::open(dir.path(), "v1").unwrap();
+    check_v1(&fs);
+    assert_eq!(list(&fs, "src").unwrap(), vec!["big.txt", "main.rs"]);
+    assert!(fs.get_metadata(&ProjPathBuf::from("src\\new.rs"), VersionInfo::NONE).is_err());
+    assert!(fs.get_metadata(&ProjPathBuf::from("README.md\\x"), VersionInfo::NONE).is_err());
+  }
+
+  #[test]
+  fn packed_objects_and_deltas() {
+    let dir = repo();
+    git(dir.path(), &["gc", "-q", "--aggressive"]);
+    assert!(std::fs::read_dir(dir.path().join(".git/objects/pack")).unwrap().count() > 0);
+    // a bare clone has nothing but packs and packed refs
+    git(dir.path(), &["clone", "-q", "--bare", ".", "bare.git"]);
+    let fs = GitTree::open(dir.path().join("bare.git"), "v1").unwrap();
+    check_v1(&fs);
+    let fs = GitTree::open(dir.path().join("bare.git"), "main").unwrap();
+    let big = read_all(&fs, "src\\big.txt").unwrap();
+    assert!(big.ends_with(b"one more line\n"));
+    assert_eq!(fs.commit(), git(dir.path(), &["rev-parse", "v2"]));
+  }
+
+  #[test]
+  fn checkout_reports_changed_paths_and_reads_by_version() {
+    let dir = repo();
+    let fs = GitTree::open(dir.path(), "v1").unwrap();
+    let old = fs.get_metadata(&ProjPathBuf::from("src\\big.txt"), VersionInfo::NONE).unwrap();
+    let changed = fs.checkout("HEAD").unwrap();
+    let changed: Vec<String> = changed.iter().map(|i| i.to_string()).collect();
+    assert_eq!(changed, vec!["docs", "src\\big.txt", "src\\new.rs"]);
+    // a placeholder created from v1 still reads the v1 blob
+    let version = crate::version_info(old.content_id.as_ref().unwrap());
+    let mut buf = vec![0; old.file_size as usize];
+    fs.read(&ProjPathBuf::from("src\\big.txt"), VersionInfo::new(&version), 0, &mut buf).unwrap();
+    assert!(buf.ends_with(b"line 1999\n"));
+    assert!(read_all(&fs, "src\\big.txt").unwrap().ends_with(b"one more line\n"));
+    // IDs of placeholders from elsewhere read what is at the path
+    for id in [&b"not an object id"[..], &[7; 20]] {
+      let version = crate::version_info(id);
+      let mut buf = vec![0; 13];
+      fs.read(&ProjPathBuf::from("src\\main.rs"), VersionInfo::new(&version), 0, &mut buf).unwrap();
+      assert_eq!(buf, b"fn main() {}\n");
+    }
+  }
+
+  #[test]
+  fn deltas() {
+    let base = b"hello world";
+    // copy 5 bytes from 0, insert "!", copy 6 bytes from 5
+    let delta = [11, 12, 0x90, 5, 1, b'!', 0x91, 5, 6];
+    assert_eq!(apply_delta(base, &delta).unwrap(), b"hello! world");
+    assert!(apply_delta(b"short", &delta).is_err());
+  }
+}
@@ -0,0 +1,534 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{check_range, dir_info, sort_entries};
+
+/// Name the default value of a key is projected as, the way regedit shows it.
+pub const DEFAULT_VALUE_NAME: &str = "(Default)";
+
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_DWORD: u32 = 4;
+const REG_DWORD_BIG_ENDIAN: u32 = 5;
+const REG_MULTI_SZ: u32 = 7;
+const REG_QWORD: u32 = 11;
+
+const BASE_BLOCK_SIZE: usize = 4096;
+/// Most data one segment of a big data value holds.
+const SEGMENT_SIZE: usize = 16344;
+const KEY_COMP_NAME: u16 = 0x20;
+const VALUE_COMP_NAME: u16 = 0x1;
+/// Offset standing for no cell.
+const NO_CELL: u32 = u32::MAX;
+
+fn corrupt(what: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, format!("corrupt hive: {}", what))
+}
+
+fn bytes<const N: usize>(data: &[u8], at: usize) -> std::io::Result<[u8; N]> {
+  data.get(at..at + N).and_then(|b| b.try_into().ok()).ok_or_else(|| corrupt("cell too short"))
+}
+
+fn u16_at(data: &[u8], at: usize) -> std::io::Result<u16> {
+  Ok(u16::from_le_bytes(bytes(data, at)?))
+}
+
+fn u32_at(data: &[u8], at: usize) -> std::io::Result<u32> {
+  Ok(u32::from_le_bytes(bytes(data, at)?))
+}
+
+/// Key or value name, Latin-1 if `compressed`, UTF-16 otherwise.
+fn name(raw: &[u8], compressed: bool) -> ProjPathBuf {
+  if compressed {
+    ProjPathBuf::from_wide(raw.iter().map(|&b| b as u16).collect())
+  } else {
+    ProjPathBuf::from_wide(utf16(raw))
+  }
+}
+
+fn utf16(data: &[u8]) -> Vec<u16> {
+  data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect()
+}
+
+/// Content of a value file: strings as UTF-8, numbers in decimal, anything else raw.
+fn encode(kind: u32, data: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+  match (kind, data.len()) {
+    (REG_SZ, _) | (REG_EXPAND_SZ, _) => {
+      let units = utf16(&data);
+      let end = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+      String::from_utf16_lossy(&units[..end]).into_bytes().into()
+    },
+    (REG_MULTI_SZ, _) => {
+      let units = utf16(&data);
+      let strings = units.split(|&u| u == 0).take_while(|s| !s.is_empty()).map(String::from_utf16_lossy).collect::<Vec<_>>();
+      strings.join("\n").into_bytes().into()
+    },
+    (REG_DWORD, 4) => u32::from_le_bytes(data[..].try_into().unwrap()).to_string().into_bytes().into(),
+    (REG_DWORD_BIG_ENDIAN, 4) => u32::from_be_bytes(data[..].try_into().unwrap()).to_string().into_bytes().into(),
+    (REG_QWORD, 8) => u64::from_le_bytes(data[..].try_into().unwrap()).to_string().into_bytes().into(),
+    _ => data,
+  }
+}
+
+/// Value of a key, with its content already encoded.
+struct Value<'a> {
+  name: ProjPathBuf,
+  data: Cow<'a, [u8]>,
+}
+
+/// Provider projecting an offline Windows registry hive file, keys as directories and values as files.
+///
+/// The hive is parsed in pure Rust from a copy in memory, so it works on any platform and callbacks
+/// do not contend on a lock. Values are encoded by type: `REG_SZ` and `REG_EXPAND_SZ` as UTF-8 text,
+/// `REG_MULTI_SZ` as lines, `REG_DWORD` and `REG_QWORD` in decimal, any other type as its raw bytes.
+/// The default value of a key shows up as [`DEFAULT_VALUE_NAME`], values whose name is taken by a subkey
+/// or contains a `\` are left out. Transaction logs are not replayed, a hive that was not flushed cleanly
+/// shows its last flushed state.
+pub struct RegistryHive {
+  path: PathBuf,
+  /// Hive bins, cell offsets are relative to their start.
+  bins: Vec<u8>,
+  root: u32,
+  minor: u32,
+}
+
+impl RegistryHive {
+  pub fn open<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+    let path = path.into();
+    let mut data = std::fs::read(&path)?;
+    if data.len() < BASE_BLOCK_SIZE || &data[..4] != b"regf" {
+      return Err(corrupt("not a registry hive"))
+    }
+    let checksum = (0..127).fold(0, |sum, i| sum ^ u32_at(&data, i * 4).unwrap());
+    let checksum = match checksum { 0 => 1, u32::MAX => u32::MAX - 1, sum => sum };
+    if checksum != u32_at(&data, 508)? {
+      return Err(corrupt("base block checksum mismatch"))
+    }
+    if u32_at(&data, 20)? != 1 {
+      return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "unsupported hive version"))
+    }
+    let minor = u32_at(&data, 24)?;
+    let root = u32_at(&data, 36)?;
+    let bins = data.split_off(BASE_BLOCK_SIZE);
+    if !bins.starts_with(b"hbin") {
+      return Err(corrupt("missing hive bin"))
+    }
+    let this = Self { path, bins, root, minor };
+    this.key(root)?;
+    Ok(this)
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Data of the allocated cell at `offset`.
+  fn cell(&self, offset: u32) -> std::io::Result<&[u8]> {
+    let start = offset as usize;
+    let size = i32::from_le_bytes(bytes(&self.bins, start).map_err(|_| corrupt("cell offset out of range"))?);
+    if size >= 0 {
+      return Err(corrupt("reference to a free cell"))
+    }
+    self.bins.get(start + 4..start + size.unsigned_abs() as usize).ok_or_else(|| corrupt("cell out of range"))
+  }
+
+  fn key(&self, offset: u32) -> std::io::Result<&[u8]> {
+    let cell = self.cell(offset)?;
+    if !cell.starts_with(b"nk") {
+      return Err(corrupt("expected a key"))
+    }
+    Ok(cell)
+  }
+
+  fn key_name(key: &[u8]) -> std::io::Result<ProjPathBuf> {
+    let len = u16_at(key, 72)? as usize;
+    let raw = key.get(76..76 + len).ok_or_else(|| corrupt("key name out of range"))?;
+    Ok(name(raw, u16_at(key, 2)? & KEY_COMP_NAME != 0))
+  }
+
+  fn key_info(key: &[u8]) -> std::io::Result<FileBasicInfo> {
+    let time = i64::from_le_bytes(bytes(key, 4)?);
+    Ok(FileBasicInfo { created: time, accessed: time, writed: time, changed: time, ..dir_info(&Self::key_name(key)?) })
+  }
+
+  /// Offsets of the keys in the subkey list at `offset`, following index roots once.
+  fn subkey_list(&self, offset: u32, nested: bool, keys: &mut Vec<u32>) -> std::io::Result<()> {
+    let list = self.cell(offset)?;
+    let count = u16_at(list, 2)? as usize;
+    match list.get(..2) {
+      Some(b"lf") | Some(b"lh") => for i in 0..count {
+        keys.push(u32_at(list, 4 + i * 8)?);
+      },
+      Some(b"li") => for i in 0..count {
+        keys.push(u32_at(list, 4 + i * 4)?);
+      },
+      Some(b"ri") if !nested => for i in 0..count {
+        self.subkey_list(u32_at(list, 4 + i * 4)?, true, keys)?;
+      },
+      _ => return Err(corrupt("unknown subkey list")),
+    }
+    Ok(())
+  }
+
+  fn subkeys(&self, key: &[u8]) -> std::io::Result<Vec<&[u8]>> {
+    let mut offsets = Vec::new();
+    if u32_at(key, 20)? != 0 {
+      self.subkey_list(u32_at(key, 28)?, false, &mut offsets)?;
+    }
+    offsets.into_iter().map(|offset| self.key(offset)).collect()
+  }
+
+  /// Value cells of a key, with their names.
+  fn value_cells(&self, key: &[u8]) -> std::io::Result<Vec<(ProjPathBuf, &[u8])>> {
+    let count = u32_at(key, 36)? as usize;
+    if count == 0 {
+      return Ok(Vec::new())
+    }
+    let list = self.cell(u32_at(key, 40)?)?;
+    let mut values = Vec::new();
+    for i in 0..count {
+      let value = self.cell(u32_at(list, i * 4)?)?;
+      if !value.starts_with(b"vk") {
+        return Err(corrupt("expected a value"))
+      }
+      let len = u16_at(value, 2)? as usize;
+      let raw = value.get(20..20 + len).ok_or_else(|| corrupt("value name out of range"))?;
+      let name = match len {
+        0 => ProjPathBuf::from(DEFAULT_VALUE_NAME),
+        _ => name(raw, u16_at(value, 16)? & VALUE_COMP_NAME != 0),
+      };
+      values.push((name, value));
+    }
+    Ok(values)
+  }
+
+  fn value<'a>(&'a self, name: ProjPathBuf, value: &'a [u8]) -> std::io::Result<Value<'a>> {
+    Ok(Value { name, data: encode(u32_at(value, 12)?, self.value_data(value)?) })
+  }
+
+  fn value_data<'a>(&'a self, value: &'a [u8]) -> std::io::Result<Cow<'a, [u8]>> {
+    let size = u32_at(value, 4)?;
+    // small data lives in the offset field
+    if size & 0x8000_0000 != 0 {
+      return Ok(bytes::<4>(value, 8)?[..(size & 0x7FFF_FFFF).min(4) as usize].to_vec().into())
+    }
+    let size = size as usize;
+    let offset = u32_at(value, 8)?;
+    if size == 0 || offset == NO_CELL {
+      return Ok(Cow::Borrowed(&[]))
+    }
+    if size > SEGMENT_SIZE && self.minor > 3 {
+      let big = self.cell(offset)?;
+      if !big.starts_with(b"db") {
+        return Err(corrupt("expected big data"))
+      }
+      let segments = self.cell(u32_at(big, 4)?)?;
+      let mut data = Vec::new();
+      for i in 0..u16_at(big, 2)? as usize {
+        let segment = self.cell(u32_at(segments, i * 4)?)?;
+        let len = (size - data.len()).min(SEGMENT_SIZE).min(segment.len());
+        data.extend_from_slice(&segment[..len]);
+      }
+      if data.len() < size {
+        return Err(corrupt("big data shorter than its value"))
+      }
+      return Ok(data.into())
+    }
+    self.cell(offset)?.get(..size).map(Cow::Borrowed).ok_or_else(|| corrupt("value data out of range"))
+  }
+
+  /// Key at `path`, `None` if a component is missing.
+  fn find_key(&self, path: &ProjPath) -> std::io::Result<Option<&[u8]>> {
+    let mut key = self.key(self.root)?;
+    for component in path.components() {
+      let mut found = None;
+      for subkey in self.subkeys(key)? {
+        if *Self::key_name(subkey)? == *component {
+          found = Some(subkey);
+          break
+        }
+      }
+      match found {
+        Some(subkey) => key = subkey,
+        None => return Ok(None),
+      }
+    }
+    Ok(Some(key))
+  }
+
+  /// Value at `path` and the key holding it, only its data is read and encoded.
+  fn find_value(&self, path: &ProjPath) -> std::io::Result<(&[u8], Value<'_>)> {
+    let (parent, name) = match (path.parent(), path.file_name()) {
+      (Some(parent), Some(name)) => (parent, name),
+      _ => return Err(std::io::ErrorKind::NotFound.into()),
+    };
+    let key = self.find_key(parent)?.ok_or(std::io::ErrorKind::NotFound)?;
+    let (name, value) = self.value_cells(key)?.into_iter().find(|(value, _)| **value == *name).ok_or(std::io::ErrorKind::NotFound)?;
+    Ok((key, self.value(name, value)?))
+  }
+}
+
+impl ProjFSDirEnum for RegistryHive {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let key = self.find_key(path)?.ok_or(std::io::ErrorKind::NotFound)?;
+    let subkeys = self.subkeys(key)?;
+    let mut entries = subkeys.iter().map(|subkey| Self::key_info(subkey)).collect::<std::io::Result<Vec<_>>>()?;
+    let names = subkeys.iter().map(|subkey| Self::key_name(subkey)).collect::<std::io::Result<std::collections::HashSet<_>>>()?;
+    let time = Self::key_info(key)?.writed;
+    for (name, value) in self.value_cells(key)? {
+      if names.contains(&name) || name.as_wide().contains(&(b'\\' as u16)) {
+        continue
+      }
+      let value = self.value(name, value)?;
+      entries.push(FileBasicInfo {
+        is_dir: false,
+        file_size: value.data.len() as u64,
+        created: time, accessed: time, writed: time, changed: time,
+        ..dir_info(&value.name)
+      });
+    }
+    sort_entries(&mut entries);
+    Ok(entries.into_iter())
+  }
+}
+
+impl ProjFSRead for RegistryHive {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    if let Some(key) = self.find_key(path)? {
+      let info = Self::key_info(key)?;
+      // the root key has a name of its own
+      return Ok(if path.is_empty() { FileBasicInfo { file_name: PathBuf::new(), ..info } } else { info })
+    }
+    let (key, value) = self.find_value(path)?;
+    let time = Self::key_info(key)?.writed;
+    Ok(FileBasicInfo {
+      is_dir: false,
+      file_size: value.data.len() as u64,
+      created: time, accessed: time, writed: time, changed: time,
+      ..dir_info(&value.name)
+    })
+  }
+
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let (_, value) = self.find_value(path)?;
+    check_range(value.data.len() as u64, offset, buf.len())?;
+    buf.copy_from_slice(&value.data[offset as usize..offset as usize + buf.len()]);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  const TIME: u64 = 132_000_000_000_000_000;
+  const NONE: (usize, u32) = (0, NO_CELL);
+
+  /// Builds a hive with one bin, cells are appended in order.
+  struct Writer {
+    bins: Vec<u8>,
+  }
+
+  impl Writer {
+    fn new() -> Self {
+      let mut bins = vec![0; 32];
+      bins[..4].copy_from_slice(b"hbin");
+      Self { bins }
+    }
+
+    fn cell(&mut self, data: &[u8]) -> u32 {
+      let offset = self.bins.len();
+      let size = (data.len() + 4 + 7) & !7;
+      self.bins.extend_from_slice(&(-(size as i32)).to_le_bytes());
+      self.bins.extend_from_slice(data);
+      self.bins.resize(offset + size, 0);
+      offset as u32
+    }
+
+    fn list(&mut self, signature: &[u8; 2], offsets: &[u32]) -> (usize, u32) {
+      let mut cell = signature.to_vec();
+      cell.extend_from_slice(&(offsets.len() as u16).to_le_bytes());
+      for offset in offsets {
+        cell.extend_from_slice(&offset.to_le_bytes());
+        if signature == b"lf" {
+          cell.extend_from_slice(&[0; 4]);
+        }
+      }
+      (offsets.len(), self.cell(&cell))
+    }
+
+    fn value(&mut self, name: &str, kind: u32, data: &[u8]) -> u32 {
+      let (size, offset) = if data.len() <= 4 {
+        let mut inline = [0; 4];
+        inline[..data.len()].copy_from_slice(data);
+        (data.len() as u32 | 0x8000_0000, u32::from_le_bytes(inline))
+      } else if data.len() > SEGMENT_SIZE {
+        let segments = data.chunks(SEGMENT_SIZE).map(|chunk| self.cell(chunk)).collect::<Vec<_>>();
+        let list = self.cell(&segments.iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>());
+        let mut big = b"db".to_vec();
+        big.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        big.extend_from_slice(&list.to_le_bytes());
+        (data.len() as u32, self.cell(&big))
+      } else {
+        (data.len() as u32, self.cell(data))
+      };
+      let mut cell = b"vk".to_vec();
+      cell.extend_from_slice(&(name.len() as u16).to_le_bytes());
+      cell.extend_from_slice(&size.to_le_bytes());
+      cell.extend_from_slice(&offset.to_le_bytes());
+      cell.extend_from_slice(&kind.to_le_bytes());
+      cell.extend_from_slice(&VALUE_COMP_NAME.to_le_bytes());
+      cell.extend_from_slice(&[0; 2]);
+      cell.extend_from_slice(name.as_bytes());
+      self.cell(&cell)
+    }
+
+    /// Key named in Latin-1 if ASCII, in UTF-16 otherwise.
+    fn key(&mut self, name: &str, subkeys: (usize, u32), values: &[u32]) -> u32 {
+      let value_list = if values.is_empty() { NO_CELL } else { self.cell(&values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>()) };
+      let (flags, name) = match name.is_ascii() {
+        true => (KEY_COMP_NAME, name.as_bytes().to_vec()),
+        false => (0, name.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+      };
+      let mut cell = vec![0; 76];
+      cell[..2].copy_from_slice(b"nk");
+      cell[2..4].copy_from_slice(&flags.to_le_bytes());
+      cell[4..12].copy_from_slice(&TIME.to_le_bytes());
+      cell[20..24].copy_from_slice(&(subkeys.0 as u32).to_le_bytes());
+      cell[28..32].copy_from_slice(&subkeys.1.to_le_bytes());
+      cell[36..40].copy_from_slice(&(values.len() as u32).to_le_bytes());
+      cell[40..44].copy_from_slice(&value_list.to_le_bytes());
+      cell[72..74].copy_from_slice(&(name.len() as u16).to_le_bytes());
+      cell.extend_from_slice(&name);
+      self.cell(&cell)
+    }
+
+    fn finish(mut self, root: u32) -> Vec<u8> {
+      let len = (self.bins.len() + 4095) & !4095;
+      self.bins.resize(len, 0);
+      self.bins[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+      let mut base = vec![0; BASE_BLOCK_SIZE];
+      base[..4].copy_from_slice(b"regf");
+      for (at, field) in [(4, 1), (8, 1), (20, 1), (24, 5), (32, 1), (36, root), (40, len as u32), (44, 1)] {
+        base[at..at + 4].copy_from_slice(&field.to_le_bytes());
+      }
+      let checksum = (0..127).fold(0, |sum, i| sum ^ u32::from_le_bytes(base[i * 4..i * 4 + 4].try_into().unwrap()));
+      base[508..512].copy_from_slice(&checksum.to_le_bytes());
+      base.extend_from_slice(&self.bins);
+      base
+    }
+  }
+
+  fn utf16z(strings: &[&str]) -> Vec<u8> {
+    strings.iter().flat_map(|s| s.encode_utf16().chain(Some(0))).chain(Some(0)).flat_map(u16::to_le_bytes).collect()
+  }
+
+  fn sample() -> Vec<u8> {
+    let mut w = Writer::new();
+    let large = (0..40_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let values = [
+      w.value("", REG_SZ, &utf16z(&["default"])),
+      w.value("Name", REG_SZ, &utf16z(&["Exämple"])),
+      w.value("Path", REG_EXPAND_SZ, &utf16z(&["%SystemRoot%"])),
+      w.value("Count", REG_DWORD, &42u32.to_le_bytes()),
+      w.value("Big", REG_QWORD, &u64::MAX.to_le_bytes()),
+      w.value("Lines", REG_MULTI_SZ, &utf16z(&["a", "b"])),
+      w.value("Blob", 3, &[1, 2, 3]),
+      w.value("Large", 3, &large),
+      w.value("a\\b", REG_SZ, &[]),
+    ];
+    let unicode = w.key("Ünïcode", NONE, &[]);
+    let vendor_list = w.list(b"li", &[unicode]);
+    let vendor = w.key("Vendor", vendor_list, &values);
+    let software_list = w.list(b"lh", &[vendor]);
+    let software = w.key("Software", software_list, &[]);
+    let (a, b) = (w.key("A", NONE, &[]), w.key("B", NONE, &[]));
+    let (first, second) = (w.list(b"li", &[b]).1, w.list(b"li", &[a]).1);
+    let system_list = w.list(b"ri", &[first, second]);
+    let system = w.key("System", (2, system_list.1), &[]);
+    let shadowed = w.value("software", REG_SZ, &[]);
+    let root_list = w.list(b"lf", &[system, software]);
+    let root = w.key("ROOT", root_list, &[shadowed]);
+    w.finish(root)
+  }
+
+  fn hive(data: &[u8]) -> std::io::Result<(TempDir, RegistryHive)> {
+    let dir = TempDir::new();
+    dir.write("SOFTWARE", data);
+    let hive = RegistryHive::open(dir.path().join("SOFTWARE"))?;
+    Ok((dir, hive))
+  }
+
+  #[test]
+  fn projects_keys_and_values() {
+    let (_dir, fs) = hive(&sample()).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["Software", "System"]);
+    assert_eq!(list(&fs, "system").unwrap(), vec!["A", "B"]);
+    assert_eq!(list(&fs, "Software\\Vendor").unwrap(), vec!["(Default)", "Big", "Blob", "Count", "Large", "Lines", "Name", "Path", "Ünïcode"]);
+    assert_eq!(list(&fs, "SOFTWARE\\VENDOR\\ÜNÏCODE").unwrap(), Vec::<String>::new());
+    let info = fs.get_metadata(&ProjPathBuf::from("software\\vendor\\ünïcode"), VersionInfo::NONE).unwrap();
+    assert!(info.is_dir && info.writed == TIME as i64);
+    assert!(fs.get_metadata(&ProjPathBuf::from("Software\\Vendor\\missing"), VersionInfo::NONE).is_err());
+  }
+
+  #[test]
+  fn value_encodings() {
+    let (_dir, fs) = hive(&sample()).unwrap();
+    let read = |name: &str| read_all(&fs, &format!("Software\\Vendor\\{}", name)).unwrap();
+    assert_eq!(read("(Default)"), b"default");
+    assert_eq!(read("name"), "Exämple".as_bytes());
+    assert_eq!(read("Path"), b"%SystemRoot%");
+    assert_eq!(read("Count"), b"42");
+    assert_eq!(read("Big"), u64::MAX.to_string().as_bytes());
+    assert_eq!(read("Lines"), b"a\nb");
+    assert_eq!(read("Blob"), [1, 2, 3]);
+    assert_eq!(read("Large"), (0..40_000u32).map(|i| i as u8).collect::<Vec<_>>());
+    let info = fs.get_metadata(&ProjPathBuf::from("Software\\Vendor\\Count"), VersionInfo::NONE).unwrap();
+    assert_eq!((info.is_dir, info.file_size, info.writed), (false, 2, TIME as i64));
+    let mut buf = [0; 2];
+    fs.read(&ProjPathBuf::from("Software\\Vendor\\Large"), VersionInfo::NONE, 39_998, &mut buf).unwrap();
+    assert!(fs.read(&ProjPathBuf::from("Software\\Vendor\\Large"), VersionInfo::NONE, 39_999, &mut buf).is_err());
+  }
+
+  #[test]
+  fn corrupt_hives() {
+    let mut data = sample();
+    data[0] = b'R';
+    assert_eq!(hive(&data).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    let mut data = sample();
+    data[12] ^= 1;
+    assert_eq!(hive(&data).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    // a key list pointing past the end fails where it is reached
+    let mut w = Writer::new();
+    let root = w.key("ROOT", (1, 0x00FF_FFF0), &[]);
+    let (_dir, fs) = hive(&w.finish(root)).unwrap();
+    assert_eq!(list(&fs, "").unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+  }
+
+  /// Hive laid out the way Windows writes one: several bins, free cells, a security cell, hashed `lh` lists,
+  /// class names, a value list with slack and a UTF-16 value name.
+  #[test]
+  fn windows_layout_fixture() {
+    let fs = RegistryHive::open(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/SOFTWARE")).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["Classes", "Microsoft"]);
+    assert_eq!(list(&fs, "Microsoft").unwrap(), vec!["Windows", "Windows NT"]);
+    let version = "Microsoft\\Windows NT\\CurrentVersion";
+    assert_eq!(list(&fs, version).unwrap(), vec![
+      "(Default)", "CurrentBuildNumber", "CurrentMajorVersionNumber", "DigitalProductId", "DigitalProductId4",
+      "InstallTime", "LicenseInfo", "Naïve €", "ProductName", "SystemRoot",
+    ]);
+    let read = |name: &str| read_all(&fs, &format!("{}\\{}", version, name)).unwrap();
+    assert_eq!(read("ProductName"), b"Windows 10 Pro");
+    assert_eq!(read("currentmajorversionnumber"), b"10");
+    assert_eq!(read("SystemRoot"), b"C:\\WINDOWS");
+    assert_eq!(read("Naïve €"), b"utf-16 name");
+    assert_eq!(read("(Default)"), b"current");
+    assert_eq!(read("LicenseInfo"), (0..3000).map(|i| (i % 253) as u8).collect::<Vec<_>>());
+    assert_eq!(read_all(&fs, "Microsoft\\Windows\\App Paths\\Dirs").unwrap(), b"C:\\Tools\nD:\\Bin");
+    let info = fs.get_metadata(&ProjPathBuf::from(version), VersionInfo::NONE).unwrap();
+    assert_eq!((info.is_dir, info.writed), (true, 133_000_000_000_000_000));
+  }
+}
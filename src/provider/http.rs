@@ -0,0 +1,380 @@
+use std::io::Read;
+use std::time::Duration;
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, VersionInfo, PLACEHOLDER_ID_LENGTH};
+use super::index::Index;
+use super::{check_range, dir_info, relative_path, trim_id, unix_filetime};
+
+/// Connection settings of an [`HttpTree`].
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+  /// Limit on a whole request, body included.
+  pub timeout: Duration,
+  /// Keep-alive connections kept open per host between requests.
+  pub idle_connections: usize,
+}
+
+impl Default for HttpOptions {
+  fn default() -> Self {
+    Self { timeout: Duration::from_secs(30), idle_connections: 8 }
+  }
+}
+
+/// File listed in the manifest.
+struct Remote {
+  /// URL relative to the manifest, percent-encoded.
+  url: String,
+  etag: Option<String>,
+}
+
+/// Request failure, transient ones of a kind [`is_transient`](super::is_transient) knows.
+fn request_error(e: ureq::Error) -> std::io::Error {
+  match e {
+    ureq::Error::Timeout(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, e),
+    ureq::Error::ConnectionFailed => std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e),
+    ureq::Error::HostNotFound => std::io::Error::new(std::io::ErrorKind::HostUnreachable, e),
+    e => e.into_io(),
+  }
+}
+
+/// Body read failure, a connection dropped halfway is worth another attempt.
+fn body_error(e: std::io::Error) -> std::io::Error {
+  match e.kind() {
+    std::io::ErrorKind::UnexpectedEof => std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e),
+    _ => e,
+  }
+}
+
+fn status_error(url: &str, status: u16) -> std::io::Error {
+  let kind = match status {
+    404 | 410 => return std::io::ErrorKind::NotFound.into(),
+    412 => return std::io::Error::other(format!("{} changed on the server", url)),
+    408 | 504 => std::io::ErrorKind::TimedOut,
+    429 | 500 | 502 | 503 => std::io::ErrorKind::ResourceBusy,
+    _ => std::io::ErrorKind::Other,
+  };
+  std::io::Error::new(kind, format!("{}: HTTP {}", url, status))
+}
+
+fn invalid_manifest(line: usize, reason: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, format!("manifest line {}: {}", line + 1, reason))
+}
+
+/// Percent-encodes `path` for a URL, keeping `/`.
+fn encode(path: &str) -> String {
+  let mut url = String::with_capacity(path.len());
+  for b in path.bytes() {
+    if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+      url.push(b as char);
+    } else {
+      url.push_str(&format!("%{:02X}", b));
+    }
+  }
+  url
+}
+
+/// Start of a `Content-Range: bytes start-end/size` header.
+fn content_range_start(value: &str) -> Option<u64> {
+  value.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+/// Provider projecting a file tree listed in a manifest served over HTTP.
+///
+/// The manifest is a text file with one entry per line, tab separated: size in bytes, modification time
+/// in unix seconds, ETag and the path relative to the manifest with `/` separators and a trailing `/` for directories.
+/// `-` stands for an unknown time or ETag, empty lines and lines starting with `#` are skipped.
+/// Files are fetched from their path resolved against the manifest URL.
+///
+/// Reads turn into `Range` requests over a pool of keep-alive connections. Connection errors, timeouts
+/// and 408, 429 or 5xx responses fail with errors [`is_transient`](super::is_transient) accepts, wrap the tree in [`Retry`](super::Retry)
+/// to retry them. Strong ETags become placeholder content IDs and are sent back in `If-Match`, so a file changed
+/// on the server fails to read instead of mixing two versions. Weak `W/` ETags still tell versions apart but are
+/// not sent, `If-Match` never matches them.
+/// Only `http://` URLs are supported unless the `https` feature is enabled.
+pub struct HttpTree {
+  /// Manifest URL up to its last `/`.
+  base: String,
+  agent: ureq::Agent,
+  index: Index<Remote>,
+}
+
+impl HttpTree {
+  pub fn open(manifest: &str, options: HttpOptions) -> std::io::Result<Self> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+      .http_status_as_error(false)
+      .timeout_global(Some(options.timeout))
+      .max_idle_connections_per_host(options.idle_connections)
+      .build()
+      .into();
+    let base = manifest[..manifest.rfind('/').map_or(0, |i| i + 1)].to_string();
+    let mut response = agent.get(manifest).call().map_err(request_error)?;
+    let text = match response.status().as_u16() {
+      200 => response.body_mut().read_to_string().map_err(request_error)?,
+      status => return Err(status_error(manifest, status)),
+    };
+    Ok(Self { base, agent, index: Self::parse(&text)? })
+  }
+
+  fn parse(text: &str) -> std::io::Result<Index<Remote>> {
+    let mut index = Index::new();
+    for (n, line) in text.lines().enumerate() {
+      if line.is_empty() || line.starts_with('#') {
+        continue
+      }
+      let fields = line.splitn(4, '\t').collect::<Vec<_>>();
+      let (size, mtime, etag, path) = match fields[..] {
+        [size, mtime, etag, path] => (size, mtime, etag, path),
+        _ => return Err(invalid_manifest(n, "expected size, time, ETag and path")),
+      };
+      let size = size.parse::<u64>().map_err(|_| invalid_manifest(n, "invalid size"))?;
+      let mtime = match mtime {
+        "-" => 0,
+        mtime => unix_filetime(mtime.parse().map_err(|_| invalid_manifest(n, "invalid time"))?),
+      };
+      let etag = Some(etag).filter(|&etag| etag != "-").map(str::to_string);
+      let (path, is_dir) = match path.strip_suffix('/') {
+        Some(path) => (path, true),
+        None => (path, false),
+      };
+      let projected = relative_path(path).ok_or_else(|| invalid_manifest(n, "invalid path"))?;
+      let mut info = dir_info(&projected);
+      info.created = mtime;
+      info.accessed = mtime;
+      info.writed = mtime;
+      info.changed = mtime;
+      if is_dir {
+        index.insert_dir(&projected, info);
+      } else {
+        info.file_size = size;
+        // an ETag cut short would never match again
+        info.content_id = etag.as_ref().filter(|etag| etag.len() <= PLACEHOLDER_ID_LENGTH).map(|etag| etag.as_bytes().to_vec());
+        index.insert_file(&projected, info, Remote { url: encode(path), etag });
+      }
+    }
+    Ok(index)
+  }
+
+  pub fn base_url(&self) -> &str {
+    &self.base
+  }
+}
+
+impl ProjFSDirEnum for HttpTree {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.index.dir_iter(path)
+  }
+}
+
+impl ProjFSRead for HttpTree {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.index.metadata(path)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let (info, remote) = self.index.file(path)?;
+    check_range(info.file_size, offset, buf.len())?;
+    if buf.is_empty() {
+      return Ok(())
+    }
+    // the placeholder being hydrated holds the ETag it was created from
+    let etag = match version.content_id() {
+      Some(id) => Some(String::from_utf8_lossy(&trim_id(id)).into_owned()),
+      None => remote.etag.clone(),
+    };
+    let url = format!("{}{}", self.base, remote.url);
+    let range = format!("bytes={}-{}", offset, offset + buf.len() as u64 - 1);
+    let mut request = self.agent.get(&url).header("Range", &range);
+    if let Some(etag) = etag.filter(|etag| !etag.starts_with("W/")) {
+      request = request.header("If-Match", etag);
+    }
+    let mut response = request.call().map_err(request_error)?;
+    let skip = match response.status().as_u16() {
+      206 => match response.headers().get("Content-Range").and_then(|v| v.to_str().ok()).and_then(content_range_start) {
+        Some(start) if start == offset => 0,
+        _ => return Err(std::io::Error::other(format!("{}: unexpected Content-Range", url))),
+      },
+      // the server ignored the range
+      200 => offset,
+      status => return Err(status_error(&url, status)),
+    };
+    let mut body = response.body_mut().as_reader();
+    std::io::copy(&mut (&mut body).take(skip), &mut std::io::sink()).map_err(body_error)?;
+    body.read_exact(buf).map_err(body_error)?;
+    // the connection goes back to the pool once the body has ended, a full response is dropped instead
+    if skip == 0 {
+      std::io::copy(&mut body, &mut std::io::sink()).map_err(body_error)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ProjPathBuf;
+  use crate::provider::{is_transient, CallKind, Retry, RetryOptions};
+  use crate::provider::testing::*;
+  use std::collections::HashMap;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Mutex};
+
+  /// Files served by path with their ETag.
+  type Files = Arc<Mutex<HashMap<String, (Vec<u8>, String)>>>;
+
+  /// Local HTTP/1.1 server with keep-alive, `Range` and `If-Match`.
+  struct Server {
+    url: String,
+    files: Files,
+    connections: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+    /// Requests to answer with 503 before serving again.
+    failures: Arc<AtomicUsize>,
+  }
+
+  impl Server {
+    fn start(files: &[(&str, &[u8])]) -> Self {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let url = format!("http://{}/", listener.local_addr().unwrap());
+      let files = files.iter().enumerate().map(|(i, (path, data))| (path.to_string(), (data.to_vec(), format!("\"v{}\"", i)))).collect();
+      let server = Server {
+        url,
+        files: Arc::new(Mutex::new(files)),
+        connections: Default::default(),
+        requests: Default::default(),
+        failures: Default::default(),
+      };
+      let (files, connections, requests, failures) = (server.files.clone(), server.connections.clone(), server.requests.clone(), server.failures.clone());
+      std::thread::spawn(move || for stream in listener.incoming() {
+        connections.fetch_add(1, Ordering::SeqCst);
+        let (files, requests, failures) = (files.clone(), requests.clone(), failures.clone());
+        std::thread::spawn(move || Self::serve(stream.unwrap(), &files, &requests, &failures));
+      });
+      server
+    }
+
+    fn serve(stream: TcpStream, files: &Files, requests: &AtomicUsize, failures: &AtomicUsize) -> Option<()> {
+      let mut reader = BufReader::new(stream.try_clone().ok()?);
+      let mut stream = stream;
+      loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|&n| n > 0)?;
+        let path = line.split(' ').nth(1)?.trim_start_matches('/').to_string();
+        let mut headers = HashMap::new();
+        loop {
+          let mut header = String::new();
+          reader.read_line(&mut header).ok()?;
+          match header.trim_end().split_once(": ") {
+            Some((name, value)) => headers.insert(name.to_ascii_lowercase(), value.to_string()),
+            None => break,
+          };
+        }
+        requests.fetch_add(1, Ordering::SeqCst);
+        let file = files.lock().unwrap().get(&path).cloned();
+        let (status, extra, body) = match file {
+          _ if failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() => ("503 Service Unavailable", String::new(), Vec::new()),
+          None => ("404 Not Found", String::new(), Vec::new()),
+          // strong comparison, as RFC 9110 asks of If-Match
+          Some((_, etag)) if headers.get("if-match").is_some_and(|m| *m != etag || m.starts_with("W/")) => ("412 Precondition Failed", String::new(), Vec::new()),
+          Some((data, etag)) => match headers.get("range").and_then(|r| r.strip_prefix("bytes=")?.split_once('-')) {
+            Some((start, end)) => {
+              let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?.min(data.len() - 1));
+              ("206 Partial Content", format!("ETag: {}\r\nContent-Range: bytes {}-{}/{}\r\n", etag, start, end, data.len()), data[start..=end].to_vec())
+            },
+            None => ("200 OK", format!("ETag: {}\r\n", etag), data),
+          },
+        };
+        // in one write, a separate body would wait on a delayed ACK
+        let mut response = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n", status, extra, body.len()).into_bytes();
+        response.extend_from_slice(&body);
+        stream.write_all(&response).ok()?;
+      }
+    }
+
+    fn etag(&self, path: &str) -> String {
+      self.files.lock().unwrap()[path].1.clone()
+    }
+  }
+
+  const MANIFEST: &str = "# size\ttime\tetag\tpath\n0\t1600000000\t-\tassets/\n5\t1600000000\t\"v1\"\tassets/hello world.txt\n100000\t-\t\"v2\"\tdata/big.bin\n";
+
+  fn server() -> Server {
+    let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    Server::start(&[("manifest.txt", MANIFEST.as_bytes()), ("assets/hello%20world.txt", b"hello"), ("data/big.bin", &big)])
+  }
+
+  fn open(server: &Server) -> Retry<HttpTree> {
+    let fs = HttpTree::open(&format!("{}manifest.txt", server.url), HttpOptions::default()).unwrap();
+    Retry::with_options(fs, RetryOptions { backoff: Duration::from_millis(1), ..Default::default() })
+  }
+
+  #[test]
+  fn projects_manifest() {
+    let server = server();
+    let fs = HttpTree::open(&format!("{}manifest.txt", server.url), HttpOptions::default()).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["assets", "data"]);
+    assert_eq!(read_all(&fs, "ASSETS\\hello world.txt").unwrap(), b"hello");
+    let info = fs.get_metadata(&ProjPathBuf::from("data\\big.bin"), VersionInfo::NONE).unwrap();
+    assert_eq!((info.file_size, info.content_id), (100_000, Some(server.etag("data/big.bin").into_bytes())));
+    assert_eq!(fs.get_metadata(&ProjPathBuf::from("assets"), VersionInfo::NONE).unwrap().writed, unix_filetime(1_600_000_000));
+    let mut buf = [0; 4];
+    fs.read(&ProjPathBuf::from("data\\big.bin"), VersionInfo::NONE, 99_996, &mut buf).unwrap();
+    assert_eq!(buf, [(99_996u32 as u8), (99_997u32 as u8), (99_998u32 as u8), (99_999u32 as u8)]);
+    assert!(fs.read(&ProjPathBuf::from("data\\big.bin"), VersionInfo::NONE, 99_997, &mut buf).is_err());
+    // one request per read, over a single kept-alive connection
+    let requests = server.requests.load(Ordering::SeqCst);
+    for offset in 0..10 {
+      fs.read(&ProjPathBuf::from("data\\big.bin"), VersionInfo::NONE, offset, &mut buf).unwrap();
+    }
+    assert_eq!(server.requests.load(Ordering::SeqCst), requests + 10);
+    assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn transient_errors_are_left_to_retry() {
+    let server = server();
+    server.failures.store(1, Ordering::SeqCst);
+    let e = HttpTree::open(&format!("{}manifest.txt", server.url), HttpOptions::default()).err().unwrap();
+    assert!(is_transient(&e), "{}", e);
+    let fs = open(&server);
+    server.failures.store(3, Ordering::SeqCst);
+    let mut buf = [0; 5];
+    fs.read(&ProjPathBuf::from("assets\\hello world.txt"), VersionInfo::NONE, 0, &mut buf).unwrap();
+    assert_eq!((&buf, fs.retries(CallKind::Read)), (b"hello", 3));
+    server.failures.store(4, Ordering::SeqCst);
+    assert!(fs.read(&ProjPathBuf::from("assets\\hello world.txt"), VersionInfo::NONE, 0, &mut buf).is_err());
+    assert_eq!(HttpTree::open(&format!("{}missing.txt", server.url), HttpOptions::default()).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+  }
+
+  #[test]
+  fn etag_mismatch() {
+    let server = server();
+    let fs = open(&server);
+    let old = fs.get_metadata(&ProjPathBuf::from("assets\\hello world.txt"), VersionInfo::NONE).unwrap();
+    let version = crate::version_info(old.content_id.as_ref().unwrap());
+    let mut buf = [0; 5];
+    fs.read(&ProjPathBuf::from("assets\\hello world.txt"), VersionInfo::new(&version), 0, &mut buf).unwrap();
+    server.files.lock().unwrap().get_mut("assets/hello%20world.txt").unwrap().1 = "\"v9\"".into();
+    let requests = server.requests.load(Ordering::SeqCst);
+    assert!(fs.read(&ProjPathBuf::from("assets\\hello world.txt"), VersionInfo::new(&version), 0, &mut buf).is_err());
+    // a changed file is not retried
+    assert_eq!(server.requests.load(Ordering::SeqCst), requests + 1);
+    // If-Match never matches a weak ETag, reads of its placeholders go without
+    let server = Server::start(&[("manifest.txt", b"5\t-\tW/\"v1\"\thello.txt\n"), ("hello.txt", b"hello")]);
+    server.files.lock().unwrap().get_mut("hello.txt").unwrap().1 = "W/\"v1\"".into();
+    let fs = open(&server);
+    let weak = fs.get_metadata(&ProjPathBuf::from("hello.txt"), VersionInfo::NONE).unwrap();
+    let weak = crate::version_info(weak.content_id.as_ref().unwrap());
+    fs.read(&ProjPathBuf::from("hello.txt"), VersionInfo::new(&weak), 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+  }
+
+  #[test]
+  fn invalid_manifests() {
+    for manifest in ["5\t-\t-", "x\t-\t-\ta", "5\tnow\t-\ta", "5\t-\t-\ta/../b", "5\t-\t-\t/a"] {
+      let err = HttpTree::parse(manifest).err().unwrap();
+      assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}", manifest);
+    }
+  }
+}
@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::{FileBasicInfo, ProjPath, ProjPathBuf};
+use super::dir_info;
+
+/// Tree of entries built up front, for providers whose backend is a flat list of paths.
+///
+/// Children are kept sorted in ProjFS collation order, directories leading to an entry
+/// are implied if the backend does not list them.
+pub(crate) struct Index<D> {
+  /// Children of every directory, keyed by the directory path.
+  dirs: HashMap<ProjPathBuf, BTreeMap<ProjPathBuf, (FileBasicInfo, Option<D>)>>,
+}
+
+impl<D> Index<D> {
+  pub fn new() -> Self {
+    let mut dirs = HashMap::new();
+    dirs.insert(ProjPathBuf::new(), BTreeMap::new());
+    Self { dirs }
+  }
+
+  /// Adds a directory at `path`, replacing an implied one.
+  pub fn insert_dir(&mut self, path: &ProjPath, info: FileBasicInfo) {
+    if let Some(name) = path.file_name() {
+      let parent = self.ensure_dir(path.parent().unwrap_or(path));
+      parent.insert(name.to_owned(), (FileBasicInfo { file_name: name.to_path_buf(), is_dir: true, file_size: 0, ..info }, None));
+      self.dirs.entry(path.to_owned()).or_default();
+    }
+  }
+
+  /// Adds a file at `path`, replacing an earlier one. A directory at `path` is kept.
+  pub fn insert_file(&mut self, path: &ProjPath, info: FileBasicInfo, data: D) {
+    if let Some(name) = path.file_name() {
+      if self.dirs.contains_key(path) {
+        return
+      }
+      let parent = self.ensure_dir(path.parent().unwrap_or(path));
+      parent.insert(name.to_owned(), (FileBasicInfo { file_name: name.to_path_buf(), is_dir: false, ..info }, Some(data)));
+    }
+  }
+
+  /// Children of `path`, creating it and its ancestors as implied directories.
+  fn ensure_dir(&mut self, path: &ProjPath) -> &mut BTreeMap<ProjPathBuf, (FileBasicInfo, Option<D>)> {
+    if !self.dirs.contains_key(path) {
+      if let Some(name) = path.file_name() {
+        let parent = self.ensure_dir(path.parent().unwrap_or(path));
+        // a file in the way becomes the directory
+        parent.insert(name.to_owned(), (dir_info(name), None));
+      }
+      self.dirs.insert(path.to_owned(), BTreeMap::new());
+    }
+    self.dirs.get_mut(path).unwrap()
+  }
+
+  fn get(&self, path: &ProjPath) -> Option<&(FileBasicInfo, Option<D>)> {
+    self.dirs.get(path.parent()?)?.get(path.file_name()?)
+  }
+
+  pub fn dir_iter(&self, path: &ProjPath) -> std::io::Result<std::vec::IntoIter<FileBasicInfo>> {
+    let children = self.dirs.get(path).ok_or(std::io::ErrorKind::NotFound)?;
+    Ok(children.values().map(|(info, _)| info.clone()).collect::<Vec<_>>().into_iter())
+  }
+
+  pub fn metadata(&self, path: &ProjPath) -> std::io::Result<FileBasicInfo> {
+    if path.is_empty() {
+      return Ok(dir_info(path))
+    }
+    self.get(path).map(|(info, _)| info.clone()).ok_or_else(|| std::io::ErrorKind::NotFound.into())
+  }
+
+  /// Metadata and data of the file at `path`, `NotFound` for directories.
+  pub fn file(&self, path: &ProjPath) -> std::io::Result<(&FileBasicInfo, &D)> {
+    match self.get(path) {
+      Some((info, Some(data))) => Ok((info, data)),
+      _ => Err(std::io::ErrorKind::NotFound.into()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::names;
+
+  fn file(size: u64) -> FileBasicInfo {
+    FileBasicInfo { file_size: size, is_dir: false, ..dir_info(ProjPath::new(&[])) }
+  }
+
+  #[test]
+  fn implied_and_explicit_directories() {
+    let mut index = Index::new();
+    index.insert_file(&ProjPathBuf::from("b\\c\\file"), file(3), 1);
+    index.insert_file(&ProjPathBuf::from("A.txt"), file(1), 2);
+    index.insert_dir(&ProjPathBuf::from("B"), FileBasicInfo { writed: 42, ..dir_info(ProjPath::new(&[])) });
+    assert_eq!(names(index.dir_iter(&ProjPathBuf::from("")).unwrap()), vec!["A.txt", "B"]);
+    assert_eq!(names(index.dir_iter(&ProjPathBuf::from("b")).unwrap()), vec!["c"]);
+    assert_eq!(index.metadata(&ProjPathBuf::from("b")).unwrap().writed, 42);
+    let (info, data) = index.file(&ProjPathBuf::from("B\\C\\FILE")).unwrap();
+    assert_eq!((info.file_size, *data), (3, 1));
+    assert!(index.file(&ProjPathBuf::from("b\\c")).is_err());
+    assert!(index.dir_iter(&ProjPathBuf::from("a.txt")).is_err());
+  }
+}
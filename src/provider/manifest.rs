@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::Deserialize;
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, VersionInfo};
+use crate::{FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM};
+use super::index::Index;
+use super::{check_range, dir_info, read_exact_at, relative_path, unix_filetime, Rng};
+
+/// Fills a buffer with the bytes of a generated file from an offset on.
+pub type Generator = Arc<dyn Fn(u64, &mut [u8]) + Send + Sync>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+  #[serde(default)]
+  entries: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+  path: String,
+  #[serde(default)]
+  dir: bool,
+  size: Option<u64>,
+  /// Unix seconds.
+  modified: Option<i64>,
+  created: Option<i64>,
+  #[serde(default)]
+  attributes: Vec<String>,
+  source: Option<Source>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Source {
+  Inline(String),
+  Hex(String),
+  File(PathBuf),
+  Range { file: PathBuf, offset: u64, length: u64 },
+  Generator(GeneratorSpec),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+enum GeneratorSpec {
+  Zeros,
+  Repeat { pattern: String },
+  Random { seed: u64 },
+  Custom { name: String },
+}
+
+enum Content {
+  Data(Vec<u8>),
+  File { path: PathBuf, offset: u64 },
+  Generated(Generator),
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+  if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+    return None
+  }
+  (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+fn attribute(name: &str) -> Option<u32> {
+  match name {
+    "readonly" => Some(FILE_ATTRIBUTE_READONLY),
+    "hidden" => Some(FILE_ATTRIBUTE_HIDDEN),
+    "system" => Some(FILE_ATTRIBUTE_SYSTEM),
+    "archive" => Some(FILE_ATTRIBUTE_ARCHIVE),
+    _ => None,
+  }
+}
+
+/// Provider serving a tree described by a JSON or TOML manifest.
+///
+/// The manifest lists `entries`, each with a `path` using `/` separators and optionally `dir = true`, `size`,
+/// `modified` and `created` in unix seconds, `attributes` out of `readonly`, `hidden`, `system` and `archive`,
+/// and for files a `source` of their bytes:
+/// - `inline`: UTF-8 text, or `hex` for binary data;
+/// - `file`: a local file, relative to the manifest;
+/// - `range`: `length` bytes of a local `file` from `offset` on;
+/// - `generator`: `zeros`, `repeat` of a `pattern`, `random` bytes from a `seed`, or a `custom` generator
+///   passed to [`ManifestTree::open_with`] by `name`. Generated files need a `size`.
+///
+/// Files without a source are empty. Loading fails listing every problem found: duplicate paths,
+/// parents not listed as directories, sizes not matching their source, sources that cannot be read.
+/// Local files are read as they are when the projection reads them.
+pub struct ManifestTree {
+  path: PathBuf,
+  index: Index<Content>,
+}
+
+impl ManifestTree {
+  /// Tree described by the manifest at `path`, TOML if its extension is `.toml`, JSON otherwise.
+  pub fn open<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+    Self::open_with(path, HashMap::new())
+  }
+
+  /// Like [`open`](Self::open), with `custom` generators by name.
+  pub fn open_with<P: Into<PathBuf>>(path: P, custom: HashMap<String, Generator>) -> std::io::Result<Self> {
+    let path = path.into();
+    let text = std::fs::read_to_string(&path)?;
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+    let manifest: Manifest = match path.extension() {
+      Some(ext) if ext == "toml" => toml::from_str(&text).map_err(|e| invalid(e.to_string()))?,
+      _ => serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?,
+    };
+    let base = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    let index = Self::validate(manifest, &base, &custom).map_err(|problems| invalid(problems.join("; ")))?;
+    Ok(Self { path, index })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn validate(manifest: Manifest, base: &Path, custom: &HashMap<String, Generator>) -> Result<Index<Content>, Vec<String>> {
+    let mut problems = Vec::new();
+    // kind of every valid path, so parents may be listed after their children
+    let mut kinds = HashMap::new();
+    let mut entries = Vec::new();
+    for entry in manifest.entries {
+      let path = match relative_path(&entry.path) {
+        Some(path) => path,
+        None => {
+          problems.push(format!("{}: invalid path", entry.path));
+          continue
+        },
+      };
+      if kinds.contains_key(&path) {
+        problems.push(format!("{}: duplicate path", entry.path));
+        continue
+      }
+      kinds.insert(path.clone(), entry.dir);
+      entries.push((path, entry));
+    }
+    let mut index = Index::new();
+    for (path, Entry { path: name, dir, size, modified, created, attributes, source }) in entries {
+      let mut problem = |what: String| problems.push(format!("{}: {}", name, what));
+      match path.parent().filter(|parent| !parent.is_empty()).map(|parent| kinds.get(parent)) {
+        Some(None) => problem("parent directory is not listed".into()),
+        Some(Some(false)) => problem("parent is a file".into()),
+        _ => {},
+      }
+      let mut attrs = 0;
+      for attr in &attributes {
+        match attribute(attr) {
+          Some(attr) => attrs |= attr,
+          None => problem(format!("unknown attribute {:?}", attr)),
+        }
+      }
+      let modified = modified.map_or(0, unix_filetime);
+      let info = FileBasicInfo {
+        created: created.map_or(modified, unix_filetime),
+        accessed: modified, writed: modified, changed: modified,
+        attrs,
+        ..dir_info(&path)
+      };
+      if dir {
+        if source.is_some() || size.is_some() {
+          problem("directories have no size or source".into());
+        }
+        index.insert_dir(&path, info);
+        continue
+      }
+      let file_len = |file: &Path| -> Result<u64, String> {
+        match std::fs::metadata(base.join(file)) {
+          Ok(metadata) if metadata.is_file() => Ok(metadata.len()),
+          Ok(_) => Err(format!("{} is not a file", file.display())),
+          Err(e) => Err(format!("{}: {}", file.display(), e)),
+        }
+      };
+      // content and the size it implies
+      let content = match source {
+        None => Ok((Content::Data(Vec::new()), Some(0))),
+        Some(Source::Inline(text)) => Ok((Content::Data(text.into_bytes()), None)),
+        Some(Source::Hex(hex)) => parse_hex(&hex).map(|data| (Content::Data(data), None)).ok_or_else(|| "invalid hex data".to_string()),
+        Some(Source::File(file)) => file_len(&file).map(|len| (Content::File { path: base.join(file), offset: 0 }, Some(len))),
+        Some(Source::Range { file, offset, length }) => file_len(&file).and_then(|len| match offset.checked_add(length) {
+          Some(end) if end <= len => Ok((Content::File { path: base.join(file), offset }, Some(length))),
+          _ => Err(format!("range goes past the {} bytes of {}", len, file.display())),
+        }),
+        Some(Source::Generator(spec)) => match spec {
+          GeneratorSpec::Zeros => Ok(Arc::new(|_: u64, buf: &mut [u8]| buf.fill(0)) as Generator),
+          GeneratorSpec::Repeat { pattern } if pattern.is_empty() => Err("empty pattern".to_string()),
+          GeneratorSpec::Repeat { pattern } => {
+            let pattern = pattern.into_bytes();
+            Ok(Arc::new(move |offset: u64, buf: &mut [u8]| for (i, b) in buf.iter_mut().enumerate() {
+              *b = pattern[((offset + i as u64) % pattern.len() as u64) as usize];
+            }) as Generator)
+          },
+          GeneratorSpec::Random { seed } => Ok(Arc::new(move |offset: u64, buf: &mut [u8]| for (i, b) in buf.iter_mut().enumerate() {
+            let at = offset + i as u64;
+            // every 8 bytes hash their offset, so any range reads the same bytes
+            *b = Rng::new(seed ^ Rng::new(at / 8).next_u64()).next_u64().to_le_bytes()[(at % 8) as usize];
+          }) as Generator),
+          GeneratorSpec::Custom { name } => custom.get(&name).cloned().ok_or_else(|| format!("unknown generator {:?}", name)),
+        }.map(|generator| (Content::Generated(generator), None)),
+      };
+      let (content, len) = match content {
+        Ok(content) => content,
+        Err(e) => {
+          problem(e);
+          continue
+        },
+      };
+      let len = match &content {
+        Content::Data(data) => Some(data.len() as u64),
+        _ => len,
+      };
+      let size = match (size, len) {
+        (Some(size), Some(len)) if size != len => {
+          problem(format!("size {} does not match the {} bytes of its source", size, len));
+          continue
+        },
+        (size, Some(len)) => size.unwrap_or(len),
+        (Some(size), None) => size,
+        (None, None) => {
+          problem("generated files need a size".into());
+          continue
+        },
+      };
+      index.insert_file(&path, FileBasicInfo { is_dir: false, file_size: size, ..info }, content);
+    }
+    if problems.is_empty() { Ok(index) } else { Err(problems) }
+  }
+}
+
+impl ProjFSDirEnum for ManifestTree {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.index.dir_iter(path)
+  }
+}
+
+impl ProjFSRead for ManifestTree {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.index.metadata(path)
+  }
+
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let (info, content) = self.index.file(path)?;
+    check_range(info.file_size, offset, buf.len())?;
+    match content {
+      Content::Data(data) => buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]),
+      Content::File { path, offset: start } => read_exact_at(&std::fs::File::open(path)?, start + offset, buf)?,
+      Content::Generated(generator) => generator(offset, buf),
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ProjPathBuf;
+  use crate::provider::testing::*;
+
+  const JSON: &str = r#"{
+    "entries": [
+      { "path": "docs/readme.txt", "source": { "inline": "hello" }, "modified": 1600000000, "attributes": ["readonly"] },
+      { "path": "docs", "dir": true, "attributes": ["hidden"] },
+      { "path": "bin/blob", "source": { "hex": "00ff10" } },
+      { "path": "bin", "dir": true },
+      { "path": "bin/data.bin", "source": { "file": "data.bin" } },
+      { "path": "bin/middle", "size": 4, "source": { "range": { "file": "data.bin", "offset": 3, "length": 4 } } },
+      { "path": "gen", "dir": true },
+      { "path": "gen/zeros", "size": 10, "source": { "generator": { "kind": "zeros" } } },
+      { "path": "gen/abc", "size": 7, "source": { "generator": { "kind": "repeat", "pattern": "abc" } } },
+      { "path": "gen/random", "size": 64, "source": { "generator": { "kind": "random", "seed": 7 } } },
+      { "path": "gen/squares", "size": 5, "source": { "generator": { "kind": "custom", "name": "squares" } } },
+      { "path": "empty" }
+    ]
+  }"#;
+
+  fn custom() -> HashMap<String, Generator> {
+    let squares: Generator = Arc::new(|offset, buf| for (i, b) in buf.iter_mut().enumerate() {
+      *b = ((offset + i as u64) * (offset + i as u64)) as u8;
+    });
+    Some(("squares".to_string(), squares)).into_iter().collect()
+  }
+
+  #[test]
+  fn serves_sources() {
+    let dir = TempDir::new();
+    dir.write("data.bin", b"0123456789");
+    dir.write("tree.json", JSON.as_bytes());
+    let fs = ManifestTree::open_with(dir.path().join("tree.json"), custom()).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["bin", "docs", "empty", "gen"]);
+    assert_eq!(list(&fs, "bin").unwrap(), vec!["blob", "data.bin", "middle"]);
+    assert_eq!(read_all(&fs, "docs\\readme.txt").unwrap(), b"hello");
+    assert_eq!(read_all(&fs, "bin\\blob").unwrap(), [0, 0xff, 0x10]);
+    assert_eq!(read_all(&fs, "bin\\data.bin").unwrap(), b"0123456789");
+    assert_eq!(read_all(&fs, "bin\\middle").unwrap(), b"3456");
+    assert_eq!(read_all(&fs, "gen\\zeros").unwrap(), [0; 10]);
+    assert_eq!(read_all(&fs, "gen\\abc").unwrap(), b"abcabca");
+    assert_eq!(read_all(&fs, "gen\\squares").unwrap(), [0, 1, 4, 9, 16]);
+    assert_eq!(read_all(&fs, "empty").unwrap(), b"");
+    // generated content depends on the offset only
+    let random = read_all(&fs, "gen\\random").unwrap();
+    let mut buf = [0; 10];
+    fs.read(&ProjPathBuf::from("gen\\random"), VersionInfo::NONE, 13, &mut buf).unwrap();
+    assert_eq!(buf, random[13..23]);
+    let readme = fs.get_metadata(&ProjPathBuf::from("docs\\readme.txt"), VersionInfo::NONE).unwrap();
+    assert_eq!((readme.file_size, readme.writed, readme.attrs), (5, unix_filetime(1_600_000_000), FILE_ATTRIBUTE_READONLY));
+    assert_eq!(fs.get_metadata(&ProjPathBuf::from("docs"), VersionInfo::NONE).unwrap().attrs, FILE_ATTRIBUTE_HIDDEN);
+  }
+
+  #[test]
+  fn toml_manifest() {
+    let dir = TempDir::new();
+    dir.write("tree.toml", br#"
+      [[entries]]
+      path = "a"
+      dir = true
+
+      [[entries]]
+      path = "a/b.txt"
+      modified = 1
+      source = { inline = "toml" }
+    "#);
+    let fs = ManifestTree::open(dir.path().join("tree.toml")).unwrap();
+    assert_eq!(read_all(&fs, "a\\b.txt").unwrap(), b"toml");
+  }
+
+  #[test]
+  fn validation() {
+    let dir = TempDir::new();
+    dir.write("data.bin", b"0123456789");
+    dir.write("tree.json", br#"{ "entries": [
+      { "path": "a", "source": { "inline": "x" } },
+      { "path": "A", "dir": true },
+      { "path": "missing/b" },
+      { "path": "a/c" },
+      { "path": "size", "size": 2, "source": { "inline": "abc" } },
+      { "path": "range", "source": { "range": { "file": "data.bin", "offset": 8, "length": 4 } } },
+      { "path": "gone", "source": { "file": "gone.bin" } },
+      { "path": "gen", "source": { "generator": { "kind": "zeros" } } },
+      { "path": "custom", "size": 1, "source": { "generator": { "kind": "custom", "name": "nope" } } },
+      { "path": "attr", "attributes": ["sticky"] },
+      { "path": "../up" }
+    ] }"#);
+    let err = ManifestTree::open(dir.path().join("tree.json")).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let message = err.to_string();
+    for problem in [
+      "A: duplicate path", "missing/b: parent directory is not listed", "a/c: parent is a file",
+      "size: size 2 does not match the 3 bytes", "range: range goes past", "gone: gone.bin", "gen: generated files need a size",
+      "custom: unknown generator", "attr: unknown attribute", "../up: invalid path",
+    ] {
+      assert!(message.contains(problem), "{} in {}", problem, message);
+    }
+    dir.write("bad.json", br#"{ "entries": [{ "path": "a", "colour": "red" }] }"#);
+    assert_eq!(ManifestTree::open(dir.path().join("bad.json")).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+  }
+}
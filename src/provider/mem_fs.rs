@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use crate::{filetime, FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{check_range, dir_info};
+
+struct Node {
+  info: FileBasicInfo,
+  /// `None` for directories.
+  data: Option<Vec<u8>>,
+}
+
+struct Tree {
+  /// Children of every directory, keyed by the directory path.
+  dirs: HashMap<ProjPathBuf, BTreeMap<ProjPathBuf, Node>>,
+  root: FileBasicInfo,
+  /// Last version given out.
+  version: u64,
+}
+
+/// Version stored in the content ID by `Tree::stamp`.
+fn version_of(info: &FileBasicInfo) -> u64 {
+  info.content_id.as_ref().and_then(|id| id[..].try_into().ok()).map_or(0, u64::from_le_bytes)
+}
+
+fn already_exists(path: &ProjPath) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} already exists", path))
+}
+
+fn not_found(path: &ProjPath) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+impl Tree {
+  /// Gives `info` the next version and the current time.
+  fn stamp(&mut self, info: &mut FileBasicInfo) -> u64 {
+    self.version += 1;
+    let now = filetime(SystemTime::now());
+    info.content_id = Some(self.version.to_le_bytes().to_vec());
+    info.writed = now;
+    info.changed = now;
+    info.accessed = now;
+    self.version
+  }
+
+  fn node_mut(&mut self, path: &ProjPath) -> Option<&mut Node> {
+    self.dirs.get_mut(path.parent()?)?.get_mut(path.file_name()?)
+  }
+
+  fn node(&self, path: &ProjPath) -> Option<&Node> {
+    self.dirs.get(path.parent()?)?.get(path.file_name()?)
+  }
+
+  /// Bumps the version of the directory at `path`, its listing changed.
+  fn touch(&mut self, path: &ProjPath) {
+    let mut info = match self.node(path) {
+      Some(node) => node.info.clone(),
+      None => self.root.clone(),
+    };
+    self.stamp(&mut info);
+    match self.node_mut(path) {
+      Some(node) => node.info = info,
+      None => self.root = info,
+    }
+  }
+
+  /// Creates the directory at `path` and its missing ancestors.
+  fn ensure_dir(&mut self, path: &ProjPath) -> std::io::Result<u64> {
+    if self.dirs.contains_key(path) {
+      return Ok(version_of(self.node(path).map_or(&self.root, |node| &node.info)))
+    }
+    let (parent, name) = match (path.parent(), path.file_name()) {
+      (Some(parent), Some(name)) => (parent, name),
+      _ => return Ok(0),
+    };
+    self.ensure_dir(parent)?;
+    if self.node(path).is_some() {
+      return Err(already_exists(path))
+    }
+    let mut info = dir_info(name);
+    let version = self.stamp(&mut info);
+    info.created = info.writed;
+    self.dirs.get_mut(parent).unwrap().insert(name.to_owned(), Node { info, data: None });
+    self.dirs.insert(path.to_owned(), BTreeMap::new());
+    self.touch(parent);
+    Ok(version)
+  }
+}
+
+/// Mutable in-memory provider, for tests and synthetic trees.
+///
+/// Every change gives the entries it touches a new version from a counter shared by the whole tree,
+/// written as their placeholder content ID: a file's version changes with its content or name,
+/// a directory's whenever its listing does. Placeholders whose content ID differs from what
+/// `get_metadata` returns are stale, and reading one fails rather than serving the new content.
+/// Names compare case-insensitively, like the rest of ProjFS.
+pub struct MemFs {
+  tree: RwLock<Tree>,
+}
+
+impl Default for MemFs {
+  fn default() -> Self {
+    let mut dirs = HashMap::new();
+    dirs.insert(ProjPathBuf::new(), BTreeMap::new());
+    Self { tree: RwLock::new(Tree { dirs, root: dir_info(ProjPath::new(&[])), version: 0 }) }
+  }
+}
+
+impl MemFs {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Latest version given to an entry, 0 while nothing was added.
+  pub fn version(&self) -> u64 {
+    self.tree.read().unwrap().version
+  }
+
+  /// Adds a directory and its missing ancestors, returning its version. An existing directory is kept.
+  pub fn add_dir(&self, path: &ProjPath) -> std::io::Result<u64> {
+    self.tree.write().unwrap().ensure_dir(path)
+  }
+
+  /// Adds a file and its missing ancestors, returning its version.
+  pub fn add_file<D: Into<Vec<u8>>>(&self, path: &ProjPath, data: D) -> std::io::Result<u64> {
+    let mut tree = self.tree.write().unwrap();
+    let (parent, name) = match (path.parent(), path.file_name()) {
+      (Some(parent), Some(name)) => (parent, name),
+      _ => return Err(already_exists(path)),
+    };
+    tree.ensure_dir(parent)?;
+    if tree.node(path).is_some() {
+      return Err(already_exists(path))
+    }
+    let data = data.into();
+    let mut info = FileBasicInfo { is_dir: false, file_size: data.len() as u64, ..dir_info(name) };
+    let version = tree.stamp(&mut info);
+    info.created = info.writed;
+    tree.dirs.get_mut(parent).unwrap().insert(name.to_owned(), Node { info, data: Some(data) });
+    tree.touch(parent);
+    Ok(version)
+  }
+
+  /// Replaces the content of an existing file, returning its new version.
+  pub fn overwrite<D: Into<Vec<u8>>>(&self, path: &ProjPath, data: D) -> std::io::Result<u64> {
+    let mut tree = self.tree.write().unwrap();
+    let mut info = match tree.node(path) {
+      Some(Node { info, data: Some(_) }) => info.clone(),
+      _ => return Err(not_found(path)),
+    };
+    let data = data.into();
+    info.file_size = data.len() as u64;
+    let version = tree.stamp(&mut info);
+    *tree.node_mut(path).unwrap() = Node { info, data: Some(data) };
+    Ok(version)
+  }
+
+  /// Removes a file, or a directory with everything below it.
+  pub fn remove(&self, path: &ProjPath) -> std::io::Result<()> {
+    let mut tree = self.tree.write().unwrap();
+    let parent = path.parent().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot remove the root"))?;
+    let node = tree.dirs.get_mut(parent).and_then(|dir| dir.remove(path.file_name()?)).ok_or_else(|| not_found(path))?;
+    if node.data.is_none() {
+      tree.dirs.retain(|dir, _| !dir.starts_with(path));
+    }
+    tree.touch(parent);
+    Ok(())
+  }
+
+  /// Moves a file or directory to `to`, whose parent must exist, returning the entry's new version.
+  /// Entries below a moved directory keep their versions.
+  pub fn rename(&self, from: &ProjPath, to: &ProjPath) -> std::io::Result<u64> {
+    let mut tree = self.tree.write().unwrap();
+    let (from_parent, to_parent, name) = match (from.parent(), to.parent(), to.file_name()) {
+      (Some(from_parent), Some(to_parent), Some(name)) => (from_parent, to_parent, name),
+      _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot rename the root")),
+    };
+    if tree.node(from).is_none() {
+      return Err(not_found(from))
+    }
+    if !tree.dirs.contains_key(to_parent) {
+      return Err(not_found(to_parent))
+    }
+    // `to` may be `from` with the case of its name changed
+    let same = from == to;
+    if !same && tree.node(to).is_some() {
+      return Err(already_exists(to))
+    }
+    if !same && to.starts_with(from) {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("cannot move {} into itself", from)))
+    }
+    let mut node = tree.dirs.get_mut(from_parent).unwrap().remove(from.file_name().unwrap()).unwrap();
+    if node.data.is_none() {
+      let moved = tree.dirs.keys().filter(|dir| dir.starts_with(from)).cloned().collect::<Vec<_>>();
+      for dir in moved {
+        let children = tree.dirs.remove(&dir).unwrap();
+        tree.dirs.insert(to.join(dir.strip_prefix(from).unwrap()), children);
+      }
+    }
+    node.info.file_name = name.to_path_buf();
+    let version = tree.stamp(&mut node.info);
+    tree.dirs.get_mut(to_parent).unwrap().insert(name.to_owned(), node);
+    tree.touch(from_parent);
+    if to_parent != from_parent {
+      tree.touch(to_parent);
+    }
+    Ok(version)
+  }
+}
+
+impl ProjFSDirEnum for MemFs {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let tree = self.tree.read().unwrap();
+    let children = tree.dirs.get(path).ok_or_else(|| not_found(path))?;
+    Ok(children.values().map(|node| node.info.clone()).collect::<Vec<_>>().into_iter())
+  }
+}
+
+impl ProjFSRead for MemFs {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    let tree = self.tree.read().unwrap();
+    if path.is_empty() {
+      return Ok(tree.root.clone())
+    }
+    tree.node(path).map(|node| node.info.clone()).ok_or_else(|| not_found(path))
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let tree = self.tree.read().unwrap();
+    let (info, data) = match tree.node(path) {
+      Some(Node { info, data: Some(data) }) => (info, data),
+      _ => return Err(not_found(path)),
+    };
+    if let (Some(placeholder), Some(current)) = (version.content_id(), &info.content_id) {
+      if placeholder[..current.len()] != current[..] {
+        return Err(std::io::Error::other(format!("{} changed since its placeholder was written", path)))
+      }
+    }
+    check_range(data.len() as u64, offset, buf.len())?;
+    buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn path(path: &str) -> ProjPathBuf {
+    ProjPathBuf::from(path)
+  }
+
+  #[test]
+  fn mutations() {
+    let fs = MemFs::new();
+    fs.add_file(&path("a\\b\\one.txt"), "one").unwrap();
+    fs.add_file(&path("two.txt"), b"two".to_vec()).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["a", "two.txt"]);
+    assert_eq!(list(&fs, "A\\B").unwrap(), vec!["one.txt"]);
+    assert_eq!(fs.add_file(&path("A\\b\\ONE.txt"), "").unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(fs.add_dir(&path("two.txt\\c")).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+    fs.overwrite(&path("a\\b\\one.txt"), "uno!").unwrap();
+    assert_eq!(read_all(&fs, "a\\b\\one.txt").unwrap(), b"uno!");
+    assert!(fs.overwrite(&path("a\\b"), "").is_err());
+    fs.rename(&path("a\\b"), &path("two")).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["a", "two", "two.txt"]);
+    assert_eq!(read_all(&fs, "two\\one.txt").unwrap(), b"uno!");
+    assert!(list(&fs, "a\\b").is_err());
+    fs.rename(&path("two.txt"), &path("TWO.TXT")).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["a", "two", "TWO.TXT"]);
+    assert_eq!(fs.rename(&path("a"), &path("a\\c")).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(fs.rename(&path("a"), &path("two.txt")).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(fs.rename(&path("a"), &path("missing\\a")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    fs.remove(&path("two")).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["a", "TWO.TXT"]);
+    assert!(list(&fs, "two").is_err());
+    assert!(fs.remove(&path("two")).is_err());
+  }
+
+  #[test]
+  fn versions() {
+    let fs = MemFs::new();
+    let metadata = |p: &str| fs.get_metadata(&path(p), VersionInfo::NONE).unwrap();
+    let v1 = fs.add_file(&path("dir\\file"), "old").unwrap();
+    assert_eq!(metadata("dir\\file").content_id, Some(v1.to_le_bytes().to_vec()));
+    let dir = metadata("dir").content_id;
+    let root = metadata("").content_id;
+    let placeholder = crate::version_info(metadata("dir\\file").content_id.as_ref().unwrap());
+    let mut buf = [0; 3];
+    fs.read(&path("dir\\file"), VersionInfo::new(&placeholder), 0, &mut buf).unwrap();
+    let v2 = fs.overwrite(&path("dir\\file"), "new").unwrap();
+    assert!(v2 > v1 && fs.version() == v2);
+    // content changes leave the listings alone
+    assert_eq!(metadata("dir").content_id, dir);
+    assert!(fs.read(&path("dir\\file"), VersionInfo::new(&placeholder), 0, &mut buf).is_err());
+    fs.read(&path("dir\\file"), VersionInfo::NONE, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"new");
+    fs.add_file(&path("dir\\other"), "").unwrap();
+    assert_ne!(metadata("dir").content_id, dir);
+    assert_eq!(metadata("").content_id, root);
+    fs.remove(&path("dir")).unwrap();
+    assert_ne!(metadata("").content_id, root);
+  }
+
+  #[test]
+  fn concurrent_mutations() {
+    let fs = MemFs::new();
+    std::thread::scope(|scope| {
+      for t in 0..4 {
+        let fs = &fs;
+        scope.spawn(move || for i in 0..50 {
+          let file = path(&format!("t{}\\f{}", t, i));
+          fs.add_file(&file, format!("{}", i)).unwrap();
+          assert_eq!(read_all(fs, &format!("t{}\\f{}", t, i)).unwrap(), format!("{}", i).as_bytes());
+        });
+      }
+    });
+    assert_eq!(list(&fs, "").unwrap(), vec!["t0", "t1", "t2", "t3"]);
+    assert_eq!(list(&fs, "t2").unwrap().len(), 50);
+    // every file and directory got one version, and its parent another
+    assert_eq!(fs.version(), 4 * (50 * 2 + 2));
+  }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+
+/// Paths cached before expired entries are dropped.
+const MAX_ENTRIES: usize = 1 << 16;
+
+struct Entry {
+  /// `None` if the path does not exist.
+  info: Option<FileBasicInfo>,
+  expires: Instant,
+}
+
+struct Entries {
+  map: HashMap<ProjPathBuf, Entry>,
+  /// Directories whose children are all in `map`, until when.
+  listed: HashMap<ProjPathBuf, Instant>,
+  /// Bumped by every invalidation or eviction, listings running across one are not trusted.
+  generation: u64,
+  /// Paths cached before expired entries are dropped.
+  max: usize,
+}
+
+impl Default for Entries {
+  fn default() -> Self {
+    Self { map: HashMap::new(), listed: HashMap::new(), generation: 0, max: MAX_ENTRIES }
+  }
+}
+
+impl Entries {
+  fn insert(&mut self, path: ProjPathBuf, info: Option<FileBasicInfo>, expires: Instant) {
+    if self.map.len() >= self.max {
+      let now = Instant::now();
+      self.map.retain(|_, i| i.expires > now);
+      self.listed.retain(|_, i| *i > now);
+      if self.map.len() >= self.max {
+        self.map.clear();
+        self.listed.clear();
+      }
+      self.generation += 1;
+    }
+    self.map.insert(path, Entry { info, expires });
+  }
+
+  /// Cached result for `path`, `None` if it has to be asked for.
+  fn lookup(&self, path: &ProjPath, now: Instant) -> Option<Option<FileBasicInfo>> {
+    match self.map.get(path) {
+      Some(entry) if entry.expires > now => Some(entry.info.clone()),
+      Some(_) => None,
+      // children of a listed directory are all known
+      None => path.parent().and_then(|parent| self.listed.get(parent)).filter(|&&until| until > now).map(|_| None),
+    }
+  }
+}
+
+/// Handle dropping entries of a [`MetadataCache`], for whatever learns about changes of the inner provider.
+#[derive(Clone)]
+pub struct MetadataInvalidator(Arc<Mutex<Entries>>);
+
+impl MetadataInvalidator {
+  /// Forgets `path`, everything below it, and its parent directory whose listing changed with it.
+  pub fn invalidate(&self, path: &ProjPath) {
+    let mut entries = self.0.lock().unwrap();
+    let parent = path.parent();
+    let stale = |i: &ProjPath| i.starts_with(path) || parent.is_some_and(|parent| *i == *parent);
+    entries.map.retain(|i, _| !stale(i));
+    entries.listed.retain(|i, _| !stale(i));
+    entries.generation += 1;
+  }
+
+  pub fn clear(&self) {
+    let mut entries = self.0.lock().unwrap();
+    entries.map.clear();
+    entries.listed.clear();
+    entries.generation += 1;
+  }
+}
+
+/// Decorator caching the results of `get_metadata`, including the paths found missing.
+///
+/// Entries found live `ttl`, missing paths `negative_ttl`; errors other than `NotFound` are not cached.
+/// Enumerations fill the cache with the entries they return, and once one went through a directory without
+/// pattern pushed down to the provider, names missing from it are answered as missing without asking.
+/// Changes of the inner provider are not noticed before entries expire, report them through a [`MetadataInvalidator`].
+pub struct MetadataCache<T> {
+  inner: T,
+  ttl: Duration,
+  negative_ttl: Duration,
+  entries: Arc<Mutex<Entries>>,
+}
+
+impl<T> MetadataCache<T> {
+  /// Cache keeping entries for 5 seconds, and missing paths for 2.
+  pub fn new(inner: T) -> Self {
+    Self::with_ttl(inner, Duration::from_secs(5), Duration::from_secs(2))
+  }
+
+  pub fn with_ttl(inner: T, ttl: Duration, negative_ttl: Duration) -> Self {
+    Self { inner, ttl, negative_ttl, entries: Arc::default() }
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  pub fn invalidator(&self) -> MetadataInvalidator {
+    MetadataInvalidator(self.entries.clone())
+  }
+
+  /// Shorthand for `invalidator().invalidate(path)`.
+  pub fn invalidate(&self, path: &ProjPath) {
+    self.invalidator().invalidate(path)
+  }
+}
+
+/// Enumeration recording the entries it returns in the cache.
+pub struct MetadataListing<I> {
+  iter: I,
+  entries: Arc<Mutex<Entries>>,
+  path: ProjPathBuf,
+  expires: Instant,
+  generation: u64,
+  /// Whether every entry of the directory comes by.
+  complete: bool,
+}
+
+impl<I: Iterator<Item=FileBasicInfo>> Iterator for MetadataListing<I> {
+  type Item = FileBasicInfo;
+  fn next(&mut self) -> Option<Self::Item> {
+    let item = self.iter.next();
+    let mut entries = self.entries.lock().unwrap();
+    if entries.generation == self.generation {
+      match &item {
+        Some(info) => entries.insert(self.path.join(ProjPathBuf::from(info.file_name.as_os_str())), Some(info.clone()), self.expires),
+        None if self.complete => {
+          entries.listed.insert(self.path.clone(), self.expires);
+          self.complete = false;
+        },
+        None => {},
+      }
+    }
+    item
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for MetadataCache<T> {
+  type DirIter = MetadataListing<T::DirIter>;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    // entries are only expected to live as long as counted from before the listing started
+    let expires = Instant::now() + self.ttl;
+    let generation = self.entries.lock().unwrap().generation;
+    let iter = self.inner.dir_iter(id, path, pattern, version)?;
+    let complete = pattern.is_none() || !self.inner.filters_pattern();
+    Ok(MetadataListing { iter, entries: self.entries.clone(), path: path.to_owned(), expires, generation, complete })
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for MetadataCache<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    let now = Instant::now();
+    let (cached, generation) = {
+      let entries = self.entries.lock().unwrap();
+      (entries.lookup(path, now), entries.generation)
+    };
+    let result = match cached {
+      Some(Some(info)) => return Ok(info),
+      Some(None) => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", path))),
+      None => self.inner.get_metadata(path, version),
+    };
+    let cached = match &result {
+      Ok(info) => Some((Some(info.clone()), self.ttl)),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some((None, self.negative_ttl)),
+      Err(_) => None,
+    };
+    let mut entries = self.entries.lock().unwrap();
+    if let (Some((info, ttl)), true) = (cached, entries.generation == generation) {
+      entries.insert(path.to_owned(), info, now + ttl);
+    }
+    result
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    self.inner.read(path, version, offset, buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::{CallKind, MemFs};
+  use crate::provider::testing::*;
+
+  fn cache<T>(inner: T, ttl: Duration, negative_ttl: Duration) -> MetadataCache<Counting<T>> {
+    MetadataCache::with_ttl(Counting::new(inner), ttl, negative_ttl)
+  }
+
+  fn lookup<T: ProjFSRead>(fs: &T, path: &str) -> std::io::Result<u64> {
+    fs.get_metadata(&ProjPathBuf::from(path), VersionInfo::NONE).map(|i| i.file_size)
+  }
+
+  fn calls<T>(fs: &MetadataCache<Counting<T>>) -> usize {
+    fs.inner().calls(CallKind::GetMetadata)
+  }
+
+  #[test]
+  fn caches_found_and_missing_paths() {
+    let tree = || Tree::new(&[("dir\\file", "data")]);
+    let fs = cache(tree(), Duration::from_secs(60), Duration::from_secs(60));
+    for _ in 0..3 {
+      assert_eq!(lookup(&fs, "dir\\file").unwrap(), 4);
+      assert_eq!(lookup(&fs, "DIR\\FILE").unwrap(), 4);
+      assert_eq!(lookup(&fs, "dir\\desktop.ini").err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    }
+    assert_eq!(calls(&fs), 2);
+    // expired entries are asked for again
+    let fs = cache(tree(), Duration::from_secs(60), Duration::ZERO);
+    for _ in 0..3 {
+      lookup(&fs, "dir\\file").unwrap();
+      lookup(&fs, ".git").err().unwrap();
+    }
+    assert_eq!(calls(&fs), 4);
+  }
+
+  #[test]
+  fn enumerations_fill_the_cache() {
+    let fs = cache(Tree::new(&[("dir\\a", "a"), ("dir\\b", "bb"), ("other\\c", "c")]), Duration::from_secs(60), Duration::from_secs(60));
+    assert_eq!(list(&fs, "dir").unwrap(), vec!["a", "b"]);
+    assert_eq!(lookup(&fs, "dir\\b").unwrap(), 2);
+    assert_eq!(lookup(&fs, "dir\\node_modules").err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(calls(&fs), 0);
+    // a listing left unfinished knows nothing about missing names
+    let mut iter = fs.dir_iter(Guid::nil(), &ProjPathBuf::from("other"), None, VersionInfo::NONE).unwrap();
+    iter.next().unwrap();
+    assert_eq!(lookup(&fs, "other\\c").unwrap(), 1);
+    lookup(&fs, "other\\d").err().unwrap();
+    assert_eq!(calls(&fs), 1);
+  }
+
+  #[test]
+  fn invalidation_drops_stale_entries() {
+    let fs = cache(MemFs::new(), Duration::from_secs(60), Duration::from_secs(60));
+    fs.inner().inner().add_dir(&ProjPathBuf::from("dir")).unwrap();
+    assert!(list(&fs, "dir").unwrap().is_empty());
+    lookup(&fs, "dir\\new").err().unwrap();
+    let dir = fs.get_metadata(&ProjPathBuf::from("dir"), VersionInfo::NONE).unwrap().content_id;
+    fs.inner().inner().add_file(&ProjPathBuf::from("dir\\new"), "new file").unwrap();
+    lookup(&fs, "dir\\new").err().unwrap();
+    let invalidator = fs.invalidator();
+    std::thread::spawn(move || invalidator.invalidate(&ProjPathBuf::from("dir\\new"))).join().unwrap();
+    assert_eq!(lookup(&fs, "dir\\new").unwrap(), 8);
+    // the parent directory changed along
+    assert_ne!(fs.get_metadata(&ProjPathBuf::from("dir"), VersionInfo::NONE).unwrap().content_id, dir);
+    fs.inner().inner().remove(&ProjPathBuf::from("dir")).unwrap();
+    fs.invalidate(&ProjPathBuf::from("dir"));
+    lookup(&fs, "dir\\new").err().unwrap();
+  }
+
+  #[test]
+  fn listings_across_an_eviction_are_not_trusted() {
+    let fs = cache(Tree::new(&[("dir\\a", "a"), ("dir\\b", "b"), ("dir\\c", "c")]), Duration::from_secs(60), Duration::from_secs(60));
+    fs.entries.lock().unwrap().max = 2;
+    // the listing's own entries are evicted before it ends
+    assert_eq!(list(&fs, "dir").unwrap(), vec!["a", "b", "c"]);
+    assert_eq!(lookup(&fs, "dir\\a").unwrap(), 1);
+    assert_eq!(calls(&fs), 1);
+  }
+}
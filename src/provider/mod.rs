@@ -0,0 +1,476 @@
+//! Ready-made providers, and building blocks to compose them.
+
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+
+mod block_cache;
+pub use block_cache::BlockCache;
+mod dir_mirror;
+pub use dir_mirror::DirMirror;
+mod disk_cache;
+pub use disk_cache::DiskCache;
+mod fault_injection;
+pub use fault_injection::{Fault, FaultInjection};
+#[cfg(feature = "chunks")]
+mod chunk_store;
+#[cfg(feature = "chunks")]
+pub use chunk_store::{Chunk, ChunkHash, ChunkStore, ChunkTree};
+#[cfg(any(feature = "tar", feature = "zip"))]
+mod checkpoint;
+mod coalesce;
+pub use coalesce::Coalesce;
+mod concurrency_limit;
+pub use concurrency_limit::ConcurrencyLimit;
+#[cfg(any(feature = "tar", feature = "zip", feature = "http", feature = "chunks", feature = "manifest"))]
+mod index;
+#[cfg(feature = "hive")]
+mod hive;
+#[cfg(feature = "hive")]
+pub use hive::{RegistryHive, DEFAULT_VALUE_NAME};
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "http")]
+pub use http::{HttpOptions, HttpTree};
+#[cfg(feature = "manifest")]
+mod manifest;
+#[cfg(feature = "manifest")]
+pub use manifest::{Generator, ManifestTree};
+mod mem_fs;
+pub use mem_fs::MemFs;
+mod metadata_cache;
+pub use metadata_cache::{MetadataCache, MetadataInvalidator, MetadataListing};
+mod overlay;
+pub use overlay::{Overlay, OPAQUE_MARKER, WHITEOUT_PREFIX};
+mod read_ahead;
+pub use read_ahead::{ReadAhead, ReadAheadOptions};
+mod retry;
+pub use retry::{is_transient, Retry, RetryOptions};
+mod router;
+pub use router::Router;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteSchema, SqliteTree};
+#[cfg(feature = "tar")]
+mod tar_archive;
+#[cfg(feature = "tar")]
+pub use tar_archive::TarArchive;
+#[cfg(feature = "zip")]
+mod zip_archive;
+#[cfg(feature = "zip")]
+pub use zip_archive::ZipArchive;
+#[cfg(feature = "git")]
+mod git;
+#[cfg(feature = "git")]
+pub use git::GitTree;
+
+/// Provider calls, as told apart by decorators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallKind {
+  DirIter,
+  GetMetadata,
+  Read,
+}
+
+impl CallKind {
+  pub(crate) fn index(self) -> usize {
+    self as usize
+  }
+}
+
+/// SplitMix64, a small generator for decisions that need no cryptographic quality.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+  pub fn new(seed: u64) -> Self {
+    Self(seed)
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in `0.0..1.0`.
+  pub fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
+
+/// Type erased [`ProjFSDirEnum::DirIter`].
+pub type BoxDirIter = Box<dyn Iterator<Item=FileBasicInfo> + Send>;
+
+/// Object safe form of [`ProjFSDirEnum`] + [`ProjFSRead`], so providers of different types can be combined.
+pub trait DynDirEnum: ProjFSRead + Send + Sync {
+  fn dir_iter_boxed(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<BoxDirIter>;
+  fn filters_pattern_boxed(&self) -> bool;
+}
+
+impl<T> DynDirEnum for T where T: ProjFSDirEnum + ProjFSRead + Send + Sync, T::DirIter: 'static {
+  fn dir_iter_boxed(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<BoxDirIter> {
+    Ok(Box::new(self.dir_iter(id, path, pattern, version)?))
+  }
+  fn filters_pattern_boxed(&self) -> bool {
+    self.filters_pattern()
+  }
+}
+
+/// Any provider behind a box, itself a provider.
+pub type BoxProvider = Box<dyn DynDirEnum>;
+
+impl ProjFSDirEnum for BoxProvider {
+  type DirIter = BoxDirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    (**self).dir_iter_boxed(id, path, pattern, version)
+  }
+  fn filters_pattern(&self) -> bool {
+    (**self).filters_pattern_boxed()
+  }
+}
+
+impl ProjFSRead for BoxProvider {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    (**self).get_metadata(path, version)
+  }
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    (**self).read(path, version, offset, buf)
+  }
+}
+
+/// Entry of a directory with no backing data of its own.
+pub(crate) fn dir_info(name: &ProjPath) -> FileBasicInfo {
+  FileBasicInfo {
+    file_name: name.to_path_buf(),
+    is_dir: true,
+    file_size: 0,
+    created: 0, accessed: 0, writed: 0, changed: 0,
+    attrs: 0,
+    content_id: None,
+  }
+}
+
+/// `FILETIME` ticks of a unix timestamp in seconds.
+#[cfg(any(feature = "tar", feature = "zip", feature = "git", feature = "sqlite", feature = "http", feature = "manifest"))]
+pub(crate) fn unix_filetime(secs: i64) -> i64 {
+  crate::filetime(std::time::UNIX_EPOCH) + secs * 10_000_000
+}
+
+/// Projected path of a relative `/` separated path read from a listing or manifest,
+/// `None` if it has empty, `.` or `..` components or a `\`.
+#[cfg(any(feature = "http", feature = "chunks", feature = "manifest"))]
+pub(crate) fn relative_path(path: &str) -> Option<ProjPathBuf> {
+  if path.split('/').any(|c| c.is_empty() || c == "." || c == ".." || c.contains('\\')) {
+    return None
+  }
+  Some(ProjPathBuf::from(path.replace('/', "\\")))
+}
+
+/// Sorts entries in ProjFS collation order, which enumerations must follow.
+pub(crate) fn sort_entries(entries: &mut [FileBasicInfo]) {
+  entries.sort_by_cached_key(|i| ProjPathBuf::from(i.file_name.as_os_str()));
+}
+
+/// Content ID without the zero padding it gets in placeholder version info.
+pub(crate) fn trim_id(id: &[u8]) -> Vec<u8> {
+  let len = id.iter().rposition(|&i| i != 0).map_or(0, |i| i + 1);
+  id[..len].to_vec()
+}
+
+/// Checks that `offset..offset + len` lies within a file of `size` bytes.
+pub(crate) fn check_range(size: u64, offset: u64, len: usize) -> std::io::Result<()> {
+  match offset.checked_add(len as u64) {
+    Some(end) if end <= size => Ok(()),
+    _ => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "read past the end of the file")),
+  }
+}
+
+/// Reader of `file` from `offset` on, leaving the file cursor alone so readers can share the file.
+pub(crate) struct ReadAt<'a> {
+  pub file: &'a std::fs::File,
+  pub offset: u64,
+}
+
+impl std::io::Read for ReadAt<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    #[cfg(windows)]
+    let n = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.offset)?;
+    #[cfg(not(windows))]
+    let n = std::os::unix::fs::FileExt::read_at(self.file, buf, self.offset)?;
+    self.offset += n as u64;
+    Ok(n)
+  }
+}
+
+/// Fills `buf` from `offset` of `file`, failing with `UnexpectedEof` if the file ends before.
+pub(crate) fn read_exact_at(file: &std::fs::File, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+  match std::io::Read::read_exact(&mut ReadAt { file, offset }, buf) {
+    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+      Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file ended before the requested range"))
+    },
+    result => result,
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+  use super::*;
+  use std::collections::BTreeMap;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Condvar, Mutex};
+
+  /// Fixed tree of files for tests, directories are implied by the file paths.
+  pub struct Tree(BTreeMap<ProjPathBuf, Vec<u8>>);
+
+  impl Tree {
+    pub fn new(files: &[(&str, &str)]) -> Self {
+      Self(files.iter().map(|(path, data)| (ProjPathBuf::from(*path), data.as_bytes().to_vec())).collect())
+    }
+
+    fn is_dir(&self, path: &ProjPath) -> bool {
+      path.is_empty() || self.0.keys().any(|i| i.starts_with(path) && i.components().count() > path.components().count())
+    }
+  }
+
+  impl ProjFSDirEnum for Tree {
+    type DirIter = std::vec::IntoIter<FileBasicInfo>;
+    fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+      if !self.is_dir(path) {
+        return Err(std::io::ErrorKind::NotFound.into())
+      }
+      let mut names: Vec<&ProjPath> = self.0.keys().filter_map(|i| i.strip_prefix(path)?.components().next()).collect();
+      names.dedup();
+      let mut entries = names.into_iter().map(|name| self.get_metadata(&path.join(name), version)).collect::<std::io::Result<Vec<_>>>()?;
+      sort_entries(&mut entries);
+      Ok(entries.into_iter())
+    }
+  }
+
+  impl ProjFSRead for Tree {
+    fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      let name = path.file_name().ok_or(std::io::ErrorKind::NotFound)?;
+      if let Some(data) = self.0.get(path) {
+        Ok(FileBasicInfo { file_size: data.len() as u64, is_dir: false, ..dir_info(name) })
+      } else if self.is_dir(path) {
+        Ok(dir_info(name))
+      } else {
+        Err(std::io::ErrorKind::NotFound.into())
+      }
+    }
+    fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      let data = self.0.get(path).ok_or(std::io::ErrorKind::NotFound)?;
+      let data = data.get(offset as usize..offset as usize + buf.len()).ok_or(std::io::ErrorKind::UnexpectedEof)?;
+      buf.copy_from_slice(data);
+      Ok(())
+    }
+  }
+
+  /// Directory under the system temporary directory, removed on drop.
+  pub struct TempDir(std::path::PathBuf);
+
+  impl TempDir {
+    pub fn new() -> Self {
+      let path = std::env::temp_dir().join(format!("projfs-test-{}", Guid::new_v4()));
+      std::fs::create_dir(&path).unwrap();
+      Self(path)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+      &self.0
+    }
+
+    /// Writes `data` to `path`, relative with `/` separators, creating parent directories.
+    pub fn write(&self, path: &str, data: &[u8]) {
+      let path = self.0.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, data).unwrap();
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  /// Provider counting the calls of each kind reaching it.
+  pub struct Counting<T> {
+    inner: T,
+    calls: [AtomicUsize; 3],
+  }
+
+  impl<T> Counting<T> {
+    pub fn new(inner: T) -> Self {
+      Self { inner, calls: Default::default() }
+    }
+
+    pub fn inner(&self) -> &T {
+      &self.inner
+    }
+
+    pub fn calls(&self, kind: CallKind) -> usize {
+      self.calls[kind.index()].load(Ordering::SeqCst)
+    }
+
+    fn count(&self, kind: CallKind) {
+      self.calls[kind.index()].fetch_add(1, Ordering::SeqCst);
+    }
+  }
+
+  impl<T: ProjFSDirEnum> ProjFSDirEnum for Counting<T> {
+    type DirIter = T::DirIter;
+    fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+      self.count(CallKind::DirIter);
+      self.inner.dir_iter(id, path, pattern, version)
+    }
+    fn filters_pattern(&self) -> bool {
+      self.inner.filters_pattern()
+    }
+  }
+
+  impl<T: ProjFSRead> ProjFSRead for Counting<T> {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.count(CallKind::GetMetadata);
+      self.inner.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      self.count(CallKind::Read);
+      self.inner.read(path, version, offset, buf)
+    }
+  }
+
+  /// Provider failing its next calls with `error`, as many as [`fail`](Self::fail) was given.
+  pub struct Failing<T> {
+    inner: T,
+    failures: AtomicUsize,
+    error: fn() -> std::io::Error,
+    calls: AtomicUsize,
+  }
+
+  impl<T> Failing<T> {
+    pub fn new(inner: T, error: fn() -> std::io::Error) -> Self {
+      Self { inner, failures: AtomicUsize::new(0), error, calls: AtomicUsize::new(0) }
+    }
+
+    pub fn inner(&self) -> &T {
+      &self.inner
+    }
+
+    /// Fails the next `n` calls, `usize::MAX` for every call until changed.
+    pub fn fail(&self, n: usize) {
+      self.failures.store(n, Ordering::SeqCst);
+    }
+
+    pub fn calls(&self) -> usize {
+      self.calls.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> std::io::Result<()> {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      match self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |i| i.checked_sub(1)) {
+        Ok(_) => Err((self.error)()),
+        Err(_) => Ok(()),
+      }
+    }
+  }
+
+  impl<T: ProjFSDirEnum> ProjFSDirEnum for Failing<T> {
+    type DirIter = T::DirIter;
+    fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+      self.check()?;
+      self.inner.dir_iter(id, path, pattern, version)
+    }
+    fn filters_pattern(&self) -> bool {
+      self.inner.filters_pattern()
+    }
+  }
+
+  impl<T: ProjFSRead> ProjFSRead for Failing<T> {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.check()?;
+      self.inner.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      self.check()?;
+      self.inner.read(path, version, offset, buf)
+    }
+  }
+
+  /// Provider holding every call until released, counting the calls and how many run at once.
+  pub struct Held<T> {
+    inner: T,
+    open: Mutex<bool>,
+    opened: Condvar,
+    calls: AtomicUsize,
+    running: AtomicUsize,
+    most: AtomicUsize,
+  }
+
+  impl<T> Held<T> {
+    pub fn new(inner: T) -> Self {
+      Self { inner, open: Mutex::new(false), opened: Condvar::new(), calls: AtomicUsize::new(0), running: AtomicUsize::new(0), most: AtomicUsize::new(0) }
+    }
+
+    /// Lets every held call and all later ones through.
+    pub fn release(&self) {
+      *self.open.lock().unwrap() = true;
+      self.opened.notify_all();
+    }
+
+    /// Waits until `n` calls are held.
+    pub fn held(&self, n: usize) {
+      while self.running() < n {
+        std::thread::yield_now();
+      }
+    }
+
+    pub fn calls(&self) -> usize {
+      self.calls.load(Ordering::SeqCst)
+    }
+
+    /// Calls held right now.
+    pub fn running(&self) -> usize {
+      self.running.load(Ordering::SeqCst)
+    }
+
+    /// Most calls held at once.
+    pub fn most(&self) -> usize {
+      self.most.load(Ordering::SeqCst)
+    }
+
+    fn call<R>(&self, f: impl FnOnce() -> R) -> R {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+      self.most.fetch_max(running, Ordering::SeqCst);
+      let _open = self.opened.wait_while(self.open.lock().unwrap(), |open| !*open).unwrap();
+      self.running.fetch_sub(1, Ordering::SeqCst);
+      f()
+    }
+  }
+
+  impl<T: ProjFSRead> ProjFSRead for Held<T> {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.call(|| self.inner.get_metadata(path, version))
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      self.call(|| self.inner.read(path, version, offset, buf))
+    }
+  }
+
+  pub fn names<I: IntoIterator<Item=FileBasicInfo>>(iter: I) -> Vec<String> {
+    iter.into_iter().map(|i| i.file_name.to_string_lossy().into_owned()).collect()
+  }
+
+  pub fn list<T: ProjFSDirEnum>(fs: &T, path: &str) -> std::io::Result<Vec<String>> {
+    Ok(names(fs.dir_iter(Guid::nil(), &ProjPathBuf::from(path), None, VersionInfo::NONE)?))
+  }
+
+  pub fn read_all<T: ProjFSRead>(fs: &T, path: &str) -> std::io::Result<Vec<u8>> {
+    let path = ProjPathBuf::from(path);
+    let info = fs.get_metadata(&path, VersionInfo::NONE)?;
+    let mut buf = vec![0; info.file_size as usize];
+    fs.read(&path, VersionInfo::NONE, 0, &mut buf)?;
+    Ok(buf)
+  }
+}
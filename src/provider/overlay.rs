@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, HashSet};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{BoxProvider, DynDirEnum};
+
+/// Name prefix of a whiteout, `.wh.name` hides `name` of the layers below.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// Entry marking its directory opaque, nothing below it in lower layers shows through.
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// `None` if a layer has nothing at the path, failing on other errors which must not uncover lower layers.
+fn found(result: std::io::Result<FileBasicInfo>) -> std::io::Result<Option<FileBasicInfo>> {
+  match result {
+    Ok(info) => Ok(Some(info)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+/// Topmost entry at a path with its layer, and the layers whose directory at the path shows through.
+type Resolved = (Option<(usize, FileBasicInfo)>, Vec<usize>);
+
+/// What one layer holds at a path.
+enum Probe {
+  Missing,
+  Whiteout,
+  File(FileBasicInfo),
+  Dir(FileBasicInfo),
+}
+
+/// Provider stacking several providers like an overlay filesystem.
+///
+/// Layers are added bottom first, every layer shadows the ones added before it:
+/// a file hides whatever lower layers have at the same path, directories of all layers are merged,
+/// and whiteout entries (`.wh.name`, `.wh..wh..opq`) hide lower entries without showing up themselves.
+///
+/// Resolving a path probes every layer at every ancestor of it, so deep paths over many layers
+/// cost many `get_metadata` calls on the layers.
+#[derive(Default)]
+pub struct Overlay {
+  /// Top layer first.
+  layers: Vec<BoxProvider>,
+}
+
+fn whiteout_of(name: &ProjPath) -> ProjPathBuf {
+  let mut units: Vec<u16> = WHITEOUT_PREFIX.encode_utf16().collect();
+  units.extend_from_slice(name.as_wide());
+  ProjPathBuf::from_wide(units)
+}
+
+fn whiteout_target(name: &ProjPath) -> Option<ProjPathBuf> {
+  let prefix: Vec<u16> = WHITEOUT_PREFIX.encode_utf16().collect();
+  let rest = name.as_wide().strip_prefix(&prefix[..])?;
+  Some(ProjPathBuf::from_wide(rest.to_vec()))
+}
+
+impl Overlay {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds `provider` on top of the current layers.
+  pub fn layer<T: DynDirEnum + 'static>(mut self, provider: T) -> Self {
+    self.layers.insert(0, Box::new(provider));
+    self
+  }
+
+  fn probe(layer: &BoxProvider, path: &ProjPath, version: VersionInfo) -> std::io::Result<Probe> {
+    match found(layer.get_metadata(path, version))? {
+      Some(info) if info.is_dir => return Ok(Probe::Dir(info)),
+      Some(info) => return Ok(Probe::File(info)),
+      None => {},
+    }
+    let whiteout = match (path.parent(), path.file_name()) {
+      (Some(parent), Some(name)) => parent.join(whiteout_of(name)),
+      _ => return Ok(Probe::Missing),
+    };
+    Ok(match found(layer.get_metadata(&whiteout, version))? {
+      Some(_) => Probe::Whiteout,
+      None => Probe::Missing,
+    })
+  }
+
+  fn is_opaque(layer: &BoxProvider, path: &ProjPath, version: VersionInfo) -> std::io::Result<bool> {
+    Ok(found(layer.get_metadata(&path.join(ProjPathBuf::from(OPAQUE_MARKER)), version))?.is_some())
+  }
+
+  /// Topmost entry at `path`, and the layers whose directory at `path` shows through, top first.
+  fn resolve(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<Resolved> {
+    if path.is_empty() {
+      // every layer has a root, unless an upper root is opaque
+      let mut end = self.layers.len();
+      for (i, layer) in self.layers.iter().enumerate() {
+        if Self::is_opaque(layer, path, version)? {
+          end = i + 1;
+          break
+        }
+      }
+      return Ok((None, (0..end).collect()))
+    }
+    let mut active: Vec<usize> = (0..self.layers.len()).collect();
+    let depth = path.components().count();
+    let mut prefix = ProjPathBuf::new();
+    for (d, component) in path.components().enumerate() {
+      prefix.push(component);
+      let last = d + 1 == depth;
+      let mut top = None;
+      let mut dirs = Vec::new();
+      for &i in &active {
+        match Self::probe(&self.layers[i], &prefix, version)? {
+          Probe::Missing => continue,
+          Probe::Whiteout => break,
+          Probe::File(info) => {
+            top.get_or_insert((i, info));
+            break
+          },
+          Probe::Dir(info) => {
+            top.get_or_insert((i, info));
+            dirs.push(i);
+            if Self::is_opaque(&self.layers[i], &prefix, version)? {
+              break
+            }
+          },
+        }
+      }
+      if last {
+        return Ok((top, dirs))
+      }
+      active = dirs;
+    }
+    unreachable!("path has at least one component")
+  }
+}
+
+impl ProjFSDirEnum for Overlay {
+  type DirIter = std::collections::btree_map::IntoValues<ProjPathBuf, FileBasicInfo>;
+  /// Layers list whole directories, a pattern could drop the whiteouts hiding what it matches in lower layers.
+  fn dir_iter(&self, id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let (top, dirs) = self.resolve(path, version)?;
+    if dirs.is_empty() {
+      return Err(match top {
+        Some(_) => std::io::Error::other("not a directory"),
+        None => std::io::ErrorKind::NotFound.into(),
+      })
+    }
+    let mut entries = BTreeMap::new();
+    let mut hidden = HashSet::new();
+    for i in dirs {
+      let mut whiteouts = Vec::new();
+      for entry in self.layers[i].dir_iter(id, path, None, version)? {
+        let name = ProjPathBuf::from(entry.file_name.as_os_str());
+        if let Some(target) = whiteout_target(&name) {
+          whiteouts.push(target);
+        } else if !hidden.contains(&name) {
+          entries.entry(name).or_insert(entry);
+        }
+      }
+      hidden.extend(whiteouts);
+      hidden.extend(entries.keys().cloned());
+    }
+    Ok(entries.into_values())
+  }
+}
+
+impl ProjFSRead for Overlay {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    match self.resolve(path, version)? {
+      (Some((_, info)), _) => Ok(info),
+      (None, _) => Err(std::io::ErrorKind::NotFound.into()),
+    }
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    match self.resolve(path, version)? {
+      (Some((i, info)), _) if !info.is_dir => self.layers[i].read(path, version, offset, buf),
+      _ => Err(std::io::ErrorKind::NotFound.into()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::{CallKind, Fault, FaultInjection};
+  use crate::provider::testing::*;
+  use crate::Pattern;
+
+  /// Layer applying the search pattern itself.
+  struct Filtering(Tree);
+
+  impl ProjFSDirEnum for Filtering {
+    type DirIter = std::vec::IntoIter<FileBasicInfo>;
+    fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+      let pattern = Pattern::new(&pattern.map_or("*".into(), |i| i.to_string_lossy()));
+      Ok(self.0.dir_iter(id, path, None, version)?.filter(|i| pattern.matches(&i.file_name.to_string_lossy())).collect::<Vec<_>>().into_iter())
+    }
+    fn filters_pattern(&self) -> bool {
+      true
+    }
+  }
+
+  impl ProjFSRead for Filtering {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.0.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      self.0.read(path, version, offset, buf)
+    }
+  }
+
+  fn overlay() -> Overlay {
+    Overlay::new()
+      .layer(Tree::new(&[
+        ("bin\\tool.exe", "base tool"),
+        ("etc\\config", "base config"),
+        ("etc\\hosts", "base hosts"),
+        ("lib\\a.dll", "a"),
+        ("lib\\b.dll", "b"),
+        ("share\\doc\\readme", "doc"),
+        ("var", "base var file"),
+      ]))
+      .layer(Tree::new(&[
+        ("etc\\config", "patched config"),
+        ("etc\\.wh.hosts", ""),
+        ("lib\\c.dll", "c"),
+        ("share\\.wh..wh..opq", ""),
+        ("share\\new", "new"),
+        ("var\\log", "log"),
+      ]))
+  }
+
+  #[test]
+  fn merges_directories_in_collation_order() {
+    let fs = overlay();
+    assert_eq!(list(&fs, "").unwrap(), vec!["bin", "etc", "lib", "share", "var"]);
+    assert_eq!(list(&fs, "lib").unwrap(), vec!["a.dll", "b.dll", "c.dll"]);
+    assert_eq!(list(&fs, "bin").unwrap(), vec!["tool.exe"]);
+  }
+
+  #[test]
+  fn upper_layer_shadows_lower() {
+    let fs = overlay();
+    assert_eq!(read_all(&fs, "etc\\config").unwrap(), b"patched config");
+    assert_eq!(read_all(&fs, "bin\\tool.exe").unwrap(), b"base tool");
+    // an upper directory shadows a lower file of the same name
+    assert!(fs.get_metadata(&ProjPathBuf::from("var"), VersionInfo::NONE).unwrap().is_dir);
+    assert_eq!(list(&fs, "var").unwrap(), vec!["log"]);
+  }
+
+  #[test]
+  fn whiteouts_hide_lower_entries() {
+    let fs = overlay();
+    assert_eq!(list(&fs, "etc").unwrap(), vec!["config"]);
+    let e = fs.get_metadata(&ProjPathBuf::from("etc\\hosts"), VersionInfo::NONE).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    assert!(read_all(&fs, "etc\\hosts").is_err());
+    // whiteouts count whatever the search pattern, even in layers applying it
+    let fs = Overlay::new()
+      .layer(Tree::new(&[("etc\\hosts", "base hosts")]))
+      .layer(Filtering(Tree::new(&[("etc\\.wh.hosts", ""), ("etc\\hostname", "host")])));
+    let entries = fs.dir_iter(Guid::nil(), &ProjPathBuf::from("etc"), Some(&ProjPathBuf::from("host*")), VersionInfo::NONE).unwrap();
+    assert_eq!(names(entries), vec!["hostname"]);
+  }
+
+  #[test]
+  fn layer_errors_do_not_uncover_lower_layers() {
+    let upper = Tree::new(&[("etc\\hosts", "upper hosts")]);
+    let fs = Overlay::new()
+      .layer(Tree::new(&[("etc\\hosts", "base hosts")]))
+      .layer(FaultInjection::new(upper, 0).inject(CallKind::GetMetadata, "*hosts", 1.0, Fault::Error(std::io::ErrorKind::PermissionDenied)));
+    assert_eq!(read_all(&fs, "etc\\hosts").unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(list(&fs, "etc").unwrap(), vec!["hosts"]);
+  }
+
+  #[test]
+  fn opaque_directory_hides_everything_below() {
+    let fs = overlay();
+    assert_eq!(list(&fs, "share").unwrap(), vec!["new"]);
+    assert!(fs.get_metadata(&ProjPathBuf::from("share\\doc"), VersionInfo::NONE).is_err());
+    assert!(list(&fs, "share\\doc").is_err());
+  }
+
+  #[test]
+  fn file_shadows_lower_directory() {
+    let fs = Overlay::new()
+      .layer(Tree::new(&[("a\\b", "lower")]))
+      .layer(Tree::new(&[("a", "upper file")]));
+    assert!(!fs.get_metadata(&ProjPathBuf::from("a"), VersionInfo::NONE).unwrap().is_dir);
+    assert!(fs.get_metadata(&ProjPathBuf::from("a\\b"), VersionInfo::NONE).is_err());
+    assert!(list(&fs, "a").is_err());
+  }
+}
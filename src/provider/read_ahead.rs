@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, HashMap};
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use crate::{FileBasicInfo, Guid, PlaceholderVersionInfo, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+
+/// Tuning of [`ReadAhead`].
+#[derive(Debug, Clone)]
+pub struct ReadAheadOptions {
+  /// Size of the reads prefetching a stream.
+  pub chunk_size: usize,
+  /// Chunks prefetched past the last read of a sequential stream.
+  pub chunks_ahead: usize,
+  /// Bytes prefetched or being prefetched across all streams.
+  pub buffer: usize,
+  /// Background threads reading ahead.
+  pub workers: usize,
+  /// Streams tracked, the least recently read is forgotten past it.
+  pub streams: usize,
+}
+
+impl Default for ReadAheadOptions {
+  fn default() -> Self {
+    Self { chunk_size: 1 << 20, chunks_ahead: 4, buffer: 64 << 20, workers: 4, streams: 64 }
+  }
+}
+
+enum Slot {
+  Pending(usize),
+  Ready(Arc<[u8]>),
+}
+
+impl Slot {
+  fn len(&self) -> usize {
+    match self {
+      Slot::Pending(len) => *len,
+      Slot::Ready(data) => data.len(),
+    }
+  }
+}
+
+struct Stream {
+  /// Changes whenever the prefetched chunks are dropped, so late prefetches are thrown away.
+  epoch: u64,
+  path: ProjPathBuf,
+  version: Option<PlaceholderVersionInfo>,
+  /// Size of the file, once the stream turned out sequential.
+  size: Option<u64>,
+  /// Where a sequential read continues.
+  next: u64,
+  /// Prefetched chunks by offset.
+  chunks: BTreeMap<u64, Slot>,
+  used: u64,
+}
+
+#[derive(Default)]
+struct State {
+  streams: HashMap<Guid, Stream>,
+  /// Bytes of all chunks.
+  buffered: usize,
+  tick: u64,
+}
+
+impl State {
+  fn drop_chunks(&mut self, id: &Guid, before: Option<u64>) {
+    self.tick += 1;
+    let tick = self.tick;
+    let stream = match self.streams.get_mut(id) {
+      Some(stream) => stream,
+      None => return,
+    };
+    let dropped: Vec<u64> = stream.chunks.iter().filter(|(&start, slot)| before.is_none_or(|end| start + slot.len() as u64 <= end)).map(|(&start, _)| start).collect();
+    for start in dropped {
+      self.buffered -= stream.chunks.remove(&start).unwrap().len();
+    }
+    if before.is_none() {
+      stream.epoch = tick;
+    }
+  }
+
+  fn forget(&mut self, id: &Guid) {
+    self.drop_chunks(id, None);
+    self.streams.remove(id);
+  }
+}
+
+struct Job {
+  stream: Guid,
+  epoch: u64,
+  offset: u64,
+  len: usize,
+  path: ProjPathBuf,
+  version: Option<PlaceholderVersionInfo>,
+}
+
+struct Shared<T> {
+  inner: T,
+  options: ReadAheadOptions,
+  state: Mutex<State>,
+  /// Signaled whenever a prefetch completes.
+  done: Condvar,
+}
+
+impl<T: ProjFSRead> Shared<T> {
+  fn prefetch(&self, job: Job) {
+    let mut data = vec![0; job.len];
+    let version = job.version.as_ref().map_or(VersionInfo::NONE, VersionInfo::new);
+    // a panic must neither leave the chunk pending, with readers waiting for it forever, nor take the worker down
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| self.inner.read(&job.path, version, job.offset, &mut data)))
+      .unwrap_or_else(|_| Err(std::io::Error::other("the provider panicked")));
+    let mut state = self.state.lock().unwrap();
+    let state = &mut *state;
+    // a read may have overtaken the chunk and dropped it meanwhile
+    if let Some(stream) = state.streams.get_mut(&job.stream).filter(|i| i.epoch == job.epoch) {
+      if let Some(slot @ Slot::Pending(_)) = stream.chunks.get_mut(&job.offset) {
+        match result {
+          Ok(()) => *slot = Slot::Ready(data.into()),
+          // the read itself goes to the inner provider again
+          Err(_) => state.buffered -= stream.chunks.remove(&job.offset).unwrap().len(),
+        }
+      }
+    }
+    self.done.notify_all();
+  }
+}
+
+/// Decorator reading ahead of the streams reading a file sequentially.
+///
+/// Reads are told apart by their [`data_stream_id`](crate::data_stream_id). A stream starting at the beginning
+/// of a file, or continuing where its last read ended, is sequential: background workers read the next
+/// `chunks_ahead` chunks of it into a buffer shared by all streams, from which its next reads are served.
+/// Any other read drops what was prefetched for the stream. Reads outside of a data stream go to the inner provider.
+pub struct ReadAhead<T> {
+  shared: Arc<Shared<T>>,
+  jobs: Option<Sender<Job>>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: ProjFSRead + Send + Sync + 'static> ReadAhead<T> {
+  pub fn new(inner: T) -> Self {
+    Self::with_options(inner, ReadAheadOptions::default())
+  }
+
+  pub fn with_options(inner: T, options: ReadAheadOptions) -> Self {
+    let workers = options.workers.max(1);
+    let shared = Arc::new(Shared { inner, options, state: Mutex::new(State::default()), done: Condvar::new() });
+    let (sender, receiver) = channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let workers = (0..workers).map(|_| {
+      let (shared, receiver): (_, Arc<Mutex<Receiver<Job>>>) = (shared.clone(), receiver.clone());
+      std::thread::spawn(move || loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+          Ok(job) => shared.prefetch(job),
+          Err(_) => break,
+        }
+      })
+    }).collect();
+    Self { shared, jobs: Some(sender), workers }
+  }
+}
+
+impl<T> ReadAhead<T> {
+  pub fn inner(&self) -> &T {
+    &self.shared.inner
+  }
+
+  /// Bytes prefetched or being prefetched.
+  pub fn buffered(&self) -> usize {
+    self.shared.state.lock().unwrap().buffered
+  }
+}
+
+impl<T: ProjFSRead> ReadAhead<T> {
+  /// Copies `offset..offset + buf.len()` of stream `id` from its chunks, waiting for pending ones.
+  /// `false` if the chunks do not cover the range.
+  fn serve<'a>(&'a self, mut state: MutexGuard<'a, State>, id: &Guid, offset: u64, buf: &mut [u8]) -> (MutexGuard<'a, State>, bool) {
+    let end = offset + buf.len() as u64;
+    loop {
+      let stream = match state.streams.get(id) {
+        Some(stream) => stream,
+        None => return (state, false),
+      };
+      let mut pos = offset;
+      let mut pending = false;
+      let mut cursor = stream.chunks.range(..=offset).next_back().into_iter().chain(stream.chunks.range(offset + 1..end));
+      while pos < end {
+        match cursor.next() {
+          Some((&start, slot)) if start <= pos && pos < start + slot.len() as u64 => {
+            pending |= matches!(slot, Slot::Pending(_));
+            pos = start + slot.len() as u64;
+          },
+          _ => return (state, false),
+        }
+      }
+      if !pending {
+        break
+      }
+      state = self.shared.done.wait(state).unwrap();
+    }
+    for (&start, slot) in state.streams[id].chunks.range(..end) {
+      let (from, to) = (offset.max(start), end.min(start + slot.len() as u64));
+      if let (true, Slot::Ready(data)) = (from < to, slot) {
+        buf[(from - offset) as usize..(to - offset) as usize].copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+      }
+    }
+    (state, true)
+  }
+
+  /// Queues the chunks of stream `id` missing up to `chunks_ahead` past its last read, within the buffer.
+  fn schedule(&self, state: &mut State, id: &Guid) {
+    let options = &self.shared.options;
+    let jobs = match &self.jobs {
+      Some(jobs) => jobs,
+      None => return,
+    };
+    let stream = match state.streams.get_mut(id) {
+      Some(stream) => stream,
+      None => return,
+    };
+    let mut start = stream.chunks.iter().next_back().map_or(stream.next, |(&start, slot)| start + slot.len() as u64).max(stream.next);
+    let limit = stream.next.saturating_add((options.chunk_size.max(1) * options.chunks_ahead) as u64).min(stream.size.unwrap_or(0));
+    while start < limit {
+      let len = (limit - start).min(options.chunk_size.max(1) as u64) as usize;
+      if state.buffered + len > options.buffer {
+        break
+      }
+      state.buffered += len;
+      stream.chunks.insert(start, Slot::Pending(len));
+      let _ = jobs.send(Job { stream: *id, epoch: stream.epoch, offset: start, len, path: stream.path.clone(), version: stream.version });
+      start += len as u64;
+    }
+  }
+}
+
+impl<T> Drop for ReadAhead<T> {
+  fn drop(&mut self) {
+    self.jobs.take();
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for ReadAhead<T> {
+  type DirIter = T::DirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.shared.inner.dir_iter(id, path, pattern, version)
+  }
+  fn filters_pattern(&self) -> bool {
+    self.shared.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for ReadAhead<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.shared.inner.get_metadata(path, version)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let id = match crate::data_stream_id() {
+      Some(id) if !buf.is_empty() => id,
+      _ => return self.shared.inner.read(path, version, offset, buf),
+    };
+    let end = offset.checked_add(buf.len() as u64).ok_or(std::io::ErrorKind::InvalidInput)?;
+    let content_id = version.content_id().copied();
+    let lock = || self.shared.state.lock().unwrap();
+    let mut state = lock();
+    if !state.streams.get(&id).is_some_and(|i| *i.path == *path && i.version.map(|v| v.ContentID) == content_id) {
+      state.forget(&id);
+      if state.streams.len() >= self.shared.options.streams {
+        if let Some(oldest) = state.streams.iter().min_by_key(|(_, i)| i.used).map(|(id, _)| *id) {
+          state.forget(&oldest);
+        }
+      }
+      let version = version.info().filter(|_| content_id.is_some()).copied();
+      state.streams.insert(id, Stream { epoch: 0, path: path.to_owned(), version, size: None, next: 0, chunks: BTreeMap::new(), used: 0 });
+    }
+    let next = state.streams[&id].next;
+    let (guard, served) = self.serve(state, &id, offset, buf);
+    state = guard;
+    let sequential = served || offset == next;
+    // chunks the stream has read past go, all of them if it jumped elsewhere
+    state.drop_chunks(&id, Some(end).filter(|_| sequential));
+    if !served {
+      drop(state);
+      self.shared.inner.read(path, version, offset, buf)?;
+      state = lock();
+    }
+    state.tick += 1;
+    let tick = state.tick;
+    let size = match state.streams.get_mut(&id) {
+      Some(stream) => {
+        stream.next = end;
+        stream.used = tick;
+        stream.size
+      },
+      None => return Ok(()),
+    };
+    if !sequential {
+      return Ok(())
+    }
+    if size.is_none() {
+      drop(state);
+      let size = match self.shared.inner.get_metadata(path, version) {
+        Ok(info) => info.file_size,
+        Err(_) => return Ok(()),
+      };
+      state = lock();
+      match state.streams.get_mut(&id) {
+        Some(stream) => stream.size = Some(size),
+        None => return Ok(()),
+      }
+    }
+    self.schedule(&mut state, &id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  /// Provider recording the ranges read from it.
+  struct Recording(Tree, Mutex<Vec<(u64, usize)>>);
+
+  impl ProjFSRead for Recording {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.0.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      self.1.lock().unwrap().push((offset, buf.len()));
+      self.0.read(path, version, offset, buf)
+    }
+  }
+
+  fn read_ahead(chunks_ahead: usize, buffer: usize) -> ReadAhead<Recording> {
+    let inner = Recording(Tree::new(&[("file", "0123456789abcdefghij")]), Mutex::new(Vec::new()));
+    ReadAhead::with_options(inner, ReadAheadOptions { chunk_size: 4, chunks_ahead, buffer, workers: 2, streams: 4 })
+  }
+
+  fn read(fs: &ReadAhead<Recording>, stream: Option<Guid>, offset: u64, len: usize) -> String {
+    let mut buf = vec![0; len];
+    let path = ProjPathBuf::from("file");
+    match stream {
+      Some(id) => crate::with_data_stream(id, || fs.read(&path, VersionInfo::NONE, offset, &mut buf)).unwrap(),
+      None => fs.read(&path, VersionInfo::NONE, offset, &mut buf).unwrap(),
+    }
+    String::from_utf8(buf).unwrap()
+  }
+
+  /// Provider panicking on reads outside of a data stream, as the prefetches are.
+  struct PanickingPrefetch(Tree);
+
+  impl ProjFSRead for PanickingPrefetch {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.0.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      assert!(crate::data_stream_id().is_some(), "prefetch of {}", offset);
+      self.0.read(path, version, offset, buf)
+    }
+  }
+
+  /// Provider holding its prefetches, the reads outside of a data stream, until released.
+  struct SlowPrefetch(Tree, Held<Tree>);
+
+  impl ProjFSRead for SlowPrefetch {
+    fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+      self.0.get_metadata(path, version)
+    }
+    fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+      match crate::data_stream_id() {
+        Some(_) => self.0.read(path, version, offset, buf),
+        None => self.1.read(path, version, offset, buf),
+      }
+    }
+  }
+
+  fn reads(fs: &ReadAhead<Recording>) -> Vec<(u64, usize)> {
+    let mut reads = fs.inner().1.lock().unwrap().clone();
+    reads.sort();
+    reads
+  }
+
+  #[test]
+  fn serves_sequential_reads_from_prefetched_chunks() {
+    let fs = read_ahead(2, 64);
+    let stream = Some(Guid::new_v4());
+    let data: Vec<String> = (0..5).map(|i| read(&fs, stream, i * 4, 4)).collect();
+    assert_eq!(data.concat(), "0123456789abcdefghij");
+    // every range reached the provider once, all but the first ahead of the stream
+    assert_eq!(reads(&fs), vec![(0, 4), (4, 4), (8, 4), (12, 4), (16, 4)]);
+    assert_eq!(fs.buffered(), 0);
+  }
+
+  #[test]
+  fn random_reads_drop_prefetched_chunks() {
+    let fs = read_ahead(2, 64);
+    let stream = Some(Guid::new_v4());
+    assert_eq!(read(&fs, stream, 0, 4), "0123");
+    assert_eq!(fs.buffered(), 8);
+    assert_eq!(read(&fs, stream, 14, 2), "ef");
+    assert_eq!(fs.buffered(), 0);
+    // the stream continues sequentially from its last read
+    assert_eq!(read(&fs, stream, 16, 2), "gh");
+    assert_eq!(fs.buffered(), 2);
+    assert_eq!(read(&fs, stream, 18, 2), "ij");
+    assert_eq!(fs.buffered(), 0);
+  }
+
+  #[test]
+  fn streams_and_buffer_are_bounded() {
+    let fs = read_ahead(4, 4);
+    // reads outside of a data stream go straight to the provider
+    assert_eq!(read(&fs, None, 0, 4), "0123");
+    assert_eq!(fs.buffered(), 0);
+    let (a, b) = (Some(Guid::new_v4()), Some(Guid::new_v4()));
+    assert_eq!(read(&fs, a, 0, 2), "01");
+    assert_eq!(fs.buffered(), 4);
+    // no room left for the other stream
+    assert_eq!(read(&fs, b, 0, 2), "01");
+    assert_eq!(fs.buffered(), 4);
+    assert_eq!(read(&fs, a, 2, 4), "2345");
+    assert_eq!(read(&fs, b, 2, 2), "23");
+    assert_eq!(reads(&fs).iter().filter(|(offset, _)| *offset == 2).count(), 2);
+  }
+
+  #[test]
+  fn panicking_prefetches_fall_back_to_the_provider() {
+    let inner = PanickingPrefetch(Tree::new(&[("file", "0123456789abcdefghij")]));
+    let fs = ReadAhead::with_options(inner, ReadAheadOptions { chunk_size: 4, chunks_ahead: 2, buffer: 64, workers: 1, streams: 4 });
+    let path = ProjPathBuf::from("file");
+    let stream = Guid::new_v4();
+    let data: Vec<u8> = (0..5).flat_map(|i| {
+      let mut buf = vec![0; 4];
+      crate::with_data_stream(stream, || fs.read(&path, VersionInfo::NONE, i * 4, &mut buf)).unwrap();
+      buf
+    }).collect();
+    // no chunk stayed pending and the single worker outlived every panic
+    assert_eq!(data, b"0123456789abcdefghij");
+    assert_eq!(fs.buffered(), 0);
+  }
+  #[test]
+  fn late_prefetches_of_overtaken_chunks_are_dropped() {
+    let tree = || Tree::new(&[("file", "0123456789abcdefghij")]);
+    let inner = SlowPrefetch(tree(), Held::new(tree()));
+    // a single worker finishes the prefetches in order
+    let fs = ReadAhead::with_options(inner, ReadAheadOptions { chunk_size: 4, chunks_ahead: 2, buffer: 64, workers: 1, streams: 4 });
+    let path = ProjPathBuf::from("file");
+    let stream = Guid::new_v4();
+    let read = |offset, len| {
+      let mut buf = vec![0; len];
+      crate::with_data_stream(stream, || fs.read(&path, VersionInfo::NONE, offset, &mut buf)).unwrap();
+      String::from_utf8(buf).unwrap()
+    };
+    assert_eq!(read(0, 4), "0123");
+    fs.inner().1.held(1);
+    // overtakes the pending 4..8 and 8..12
+    assert_eq!(read(4, 12), "456789abcdef");
+    assert_eq!(fs.buffered(), 4);
+    fs.inner().1.release();
+    // served once 16..20 came in, after the overtaken chunks
+    assert_eq!(read(16, 4), "ghij");
+    assert_eq!(fs.buffered(), 0);
+  }
+}
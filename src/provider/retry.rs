@@ -0,0 +1,177 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, VersionInfo};
+use super::{CallKind, Rng};
+
+/// Tuning of [`Retry`].
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+  /// Attempts after the first one.
+  pub retries: u32,
+  /// Delay before the first retry, doubled for every further one.
+  pub backoff: Duration,
+  /// Limit of the doubled delay.
+  pub max_backoff: Duration,
+  /// Time a call may take in all, no retry starts past it.
+  pub deadline: Duration,
+}
+
+impl Default for RetryOptions {
+  fn default() -> Self {
+    Self { retries: 3, backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(5), deadline: Duration::from_secs(30) }
+  }
+}
+
+/// Errors worth trying again by default: timeouts, interruptions, busy resources and network failures.
+pub fn is_transient(e: &std::io::Error) -> bool {
+  use std::io::ErrorKind::*;
+  matches!(e.kind(), TimedOut | Interrupted | ResourceBusy | ConnectionRefused | ConnectionReset | ConnectionAborted
+    | NotConnected | BrokenPipe | HostUnreachable | NetworkUnreachable | NetworkDown)
+}
+
+type Classifier = Box<dyn Fn(&std::io::Error) -> bool + Send + Sync>;
+
+/// Decorator retrying the calls of a provider failing with transient errors.
+///
+/// Errors are sorted into transient and permanent ones by [`is_transient`], or by the function given to
+/// [`classify`](Self::classify). Transient failures are retried after an exponential backoff, of which a random
+/// part up to half is left out so clients failing together do not retry together, as long as the call stays
+/// within its deadline. Only `dir_iter` itself is retried for enumerations, not the iteration after it.
+pub struct Retry<T> {
+  inner: T,
+  options: RetryOptions,
+  classify: Classifier,
+  rng: Mutex<Rng>,
+  retries: [AtomicU64; 3],
+  exhausted: [AtomicU64; 3],
+}
+
+impl<T> Retry<T> {
+  pub fn new(inner: T) -> Self {
+    Self::with_options(inner, RetryOptions::default())
+  }
+
+  pub fn with_options(inner: T, options: RetryOptions) -> Self {
+    let seed = Guid::new_v4().as_u128() as u64;
+    Self { inner, options, classify: Box::new(is_transient), rng: Mutex::new(Rng::new(seed)), retries: Default::default(), exhausted: Default::default() }
+  }
+
+  /// Retries the errors for which `transient` returns true instead.
+  pub fn classify<F: Fn(&std::io::Error) -> bool + Send + Sync + 'static>(mut self, transient: F) -> Self {
+    self.classify = Box::new(transient);
+    self
+  }
+
+  pub fn inner(&self) -> &T {
+    &self.inner
+  }
+
+  /// Retries made of calls of `kind`.
+  pub fn retries(&self, kind: CallKind) -> u64 {
+    self.retries[kind.index()].load(Ordering::Relaxed)
+  }
+
+  /// Calls of `kind` that still failed with a transient error when out of retries or time.
+  pub fn exhausted(&self, kind: CallKind) -> u64 {
+    self.exhausted[kind.index()].load(Ordering::Relaxed)
+  }
+
+  fn run<R>(&self, kind: CallKind, mut f: impl FnMut() -> std::io::Result<R>) -> std::io::Result<R> {
+    let deadline = Instant::now() + self.options.deadline;
+    let mut backoff = self.options.backoff;
+    let mut attempt = 0;
+    loop {
+      let e = match f() {
+        Err(e) if (self.classify)(&e) => e,
+        result => return result,
+      };
+      let delay = backoff.mul_f64(1.0 - self.rng.lock().unwrap().next_f64() / 2.0);
+      if attempt >= self.options.retries || Instant::now() + delay >= deadline {
+        self.exhausted[kind.index()].fetch_add(1, Ordering::Relaxed);
+        return Err(e)
+      }
+      std::thread::sleep(delay);
+      attempt += 1;
+      self.retries[kind.index()].fetch_add(1, Ordering::Relaxed);
+      backoff = (backoff * 2).min(self.options.max_backoff);
+    }
+  }
+}
+
+impl<T: ProjFSDirEnum> ProjFSDirEnum for Retry<T> {
+  type DirIter = T::DirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.run(CallKind::DirIter, || self.inner.dir_iter(id, path, pattern, version))
+  }
+  fn filters_pattern(&self) -> bool {
+    self.inner.filters_pattern()
+  }
+}
+
+impl<T: ProjFSRead> ProjFSRead for Retry<T> {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.run(CallKind::GetMetadata, || self.inner.get_metadata(path, version))
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    self.run(CallKind::Read, || self.inner.read(path, version, offset, buf))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn retry(failures: usize, error: fn() -> std::io::Error, options: RetryOptions) -> Retry<Failing<Tree>> {
+    let inner = Failing::new(Tree::new(&[("dir\\file", "data")]), error);
+    inner.fail(failures);
+    Retry::with_options(inner, options)
+  }
+
+  fn quick() -> RetryOptions {
+    RetryOptions { backoff: Duration::from_millis(1), ..Default::default() }
+  }
+
+  #[test]
+  fn retries_transient_errors() {
+    let fs = retry(2, || std::io::ErrorKind::ConnectionReset.into(), quick());
+    assert_eq!(read_all(&fs, "dir\\file").unwrap(), b"data");
+    assert_eq!(fs.retries(CallKind::GetMetadata), 2);
+    assert_eq!(fs.retries(CallKind::Read), 0);
+    fs.inner().fail(3);
+    assert_eq!(list(&fs, "dir").unwrap(), vec!["file"]);
+    assert_eq!(fs.retries(CallKind::DirIter), 3);
+    // out of retries
+    fs.inner().fail(4);
+    assert_eq!(list(&fs, "dir").err().unwrap().kind(), std::io::ErrorKind::ConnectionReset);
+    assert_eq!(fs.retries(CallKind::DirIter), 6);
+    assert_eq!(fs.exhausted(CallKind::DirIter), 1);
+  }
+
+  #[test]
+  fn permanent_errors_fail_at_once() {
+    let fs = retry(1, || std::io::ErrorKind::NotFound.into(), quick());
+    assert_eq!(read_all(&fs, "dir\\file").err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(fs.inner().calls(), 1);
+    assert_eq!(fs.retries(CallKind::GetMetadata), 0);
+    assert_eq!(fs.exhausted(CallKind::GetMetadata), 0);
+    // unless classified otherwise
+    let fs = retry(1, || std::io::Error::other("backend hiccup"), quick()).classify(|e| e.to_string().contains("hiccup"));
+    assert_eq!(read_all(&fs, "dir\\file").unwrap(), b"data");
+  }
+
+  #[test]
+  fn stops_at_the_deadline() {
+    let options = RetryOptions { retries: 100, backoff: Duration::from_millis(10), max_backoff: Duration::from_millis(10), deadline: Duration::from_millis(50) };
+    let fs = retry(usize::MAX, || std::io::ErrorKind::TimedOut.into(), options);
+    let start = Instant::now();
+    assert_eq!(read_all(&fs, "dir\\file").err().unwrap().kind(), std::io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_millis(100));
+    // delays of 5 to 10ms fit at most 9 times
+    let calls = fs.inner().calls();
+    assert!((2..=10).contains(&calls), "{} calls", calls);
+    assert_eq!(fs.exhausted(CallKind::GetMetadata), 1);
+  }
+}
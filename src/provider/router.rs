@@ -0,0 +1,152 @@
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{dir_info, sort_entries, BoxDirIter, BoxProvider, DynDirEnum};
+
+/// Provider mounting other providers at sub-paths.
+///
+/// Each path is served by the provider of the deepest mount point containing it,
+/// with the mount point stripped from the path. Directories leading to mount points
+/// are synthesized, and shadow entries of the same name of an outer provider.
+#[derive(Default)]
+pub struct Router {
+  /// Deepest mount points first.
+  mounts: Vec<(ProjPathBuf, BoxProvider)>,
+}
+
+impl Router {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Mounts `provider` at `at`, replacing whatever was mounted there. An empty `at` mounts at the root.
+  pub fn mount<P: Into<ProjPathBuf>, T: DynDirEnum + 'static>(mut self, at: P, provider: T) -> Self {
+    let at = at.into();
+    self.mounts.retain(|(i, _)| i.components().ne(at.components()));
+    let depth = at.components().count();
+    let index = self.mounts.iter().position(|(i, _)| i.components().count() < depth).unwrap_or(self.mounts.len());
+    self.mounts.insert(index, (at, Box::new(provider)));
+    self
+  }
+
+  /// Provider serving `path`, with `path` relative to its mount point.
+  fn resolve<'p>(&self, path: &'p ProjPath) -> Option<(&BoxProvider, &'p ProjPath)> {
+    self.mounts.iter().find_map(|(at, provider)| Some((provider, path.strip_prefix(at)?)))
+  }
+
+  /// Names of the synthesized directories directly under `path`.
+  fn synthesized(&self, path: &ProjPath) -> Vec<&ProjPath> {
+    let mut names: Vec<&ProjPath> = self.mounts.iter()
+      .filter_map(|(at, _)| at.strip_prefix(path)?.components().next())
+      .collect();
+    names.sort();
+    names.dedup();
+    names
+  }
+
+  /// Whether `path` is a mount point or leads to one.
+  fn is_synthesized(&self, path: &ProjPath) -> bool {
+    !path.is_empty() && self.mounts.iter().any(|(at, _)| at.starts_with(path))
+  }
+}
+
+impl ProjFSDirEnum for Router {
+  type DirIter = BoxDirIter;
+  fn dir_iter(&self, id: Guid, path: &ProjPath, pattern: Option<&ProjPath>, version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let synthesized = self.synthesized(path);
+    let owned = match self.resolve(path).map(|(provider, rest)| provider.dir_iter(id, rest, pattern, version)) {
+      Some(Ok(iter)) => Some(iter),
+      Some(Err(e)) if synthesized.is_empty() || e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+      _ => None,
+    };
+    if synthesized.is_empty() {
+      return owned.ok_or_else(|| std::io::ErrorKind::NotFound.into())
+    }
+    let mut entries: Vec<FileBasicInfo> = owned.into_iter().flatten()
+      .filter(|i| !synthesized.contains(&&*ProjPathBuf::from(i.file_name.as_os_str())))
+      .collect();
+    entries.extend(synthesized.into_iter().map(dir_info));
+    sort_entries(&mut entries);
+    Ok(Box::new(entries.into_iter()))
+  }
+}
+
+impl ProjFSRead for Router {
+  fn get_metadata(&self, path: &ProjPath, version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    if self.is_synthesized(path) {
+      return Ok(dir_info(path.file_name().unwrap_or(path)))
+    }
+    let (provider, rest) = self.resolve(path).ok_or(std::io::ErrorKind::NotFound)?;
+    provider.get_metadata(rest, version)
+  }
+
+  fn read(&self, path: &ProjPath, version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    if self.is_synthesized(path) {
+      return Err(std::io::ErrorKind::NotFound.into())
+    }
+    let (provider, rest) = self.resolve(path).ok_or(std::io::ErrorKind::NotFound)?;
+    provider.read(rest, version, offset, buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn router() -> Router {
+    Router::new()
+      .mount("", Tree::new(&[("readme.txt", "root"), ("build\\stale.txt", "stale"), ("build\\out\\shadowed", "x")]))
+      .mount("build\\out", Tree::new(&[("app.exe", "binary"), ("lib\\a.dll", "library")]))
+      .mount("cache\\pkg", Tree::new(&[("index.json", "{}")]))
+      .mount("docs", Tree::new(&[("index.html", "<html>")]))
+  }
+
+  #[test]
+  fn root_merges_mounts_and_root_provider() {
+    let router = router();
+    assert_eq!(list(&router, "").unwrap(), vec!["build", "cache", "docs", "readme.txt"]);
+    // the root provider's own `build` is merged with the synthesized `out`
+    assert_eq!(list(&router, "build").unwrap(), vec!["out", "stale.txt"]);
+    assert_eq!(list(&router, "cache").unwrap(), vec!["pkg"]);
+  }
+
+  #[test]
+  fn paths_are_rewritten_for_mounted_providers() {
+    let router = router();
+    assert_eq!(list(&router, "build\\out").unwrap(), vec!["app.exe", "lib"]);
+    assert_eq!(list(&router, "BUILD\\Out\\lib").unwrap(), vec!["a.dll"]);
+    assert_eq!(read_all(&router, "build\\out\\lib\\a.dll").unwrap(), b"library");
+    assert_eq!(read_all(&router, "docs\\index.html").unwrap(), b"<html>");
+    assert_eq!(read_all(&router, "readme.txt").unwrap(), b"root");
+  }
+
+  #[test]
+  fn intermediate_directories_have_metadata() {
+    let router = router();
+    for path in &["build", "build\\out", "cache", "cache\\pkg"] {
+      let info = router.get_metadata(&ProjPathBuf::from(*path), VersionInfo::NONE).unwrap();
+      assert!(info.is_dir, "{}", path);
+    }
+    let info = router.get_metadata(&ProjPathBuf::from("cache\\pkg\\index.json"), VersionInfo::NONE).unwrap();
+    assert_eq!(info.file_size, 2);
+    let e = router.get_metadata(&ProjPathBuf::from("cache\\other"), VersionInfo::NONE).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(list(&router, "cache\\other").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+  }
+
+  #[test]
+  fn without_root_provider() {
+    let router = Router::new().mount("a\\b", Tree::new(&[("f", "x")]));
+    assert_eq!(list(&router, "").unwrap(), vec!["a"]);
+    assert_eq!(list(&router, "a\\b").unwrap(), vec!["f"]);
+    let e = router.get_metadata(&ProjPathBuf::from("readme.txt"), VersionInfo::NONE).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+  }
+
+  #[test]
+  fn remount_replaces() {
+    let router = Router::new()
+      .mount("a", Tree::new(&[("old", "x")]))
+      .mount("A", Tree::new(&[("new", "x")]));
+    assert_eq!(list(&router, "a").unwrap(), vec!["new"]);
+  }
+}
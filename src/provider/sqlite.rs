@@ -0,0 +1,393 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::{check_range, dir_info, sort_entries, unix_filetime};
+
+/// Columns of the table a [`SqliteTree`] projects.
+#[derive(Debug, Clone)]
+pub struct SqliteSchema {
+  pub table: String,
+  /// Path of the entry, its components joined by `separator`.
+  pub path: String,
+  /// Path of the parent directory, empty at the root. Enumerations look entries up by it.
+  pub parent: String,
+  /// Non-zero for directories.
+  pub is_dir: String,
+  /// File size, `None` to take the length of the `data` column.
+  pub size: Option<String>,
+  /// Modification time in unix seconds, `None` if the table has none.
+  pub mtime: Option<String>,
+  /// Blob column with the content of files.
+  pub data: String,
+  pub separator: char,
+}
+
+impl Default for SqliteSchema {
+  fn default() -> Self {
+    Self {
+      table: "files".into(),
+      path: "path".into(),
+      parent: "parent".into(),
+      is_dir: "is_dir".into(),
+      size: None,
+      mtime: Some("mtime".into()),
+      data: "data".into(),
+      separator: '/',
+    }
+  }
+}
+
+fn quote(name: &str) -> String {
+  format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_error(e: rusqlite::Error) -> std::io::Error {
+  std::io::Error::other(e)
+}
+
+/// Row of the projected table.
+struct Row {
+  rowid: i64,
+  path: String,
+  is_dir: bool,
+  size: i64,
+  mtime: i64,
+}
+
+impl Row {
+  /// Reads the columns selected by `SqliteTree::columns`.
+  fn read(r: &rusqlite::Row) -> rusqlite::Result<Self> {
+    Ok(Self {
+      rowid: r.get(0)?,
+      path: r.get(1)?,
+      is_dir: r.get::<_, Option<i64>>(2)?.unwrap_or(0) != 0,
+      size: r.get::<_, Option<i64>>(3)?.unwrap_or(0),
+      mtime: r.get::<_, Option<i64>>(4)?.unwrap_or(0),
+    })
+  }
+}
+
+/// Provider projecting the rows of a SQLite table, one row per file or directory.
+///
+/// Enumerations query by the parent column and lookups by the path column, which want indexes, see
+/// [`create_indexes`](Self::create_indexes). Reads go through incremental blob I/O so only the requested range is loaded.
+/// Directories without a row of their own are implied by the rows below them, at any depth.
+/// Lookups try the exact path first, then compare case-insensitively (ASCII only, like SQLite's `NOCASE`)
+/// if the column has a `COLLATE NOCASE` index, as that would scan the table otherwise. The table needs a rowid.
+pub struct SqliteTree {
+  path: PathBuf,
+  schema: SqliteSchema,
+  /// `rowid, path, is_dir, size, mtime` in SQL.
+  columns: String,
+  /// Read-only connections not in use.
+  pool: Mutex<Vec<Connection>>,
+  /// Whether the path and parent columns can be looked up case-insensitively through an index.
+  nocase_path: bool,
+  nocase_parent: bool,
+}
+
+/// Whether an index of `table` compares `column` case-insensitively as its first key.
+fn nocase_index(conn: &Connection, table: &str, column: &str) -> std::io::Result<bool> {
+  let sql = "SELECT count(*) FROM pragma_index_list(?1) AS l, pragma_index_xinfo(l.name) AS x
+    WHERE x.seqno = 0 AND x.name = ?2 AND x.coll = 'NOCASE' COLLATE NOCASE";
+  conn.query_row(sql, [table, column], |r| r.get::<_, i64>(0)).map(|n| n > 0).map_err(sql_error)
+}
+
+impl SqliteTree {
+  /// Opens the database at `path` read-only, it is never written to.
+  pub fn open<P: Into<PathBuf>>(path: P, schema: SqliteSchema) -> std::io::Result<Self> {
+    let path = path.into();
+    let q = quote;
+    let columns = format!(
+      "rowid, {}, {}, {}, {}",
+      q(&schema.path),
+      q(&schema.is_dir),
+      schema.size.as_deref().map(q).unwrap_or_else(|| format!("length({})", q(&schema.data))),
+      schema.mtime.as_deref().map(q).unwrap_or_else(|| "0".into()),
+    );
+    let mut this = Self { path, schema, columns, pool: Mutex::new(Vec::new()), nocase_path: false, nocase_parent: false };
+    // fail early on a missing file or table
+    let (nocase_path, nocase_parent) = this.with_conn(|conn| {
+      conn.prepare(&format!("SELECT {} FROM {} LIMIT 1", this.columns, quote(&this.schema.table))).map_err(sql_error)?;
+      Ok((nocase_index(conn, &this.schema.table, &this.schema.path)?, nocase_index(conn, &this.schema.table, &this.schema.parent)?))
+    })?;
+    this.nocase_path = nocase_path;
+    this.nocase_parent = nocase_parent;
+    Ok(this)
+  }
+
+  /// Adds the indexes the provider queries by to the table at `path` if missing: on the parent column,
+  /// and `COLLATE NOCASE` ones on the path and parent columns for case-insensitive lookups.
+  pub fn create_indexes<P: AsRef<Path>>(path: P, schema: &SqliteSchema) -> std::io::Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE).map_err(sql_error)?;
+    let table = quote(&schema.table);
+    for (column, suffix, collate) in [(&schema.parent, "index", ""), (&schema.parent, "nocase", " COLLATE NOCASE"), (&schema.path, "nocase", " COLLATE NOCASE")] {
+      let index = quote(&format!("{}_{}_{}", schema.table, column, suffix));
+      conn.execute(&format!("CREATE INDEX IF NOT EXISTS {} ON {}({}{})", index, table, quote(column), collate), []).map_err(sql_error)?;
+    }
+    Ok(())
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn with_conn<R, F: FnOnce(&Connection) -> std::io::Result<R>>(&self, f: F) -> std::io::Result<R> {
+    let conn = match self.pool.lock().unwrap().pop() {
+      Some(conn) => conn,
+      None => Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX).map_err(sql_error)?,
+    };
+    let result = f(&conn);
+    self.pool.lock().unwrap().push(conn);
+    result
+  }
+
+  /// `path` as stored in the table.
+  fn key(&self, path: &ProjPath) -> String {
+    let mut key = String::new();
+    for component in path.components() {
+      if !key.is_empty() {
+        key.push(self.schema.separator);
+      }
+      key.push_str(&component.to_string_lossy());
+    }
+    key
+  }
+
+  fn file_name<'a>(&self, key: &'a str) -> &'a str {
+    key.rsplit(self.schema.separator).next().unwrap_or(key)
+  }
+
+  fn info(&self, row: &Row) -> FileBasicInfo {
+    let mut info = dir_info(&ProjPathBuf::from(self.file_name(&row.path)));
+    info.is_dir = row.is_dir;
+    if !row.is_dir {
+      info.file_size = row.size.max(0) as u64;
+    }
+    let time = if row.mtime != 0 { unix_filetime(row.mtime) } else { 0 };
+    info.created = time;
+    info.accessed = time;
+    info.writed = time;
+    info.changed = time;
+    info
+  }
+
+  /// Comparisons to try in turn on a column, the case-insensitive one only if `nocase` has an index.
+  fn collations(nocase: bool) -> &'static [&'static str] {
+    if nocase { &["", " COLLATE NOCASE"] } else { &[""] }
+  }
+
+  /// Row at `key`, exact match first.
+  fn row(&self, conn: &Connection, key: &str) -> std::io::Result<Option<Row>> {
+    for collate in Self::collations(self.nocase_path) {
+      let sql = format!("SELECT {} FROM {} WHERE {} = ?1{} LIMIT 1", self.columns, quote(&self.schema.table), quote(&self.schema.path), collate);
+      let row = conn.prepare_cached(&sql).map_err(sql_error)?
+        .query_row([key], Row::read)
+        .optional().map_err(sql_error)?;
+      if row.is_some() {
+        return Ok(row)
+      }
+    }
+    Ok(None)
+  }
+
+  /// Smallest string above every path below `key`, those starting with `key` and the separator.
+  fn after(&self, key: &str) -> String {
+    let next = char::from_u32(self.schema.separator as u32 + 1).unwrap_or(char::MAX);
+    format!("{}{}", key, next)
+  }
+
+  /// Stored path of the directory at `key`, with a row of its own or implied by the rows below it.
+  fn dir(&self, conn: &Connection, key: &str) -> std::io::Result<String> {
+    if key.is_empty() {
+      return Ok(String::new())
+    }
+    match self.row(conn, key)? {
+      Some(row) if row.is_dir => return Ok(row.path),
+      Some(_) => return Err(std::io::Error::other("not a directory")),
+      None => {},
+    }
+    let below = format!("{}{}", key, self.schema.separator);
+    for collate in Self::collations(self.nocase_parent) {
+      let sql = format!("SELECT {0} FROM {1} WHERE {0} = ?1{2} OR ({0} >= ?2{2} AND {0} < ?3{2}) LIMIT 1", quote(&self.schema.parent), quote(&self.schema.table), collate);
+      let parent = conn.prepare_cached(&sql).map_err(sql_error)?
+        .query_row([key, &below, &self.after(key)], |r| r.get::<_, String>(0))
+        .optional().map_err(sql_error)?;
+      if let Some(parent) = parent {
+        return Ok(parent[..key.len()].to_owned())
+      }
+    }
+    Err(std::io::ErrorKind::NotFound.into())
+  }
+
+  /// Names of the directories in `dir` implied by the rows below them, seeking past each one's subtree.
+  fn implied_dirs(&self, conn: &Connection, dir: &str) -> std::io::Result<Vec<String>> {
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}{}", dir, self.schema.separator) };
+    let end = if dir.is_empty() { None } else { Some(self.after(dir)) };
+    let sql = format!("SELECT {0} FROM {1} WHERE {0} >= ?1 AND (?2 IS NULL OR {0} < ?2) ORDER BY {0} LIMIT 1", quote(&self.schema.parent), quote(&self.schema.table));
+    let mut stmt = conn.prepare_cached(&sql).map_err(sql_error)?;
+    let mut names = Vec::new();
+    // the smallest string after `prefix`, which skips the rows of `dir` itself
+    let mut from = format!("{}\0", prefix);
+    while let Some(parent) = stmt.query_row(rusqlite::params![from, end], |r| r.get::<_, String>(0)).optional().map_err(sql_error)? {
+      let name = parent[prefix.len()..].split(self.schema.separator).next().unwrap().to_owned();
+      let path = format!("{}{}", prefix, name);
+      from = if parent == path { format!("{}\0", parent) } else { self.after(&path) };
+      if !name.is_empty() && !names.contains(&name) {
+        names.push(name);
+      }
+    }
+    Ok(names)
+  }
+}
+
+impl ProjFSDirEnum for SqliteTree {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    let key = self.key(path);
+    let mut entries = self.with_conn(|conn| {
+      let dir = self.dir(conn, &key)?;
+      let sql = format!("SELECT {} FROM {} WHERE {} = ?1", self.columns, quote(&self.schema.table), quote(&self.schema.parent));
+      let mut stmt = conn.prepare_cached(&sql).map_err(sql_error)?;
+      let rows = stmt.query_map([&dir], Row::read).map_err(sql_error)?;
+      let mut entries = rows.map(|row| Ok(self.info(&row.map_err(sql_error)?))).collect::<std::io::Result<Vec<_>>>()?;
+      for name in self.implied_dirs(conn, &dir)? {
+        if !entries.iter().any(|i| i.file_name.to_string_lossy().eq_ignore_ascii_case(&name)) {
+          entries.push(dir_info(&ProjPathBuf::from(name.as_str())));
+        }
+      }
+      Ok(entries)
+    })?;
+    sort_entries(&mut entries);
+    Ok(entries.into_iter())
+  }
+}
+
+impl ProjFSRead for SqliteTree {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    let key = self.key(path);
+    self.with_conn(|conn| match self.row(conn, &key)? {
+      Some(row) => Ok(self.info(&row)),
+      None => Ok(dir_info(&ProjPathBuf::from(self.file_name(&self.dir(conn, &key)?)))),
+    })
+  }
+
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let key = self.key(path);
+    self.with_conn(|conn| {
+      let row = match self.row(conn, &key)? {
+        Some(row) if !row.is_dir => row,
+        _ => return Err(std::io::ErrorKind::NotFound.into()),
+      };
+      let blob = conn.blob_open("main", self.schema.table.as_str(), self.schema.data.as_str(), row.rowid, true).map_err(sql_error)?;
+      check_range(blob.len() as u64, offset, buf.len())?;
+      blob.read_at_exact(buf, offset as usize).map_err(sql_error)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn database(dir: &TempDir, indexed: bool) -> PathBuf {
+    let path = dir.path().join("catalog.db");
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch("CREATE TABLE files (path TEXT PRIMARY KEY, parent TEXT, is_dir INTEGER, mtime INTEGER, data BLOB)").unwrap();
+    let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let rows: &[(&str, &str, i64, &[u8])] = &[
+      ("textures", "", 1, b""),
+      ("textures/Stone.png", "textures", 0, b"png data"),
+      ("textures/big.bin", "textures", 0, &big),
+      ("models/tree/leaf.obj", "models/tree", 0, b"v 0 0 0"),
+      ("models/rock/mossy/rock.obj", "models/rock/mossy", 0, b"v 1 1 1"),
+      ("models/rock-1/rock.obj", "models/rock-1", 0, b"v 2 2 2"),
+      ("readme.txt", "", 0, b"catalog"),
+    ];
+    for (path, parent, is_dir, data) in rows {
+      conn.execute("INSERT INTO files VALUES (?1, ?2, ?3, 1600000000, ?4)", rusqlite::params![path, parent, is_dir, data]).unwrap();
+    }
+    if indexed {
+      SqliteTree::create_indexes(&path, &SqliteSchema::default()).unwrap();
+    }
+    path
+  }
+
+  #[test]
+  fn projects_rows() {
+    let dir = TempDir::new();
+    let fs = SqliteTree::open(database(&dir, true), SqliteSchema::default()).unwrap();
+    assert_eq!(list(&fs, "").unwrap(), vec!["models", "readme.txt", "textures"]);
+    assert_eq!(list(&fs, "TEXTURES").unwrap(), vec!["big.bin", "Stone.png"]);
+    assert_eq!(read_all(&fs, "textures\\stone.png").unwrap(), b"png data");
+    let info = fs.get_metadata(&ProjPathBuf::from("textures\\big.bin"), VersionInfo::NONE).unwrap();
+    assert_eq!(info.file_size, 100_000);
+    assert_eq!(info.writed, unix_filetime(1_600_000_000));
+    let mut buf = [0; 4];
+    fs.read(&ProjPathBuf::from("textures\\big.bin"), VersionInfo::NONE, 99_996, &mut buf).unwrap();
+    assert_eq!(buf, [(99_996u32 as u8), (99_997u32 as u8), (99_998u32 as u8), (99_999u32 as u8)]);
+    assert!(fs.read(&ProjPathBuf::from("textures\\big.bin"), VersionInfo::NONE, 99_997, &mut buf).is_err());
+  }
+
+  #[test]
+  fn exact_lookups_without_nocase_indexes() {
+    let dir = TempDir::new();
+    let fs = SqliteTree::open(database(&dir, false), SqliteSchema::default()).unwrap();
+    // opening leaves the database alone
+    let conn = Connection::open(fs.path()).unwrap();
+    let indexes = |conn: &Connection| conn.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'index' AND name LIKE 'files_%'", [], |r| r.get::<_, i64>(0)).unwrap();
+    assert_eq!(indexes(&conn), 0);
+    assert_eq!(read_all(&fs, "textures\\Stone.png").unwrap(), b"png data");
+    assert_eq!(read_all(&fs, "textures\\stone.png").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(list(&fs, "TEXTURES").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    SqliteTree::create_indexes(fs.path(), &SqliteSchema::default()).unwrap();
+    assert_eq!(indexes(&conn), 3);
+    let fs = SqliteTree::open(fs.path(), SqliteSchema::default()).unwrap();
+    assert_eq!(read_all(&fs, "textures\\stone.png").unwrap(), b"png data");
+    assert_eq!(list(&fs, "TEXTURES").unwrap(), vec!["big.bin", "Stone.png"]);
+  }
+
+  #[test]
+  fn implied_directories() {
+    let dir = TempDir::new();
+    let fs = SqliteTree::open(database(&dir, true), SqliteSchema::default()).unwrap();
+    assert_eq!(list(&fs, "models").unwrap(), vec!["rock", "rock-1", "tree"]);
+    assert_eq!(list(&fs, "models\\rock").unwrap(), vec!["mossy"]);
+    assert_eq!(list(&fs, "models\\tree").unwrap(), vec!["leaf.obj"]);
+    assert_eq!(read_all(&fs, "models\\rock\\mossy\\rock.obj").unwrap(), b"v 1 1 1");
+    assert!(fs.get_metadata(&ProjPathBuf::from("MODELS"), VersionInfo::NONE).unwrap().is_dir);
+    assert_eq!(list(&fs, "model").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    let info = fs.get_metadata(&ProjPathBuf::from("Models\\Tree"), VersionInfo::NONE).unwrap();
+    assert!(info.is_dir);
+    assert_eq!(info.file_name, Path::new("tree"));
+    assert!(fs.get_metadata(&ProjPathBuf::from("missing"), VersionInfo::NONE).is_err());
+  }
+
+  #[test]
+  fn custom_schema() {
+    let dir = TempDir::new();
+    let path = dir.path().join("assets.db");
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch("
+      CREATE TABLE asset (name TEXT, folder TEXT, kind INTEGER, bytes INTEGER, content BLOB);
+      INSERT INTO asset VALUES ('a\\b.txt', 'a', 0, 5, x'68656c6c6f');
+      INSERT INTO asset VALUES ('a', '', 1, 0, NULL);
+    ").unwrap();
+    let schema = SqliteSchema {
+      table: "asset".into(),
+      path: "name".into(),
+      parent: "folder".into(),
+      is_dir: "kind".into(),
+      size: Some("bytes".into()),
+      mtime: None,
+      data: "content".into(),
+      separator: '\\',
+    };
+    let fs = SqliteTree::open(&path, schema).unwrap();
+    assert_eq!(list(&fs, "a").unwrap(), vec!["b.txt"]);
+    assert_eq!(read_all(&fs, "a\\b.txt").unwrap(), b"hello");
+    assert!(SqliteTree::open(&path, SqliteSchema::default()).is_err());
+  }
+}
@@ -0,0 +1,510 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::checkpoint::{Checkpoints, Recorder, Resumable, Resume};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use {std::io::Read, std::sync::Arc, super::checkpoint::Stream};
+use super::index::Index;
+use super::{check_range, dir_info, read_exact_at, unix_filetime};
+
+/// Decompressors kept positioned after a read, so sequential reads do not restart from the beginning.
+const CHECKPOINTS: usize = 4;
+/// Bytes of the decompressed archive between the points reads resume from.
+const CHECKPOINT_INTERVAL: u64 = 8 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+  None,
+  Gzip,
+  Zstd,
+}
+
+impl Compression {
+  fn detect(magic: &[u8]) -> Self {
+    if magic.starts_with(&[0x1f, 0x8b]) {
+      Self::Gzip
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+      Self::Zstd
+    } else {
+      Self::None
+    }
+  }
+}
+
+/// Decompressed stream of the archive at `path`.
+fn open_stream(path: &Path, compression: Compression) -> std::io::Result<Box<dyn Resumable>> {
+  match compression {
+    Compression::None => Ok(Box::new(BufReader::new(File::open(path)?))),
+    #[cfg(feature = "gzip")]
+    Compression::Gzip => Ok(Box::new(GzipMembers::open(path.into(), 0, 0, None)?)),
+    #[cfg(feature = "zstd")]
+    Compression::Zstd => Ok(Box::new(ZstdFrames::open(path.into(), 0, 0)?)),
+    #[allow(unreachable_patterns)]
+    _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{:?} compressed archives need the feature enabled", compression))),
+  }
+}
+
+/// Plain archives are read at file offsets, never resumed.
+impl Resumable for BufReader<File> {
+  fn checkpoint(&self) -> Option<(u64, Box<dyn Resume>)> {
+    None
+  }
+}
+
+/// Compressed input of a decoder, counting the bytes taken from it.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+struct Input {
+  reader: BufReader<File>,
+  /// File offset of the next byte.
+  offset: u64,
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+impl Input {
+  fn open(path: &Path, offset: u64) -> std::io::Result<Self> {
+    let mut file = File::open(path)?;
+    std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(offset))?;
+    Ok(Self { reader: BufReader::new(file), offset })
+  }
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+impl Read for Input {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let n = self.reader.read(buf)?;
+    self.offset += n as u64;
+    Ok(n)
+  }
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+impl BufRead for Input {
+  fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+    self.reader.fill_buf()
+  }
+  fn consume(&mut self, n: usize) {
+    self.reader.consume(n);
+    self.offset += n as u64;
+  }
+}
+
+/// gzip decoder continuing over concatenated members, resumable anywhere from a copy of its inflater.
+#[cfg(feature = "gzip")]
+struct GzipMembers {
+  path: Arc<Path>,
+  input: Input,
+  /// Position in the decompressed stream.
+  position: u64,
+  /// Inflater of the current member, `None` before the header of the next one.
+  state: Option<Box<miniz_oxide::inflate::stream::InflateState>>,
+  /// Checksum and size of the member so far, unless the stream was resumed within it.
+  check: Option<(crc32fast::Hasher, u32)>,
+}
+
+#[cfg(feature = "gzip")]
+impl GzipMembers {
+  fn open(path: Arc<Path>, offset: u64, position: u64, state: Option<Box<miniz_oxide::inflate::stream::InflateState>>) -> std::io::Result<Self> {
+    let input = Input::open(&path, offset)?;
+    Ok(Self { path, input, position, state, check: None })
+  }
+
+  fn skip(&mut self, len: u64) -> std::io::Result<()> {
+    if std::io::copy(&mut (&mut self.input).take(len), &mut std::io::sink())? < len {
+      return Err(std::io::ErrorKind::UnexpectedEof.into())
+    }
+    Ok(())
+  }
+
+  /// Reads past the header of the next member.
+  fn header(&mut self) -> std::io::Result<()> {
+    let mut header = [0; 10];
+    self.input.read_exact(&mut header)?;
+    if header[..3] != [0x1f, 0x8b, 8] {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "gzip: bad member header"))
+    }
+    let flags = header[3];
+    if flags & 4 != 0 {
+      let mut len = [0; 2];
+      self.input.read_exact(&mut len)?;
+      self.skip(u16::from_le_bytes(len) as u64)?;
+    }
+    // file name and comment, zero terminated
+    for flag in [8, 16] {
+      if flags & flag != 0 {
+        self.input.read_until(0, &mut Vec::new())?;
+      }
+    }
+    if flags & 2 != 0 {
+      self.skip(2)?;
+    }
+    Ok(())
+  }
+
+  fn trailer(&mut self) -> std::io::Result<()> {
+    let mut trailer = [0; 8];
+    self.input.read_exact(&mut trailer)?;
+    if let Some((crc, size)) = self.check.take() {
+      if trailer[..4] != crc.finalize().to_le_bytes() || trailer[4..] != size.to_le_bytes() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "gzip: checksum mismatch"))
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(feature = "gzip")]
+impl Read for GzipMembers {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    use miniz_oxide::inflate::stream::{inflate, InflateState};
+    use miniz_oxide::{DataFormat, MZFlush, MZStatus};
+    if buf.is_empty() {
+      return Ok(0)
+    }
+    loop {
+      if self.state.is_none() {
+        if self.input.fill_buf()?.is_empty() {
+          return Ok(0)
+        }
+        self.header()?;
+        self.state = Some(InflateState::new_boxed(DataFormat::Raw));
+        self.check = Some((crc32fast::Hasher::new(), 0));
+      }
+      let state = self.state.as_mut().unwrap();
+      let input = self.input.fill_buf()?;
+      if input.is_empty() {
+        return Err(std::io::ErrorKind::UnexpectedEof.into())
+      }
+      let result = inflate(state, input, buf, MZFlush::None);
+      self.input.consume(result.bytes_consumed);
+      let out = &buf[..result.bytes_written];
+      self.position += out.len() as u64;
+      if let Some((crc, size)) = &mut self.check {
+        crc.update(out);
+        *size = size.wrapping_add(out.len() as u32);
+      }
+      match result.status {
+        Ok(MZStatus::StreamEnd) => {
+          self.state = None;
+          self.trailer()?;
+        },
+        Ok(_) => {},
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "gzip: corrupt deflate data")),
+      }
+      if !out.is_empty() {
+        return Ok(out.len())
+      }
+    }
+  }
+}
+
+#[cfg(feature = "gzip")]
+impl Resumable for GzipMembers {
+  fn checkpoint(&self) -> Option<(u64, Box<dyn Resume>)> {
+    let point = GzipPoint { path: self.path.clone(), offset: self.input.offset, position: self.position, state: self.state.clone() };
+    Some((self.position, Box::new(point)))
+  }
+}
+
+#[cfg(feature = "gzip")]
+struct GzipPoint {
+  path: Arc<Path>,
+  offset: u64,
+  position: u64,
+  state: Option<Box<miniz_oxide::inflate::stream::InflateState>>,
+}
+
+#[cfg(feature = "gzip")]
+impl Resume for GzipPoint {
+  fn resume(&self) -> std::io::Result<Stream> {
+    Ok(Box::new(GzipMembers::open(self.path.clone(), self.offset, self.position, self.state.clone())?))
+  }
+}
+
+/// zstd decoder continuing over concatenated frames, resumable at the start of each.
+#[cfg(feature = "zstd")]
+struct ZstdFrames {
+  path: Arc<Path>,
+  decoder: Option<ruzstd::decoding::StreamingDecoder<Input, ruzstd::decoding::FrameDecoder>>,
+  /// Position in the decompressed stream.
+  position: u64,
+  /// Start of the current frame, in the decompressed stream and in the file.
+  frame: (u64, u64),
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdFrames {
+  fn open(path: Arc<Path>, offset: u64, position: u64) -> std::io::Result<Self> {
+    let decoder = ruzstd::decoding::StreamingDecoder::new(Input::open(&path, offset)?).map_err(std::io::Error::other)?;
+    Ok(Self { path, decoder: Some(decoder), position, frame: (position, offset) })
+  }
+}
+
+#[cfg(feature = "zstd")]
+impl Read for ZstdFrames {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+      let inner = self.decoder.as_mut().ok_or_else(|| std::io::Error::other("zstd stream failed"))?;
+      let n = inner.read(buf)?;
+      self.position += n as u64;
+      if n > 0 || buf.is_empty() || inner.get_mut().fill_buf()?.is_empty() {
+        return Ok(n)
+      }
+      let (source, decoder) = self.decoder.take().unwrap().into_parts();
+      self.frame = (self.position, source.offset);
+      self.decoder = Some(ruzstd::decoding::StreamingDecoder::new_with_decoder(source, decoder).map_err(std::io::Error::other)?);
+    }
+  }
+}
+
+/// The decoder state within a frame cannot be copied, so only frame starts are recorded.
+#[cfg(feature = "zstd")]
+impl Resumable for ZstdFrames {
+  fn checkpoint(&self) -> Option<(u64, Box<dyn Resume>)> {
+    let (position, offset) = self.frame;
+    Some((position, Box::new(ZstdFrame { path: self.path.clone(), offset, position })))
+  }
+}
+
+#[cfg(feature = "zstd")]
+struct ZstdFrame {
+  path: Arc<Path>,
+  offset: u64,
+  position: u64,
+}
+
+#[cfg(feature = "zstd")]
+impl Resume for ZstdFrame {
+  fn resume(&self) -> std::io::Result<Stream> {
+    Ok(Box::new(ZstdFrames::open(self.path.clone(), self.offset, self.position)?))
+  }
+}
+
+/// Location of a file's data in the decompressed archive.
+struct Span {
+  offset: u64,
+}
+
+/// Provider projecting the content of a tar archive, plain or gzip / zstd compressed.
+///
+/// The archive is indexed once when opened. Reads of a plain archive go straight to the file offset,
+/// compressed ones resume from the nearest decompressor left behind by earlier reads, or from the points
+/// recorded while indexing every 8 MiB of decompressed data. Reading a file front to back decompresses the
+/// archive only once, and a random read of a gzip archive decompresses at most 8 MiB before the data.
+/// zstd archives resume at frame starts only, so random reads are only that cheap with many small frames.
+/// Directories, regular files and hard links are projected, other entry types are left out.
+pub struct TarArchive {
+  path: PathBuf,
+  compression: Compression,
+  index: Index<Span>,
+  checkpoints: Checkpoints<()>,
+  /// Points of the decompressed archive reads resume from, by position.
+  points: Vec<(u64, Box<dyn Resume>)>,
+}
+
+impl TarArchive {
+  pub fn open<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+    Self::with_interval(path.into(), CHECKPOINT_INTERVAL)
+  }
+
+  fn with_interval(path: PathBuf, interval: u64) -> std::io::Result<Self> {
+    let compression = Compression::detect(BufReader::new(File::open(&path)?).fill_buf()?);
+    let mut index = Index::new();
+    let mut archive = tar::Archive::new(Recorder::new(open_stream(&path, compression)?, interval));
+    for entry in archive.entries()? {
+      let entry = entry?;
+      let name = match projected_path(&entry.path()?) {
+        Some(name) => name,
+        None => continue,
+      };
+      let header = entry.header();
+      let mut info = dir_info(&name);
+      info.writed = header.mtime().map(|t| unix_filetime(t as i64)).unwrap_or(0);
+      info.created = info.writed;
+      info.accessed = info.writed;
+      info.changed = info.writed;
+      if header.mode().is_ok_and(|m| m & 0o222 == 0) {
+        info.attrs |= crate::FILE_ATTRIBUTE_READONLY;
+      }
+      match header.entry_type() {
+        tar::EntryType::Directory => index.insert_dir(&name, info),
+        tar::EntryType::Regular | tar::EntryType::Continuous => {
+          info.file_size = entry.size();
+          index.insert_file(&name, info, Span { offset: entry.raw_file_position() });
+        },
+        tar::EntryType::Link => {
+          let target = match entry.link_name()?.as_deref().and_then(projected_path) {
+            Some(target) => target,
+            None => continue,
+          };
+          // hard links refer to an entry earlier in the archive
+          if let Ok((target, span)) = index.file(&target) {
+            info.file_size = target.file_size;
+            let span = Span { offset: span.offset };
+            index.insert_file(&name, info, span);
+          }
+        },
+        _ => {},
+      }
+    }
+    let points = archive.into_inner().into_points();
+    Ok(Self { path, compression, index, checkpoints: Checkpoints::new(CHECKPOINTS), points })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+/// Path of an archive entry inside the projection, `None` for entries that would escape it.
+fn projected_path(path: &Path) -> Option<ProjPathBuf> {
+  if path.components().any(|i| matches!(i, Component::ParentDir | Component::Prefix(_))) {
+    return None
+  }
+  let path = ProjPathBuf::from(path);
+  if path.is_empty() { None } else { Some(path) }
+}
+
+impl ProjFSDirEnum for TarArchive {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.index.dir_iter(path)
+  }
+}
+
+impl ProjFSRead for TarArchive {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.index.metadata(path)
+  }
+
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let (info, span) = self.index.file(path)?;
+    check_range(info.file_size, offset, buf.len())?;
+    if self.compression == Compression::None {
+      read_exact_at(&File::open(&self.path)?, span.offset + offset, buf)
+    } else {
+      let position = span.offset + offset;
+      let (start, point) = match self.points.partition_point(|(at, _)| *at <= position).checked_sub(1) {
+        Some(i) => (self.points[i].0, Some(&self.points[i].1)),
+        None => (0, None),
+      };
+      self.checkpoints.read((), position, buf, start, || match point {
+        Some(point) => point.resume(),
+        None => Ok(open_stream(&self.path, self.compression)?),
+      })
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::provider::testing::*;
+
+  fn tarball() -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut add = |path: &str, data: &[u8], kind: tar::EntryType| {
+      let mut header = tar::Header::new_gnu();
+      header.set_entry_type(kind);
+      header.set_size(data.len() as u64);
+      header.set_mode(0o644);
+      header.set_mtime(1_600_000_000);
+      builder.append_data(&mut header, path, data).unwrap();
+    };
+    add("./bin/", b"", tar::EntryType::Directory);
+    add("./bin/tool", b"#!/bin/sh\necho tool\n", tar::EntryType::Regular);
+    add("./share/doc/README", &b"0123456789".repeat(1000), tar::EntryType::Regular);
+    add("./share/doc/a-very-long-name-that-needs-a-gnu-long-name-header-because-it-exceeds-one-hundred-bytes.txt", b"long", tar::EntryType::Regular);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    builder.append_link(&mut header, "./bin/alias", "./bin/tool").unwrap();
+    builder.into_inner().unwrap()
+  }
+
+  fn open(dir: &TempDir, name: &str, data: &[u8]) -> TarArchive {
+    dir.write(name, data);
+    TarArchive::open(dir.path().join(name)).unwrap()
+  }
+
+  fn check(fs: &TarArchive) {
+    assert_eq!(list(fs, "").unwrap(), vec!["bin", "share"]);
+    assert_eq!(list(fs, "bin").unwrap(), vec!["alias", "tool"]);
+    assert_eq!(list(fs, "share\\doc").unwrap().len(), 2);
+    assert_eq!(read_all(fs, "bin\\tool").unwrap(), b"#!/bin/sh\necho tool\n");
+    assert_eq!(read_all(fs, "BIN\\ALIAS").unwrap(), b"#!/bin/sh\necho tool\n");
+    let info = fs.get_metadata(&ProjPathBuf::from("share\\doc\\readme"), VersionInfo::NONE).unwrap();
+    assert_eq!(info.file_size, 10_000);
+    assert_eq!(info.writed, unix_filetime(1_600_000_000));
+    // random offsets, backwards and forwards
+    let path = ProjPathBuf::from("share\\doc\\README");
+    for &offset in &[5000, 10, 9990, 0, 4321] {
+      let mut buf = [0; 10];
+      fs.read(&path, VersionInfo::NONE, offset, &mut buf).unwrap();
+      let expected: Vec<u8> = (offset..offset + 10).map(|i| b'0' + (i % 10) as u8).collect();
+      assert_eq!(&buf[..], &expected[..], "offset {}", offset);
+    }
+    let e = fs.read(&path, VersionInfo::NONE, 9995, &mut [0; 10]).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn plain() {
+    let dir = TempDir::new();
+    let fs = open(&dir, "a.tar", &tarball());
+    assert_eq!(fs.compression, Compression::None);
+    check(&fs);
+  }
+
+  #[cfg(feature = "gzip")]
+  #[test]
+  fn gzip() {
+    use std::io::Write;
+    let gzip = |data: &[u8]| {
+      let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+      encoder.write_all(data).unwrap();
+      encoder.finish().unwrap()
+    };
+    let dir = TempDir::new();
+    let data = tarball();
+    let (head, tail) = data.split_at(5000);
+    let compressed = [gzip(head), gzip(tail)].concat();
+    dir.write("a.tar.gz", &compressed);
+    let fs = TarArchive::with_interval(dir.path().join("a.tar.gz"), 1024).unwrap();
+    assert_eq!(fs.compression, Compression::Gzip);
+    // resumable every interval, across the member boundary too
+    let points: Vec<u64> = fs.points.iter().map(|(at, _)| *at).collect();
+    assert_eq!(points, (0..points.len() as u64).map(|i| i * 1024).collect::<Vec<_>>());
+    assert!(points.len() > 10);
+    check(&fs);
+    assert!(fs.checkpoints.len() <= CHECKPOINTS);
+    // checksums are verified while indexing
+    let mut corrupt = compressed.clone();
+    corrupt[gzip(head).len() - 8] ^= 1;
+    dir.write("b.tar.gz", &corrupt);
+    assert_eq!(TarArchive::open(dir.path().join("b.tar.gz")).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+  }
+
+  #[cfg(feature = "zstd")]
+  #[test]
+  fn zstd_multiple_frames() {
+    let dir = TempDir::new();
+    let data = tarball();
+    let (head, tail) = data.split_at(3000);
+    let mut compressed = ruzstd::encoding::compress_to_vec(head, ruzstd::encoding::CompressionLevel::Fastest);
+    compressed.extend(ruzstd::encoding::compress_to_vec(tail, ruzstd::encoding::CompressionLevel::Fastest));
+    dir.write("a.tar.zst", &compressed);
+    let fs = TarArchive::with_interval(dir.path().join("a.tar.zst"), 1024).unwrap();
+    assert_eq!(fs.compression, Compression::Zstd);
+    // frames are the only points to resume from
+    assert_eq!(fs.points.iter().map(|(at, _)| *at).collect::<Vec<_>>(), vec![0, 3000]);
+    check(&fs);
+  }
+
+  #[test]
+  fn skips_escaping_entries() {
+    assert!(projected_path(Path::new("../etc/passwd")).is_none());
+    assert!(projected_path(Path::new("./")).is_none());
+    assert_eq!(projected_path(Path::new("./a/b")).unwrap(), ProjPathBuf::from("a\\b"));
+  }
+}
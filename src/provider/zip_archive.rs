@@ -0,0 +1,466 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use crate::{FileBasicInfo, Guid, ProjFSDirEnum, ProjFSRead, ProjPath, ProjPathBuf, VersionInfo};
+use super::checkpoint::Checkpoints;
+use super::index::Index;
+use super::{check_range, dir_info, read_exact_at, unix_filetime};
+
+const EOCD: u32 = 0x0605_4b50;
+const ZIP64_EOCD: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+  u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+  u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+  u64::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3], b[at + 4], b[at + 5], b[at + 6], b[at + 7]])
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, format!("zip: {}", msg))
+}
+
+/// File entry of the central directory.
+struct Entry {
+  method: u16,
+  crc: u32,
+  compressed_size: u64,
+  header_offset: u64,
+  /// Where the data starts, known once the local header has been read.
+  data_offset: OnceLock<u64>,
+}
+
+/// Provider projecting the content of a zip archive.
+///
+/// The central directory is read once when opened. Stored entries are read at their offset in the archive,
+/// deflated ones are decompressed whole into a cache bounded by [`with_cache_size`](Self::with_cache_size),
+/// entries too large for the cache resume from the decompressor left behind by the previous read.
+/// Encrypted entries and other compression methods fail to read with `Unsupported`.
+pub struct ZipArchive {
+  path: PathBuf,
+  index: Index<Entry>,
+  cache_size: usize,
+  /// Decompressed entries keyed by header offset, most recently used last.
+  cache: Mutex<Vec<(u64, Arc<Vec<u8>>)>>,
+  checkpoints: Checkpoints<u64>,
+}
+
+impl ZipArchive {
+  pub fn open<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+    let path = path.into();
+    let mut file = File::open(&path)?;
+    let (count, offset, size) = central_directory(&mut file)?;
+    let mut dir = vec![0; size as usize];
+    read_exact_at(&file, offset, &mut dir)?;
+    let mut index = Index::new();
+    let mut at = 0;
+    for _ in 0..count {
+      if dir.len() < at + 46 || u32_at(&dir, at) != CENTRAL_HEADER {
+        return Err(invalid("bad central directory"))
+      }
+      let h = &dir[at..];
+      let (name_len, extra_len, comment_len) = (u16_at(h, 28) as usize, u16_at(h, 30) as usize, u16_at(h, 32) as usize);
+      if h.len() < 46 + name_len + extra_len {
+        return Err(invalid("bad central directory"))
+      }
+      let raw_name = &h[46..46 + name_len];
+      let extra = &h[46 + name_len..46 + name_len + extra_len];
+      at += 46 + name_len + extra_len + comment_len;
+
+      let mut entry = Entry {
+        method: u16_at(h, 10),
+        crc: u32_at(h, 16),
+        compressed_size: u32_at(h, 20) as u64,
+        header_offset: u32_at(h, 42) as u64,
+        data_offset: OnceLock::new(),
+      };
+      let mut size = u32_at(h, 24) as u64;
+      let name = if u16_at(h, 8) & 0x800 != 0 {
+        String::from_utf8_lossy(raw_name).into_owned()
+      } else {
+        String::from_utf8(raw_name.to_vec()).unwrap_or_else(|_| raw_name.iter().map(|&b| b as char).collect())
+      };
+      let path = match projected_path(&name) {
+        Some(path) => path,
+        None => continue,
+      };
+      let mut info = dir_info(&path);
+      let time = timestamps(extra).unwrap_or_else(|| dos_filetime(u16_at(h, 14), u16_at(h, 12)));
+      info.writed = time.0;
+      info.accessed = time.1;
+      info.created = time.2;
+      info.changed = time.0;
+      let external = u32_at(h, 38);
+      info.attrs = match h[5] {
+        // DOS / NTFS attributes, without the directory bit
+        0 | 10 | 11 | 14 => external & (crate::FILE_ATTRIBUTE_READONLY | crate::FILE_ATTRIBUTE_HIDDEN),
+        3 if external >> 16 & 0o222 == 0 && external >> 16 != 0 => crate::FILE_ATTRIBUTE_READONLY,
+        _ => 0,
+      };
+      if name.ends_with('/') || name.ends_with('\\') {
+        index.insert_dir(&path, info);
+        continue
+      }
+      zip64(extra, &mut size, &mut entry.compressed_size, &mut entry.header_offset);
+      if u16_at(h, 8) & 0x1 != 0 {
+        // encrypted
+        entry.method = u16::MAX;
+      }
+      info.file_size = size;
+      index.insert_file(&path, info, entry);
+    }
+    Ok(Self { path, index, cache_size: 64 << 20, cache: Mutex::new(Vec::new()), checkpoints: Checkpoints::new(4) })
+  }
+
+  /// Bytes of decompressed entries to keep, 64 MiB by default.
+  pub fn with_cache_size(mut self, cache_size: usize) -> Self {
+    self.cache_size = cache_size;
+    self
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn data_offset(&self, file: &File, entry: &Entry) -> std::io::Result<u64> {
+    if let Some(&offset) = entry.data_offset.get() {
+      return Ok(offset)
+    }
+    let mut header = [0; 30];
+    read_exact_at(file, entry.header_offset, &mut header)?;
+    if u32_at(&header, 0) != LOCAL_HEADER {
+      return Err(invalid("bad local header"))
+    }
+    let offset = entry.header_offset + 30 + u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64;
+    Ok(*entry.data_offset.get_or_init(|| offset))
+  }
+
+  fn cached(&self, key: u64) -> Option<Arc<Vec<u8>>> {
+    let mut cache = self.cache.lock().unwrap();
+    let i = cache.iter().position(|(k, _)| *k == key)?;
+    let hit = cache.remove(i);
+    let data = hit.1.clone();
+    cache.push(hit);
+    Some(data)
+  }
+
+  fn insert_cache(&self, key: u64, data: Arc<Vec<u8>>) {
+    let mut cache = self.cache.lock().unwrap();
+    cache.retain(|(k, _)| *k != key);
+    let mut total: usize = cache.iter().map(|(_, d)| d.len()).sum::<usize>() + data.len();
+    while total > self.cache_size && !cache.is_empty() {
+      total -= cache.remove(0).1.len();
+    }
+    cache.push((key, data));
+  }
+
+  fn inflate(&self, entry: &Entry) -> std::io::Result<flate2::read::DeflateDecoder<std::io::Take<File>>> {
+    let file = File::open(&self.path)?;
+    let mut data = file.try_clone()?;
+    std::io::Seek::seek(&mut data, std::io::SeekFrom::Start(self.data_offset(&file, entry)?))?;
+    Ok(flate2::read::DeflateDecoder::new(data.take(entry.compressed_size)))
+  }
+}
+
+/// Entry count, offset and size of the central directory.
+fn central_directory(file: &mut File) -> std::io::Result<(u64, u64, u64)> {
+  let len = file.metadata()?.len();
+  // the end record is 22 bytes, followed by a comment of up to 64 KiB
+  let tail_len = len.min(22 + 0xFFFF);
+  let mut tail = vec![0; tail_len as usize];
+  read_exact_at(file, len - tail_len, &mut tail)?;
+  let at = (0..tail.len().saturating_sub(21)).rev().find(|&i| u32_at(&tail, i) == EOCD).ok_or_else(|| invalid("no end of central directory"))?;
+  let eocd = &tail[at..];
+  let (count, size, offset) = (u16_at(eocd, 10) as u64, u32_at(eocd, 12) as u64, u32_at(eocd, 16) as u64);
+  let (count, offset, size) = if count != 0xFFFF && size != 0xFFFF_FFFF && offset != 0xFFFF_FFFF {
+    (count, offset, size)
+  } else {
+    let locator_at = (len - tail_len + at as u64).checked_sub(20).ok_or_else(|| invalid("no zip64 locator"))?;
+    let mut locator = [0; 20];
+    read_exact_at(file, locator_at, &mut locator)?;
+    if u32_at(&locator, 0) != ZIP64_LOCATOR {
+      return Err(invalid("no zip64 locator"))
+    }
+    let mut record = [0; 56];
+    read_exact_at(file, u64_at(&locator, 8), &mut record)?;
+    if u32_at(&record, 0) != ZIP64_EOCD {
+      return Err(invalid("bad zip64 end of central directory"))
+    }
+    (u64_at(&record, 32), u64_at(&record, 48), u64_at(&record, 40))
+  };
+  // the size is read into memory whole, a corrupt one must not allocate past the file
+  if offset.checked_add(size).is_none_or(|end| end > len) {
+    return Err(invalid("central directory out of range"))
+  }
+  Ok((count, offset, size))
+}
+
+/// Replaces saturated sizes and offset by those of the zip64 extra field.
+fn zip64(extra: &[u8], size: &mut u64, compressed_size: &mut u64, offset: &mut u64) {
+  for (id, data) in extra_fields(extra) {
+    if id != 0x0001 {
+      continue
+    }
+    let mut fields = data.chunks_exact(8).map(|i| u64_at(i, 0));
+    for value in [&mut *size, &mut *compressed_size, &mut *offset] {
+      if *value == 0xFFFF_FFFF {
+        if let Some(v) = fields.next() {
+          *value = v;
+        }
+      }
+    }
+  }
+}
+
+fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item=(u16, &[u8])> {
+  std::iter::from_fn(move || {
+    if extra.len() < 4 {
+      return None
+    }
+    let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+    let data = extra.get(4..4 + len)?;
+    extra = &extra[4 + len..];
+    Some((id, data))
+  })
+}
+
+/// Modification, access and creation time from the NTFS or extended timestamp extra fields.
+fn timestamps(extra: &[u8]) -> Option<(i64, i64, i64)> {
+  let mut unix = None;
+  for (id, data) in extra_fields(extra) {
+    match id {
+      0x000a if data.len() >= 32 && u16_at(data, 4) == 1 && u16_at(data, 6) >= 24 => {
+        return Some((u64_at(data, 8) as i64, u64_at(data, 16) as i64, u64_at(data, 24) as i64))
+      },
+      0x5455 if data.len() >= 5 && data[0] & 1 != 0 => {
+        let t = unix_filetime(u32_at(data, 1) as i32 as i64);
+        unix = Some((t, t, t));
+      },
+      _ => {},
+    }
+  }
+  unix
+}
+
+/// MS-DOS date and time, taken as UTC.
+fn dos_filetime(date: u16, time: u16) -> (i64, i64, i64) {
+  let (y, m, d) = (1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as i64, (date & 0x1F) as i64);
+  // days from civil, with the year starting in March
+  let (y, m) = if m <= 2 { (y - 1, m + 9) } else { (y, m - 3) };
+  let era = y.div_euclid(400);
+  let yoe = y - era * 400;
+  let doy = (153 * m + 2) / 5 + d - 1;
+  let days = era * 146_097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719_468;
+  let secs = days * 86400 + (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+  let t = unix_filetime(secs);
+  (t, t, t)
+}
+
+/// Path of an entry name inside the projection, `None` for names that would escape it.
+fn projected_path(name: &str) -> Option<ProjPathBuf> {
+  let mut path = ProjPathBuf::new();
+  for component in name.split(['/', '\\']) {
+    match component {
+      "" | "." => {},
+      ".." => return None,
+      c if c.ends_with(':') => return None,
+      c => path.push(ProjPathBuf::from(c)),
+    }
+  }
+  if path.is_empty() { None } else { Some(path) }
+}
+
+impl ProjFSDirEnum for ZipArchive {
+  type DirIter = std::vec::IntoIter<FileBasicInfo>;
+  fn dir_iter(&self, _id: Guid, path: &ProjPath, _pattern: Option<&ProjPath>, _version: VersionInfo) -> std::io::Result<Self::DirIter> {
+    self.index.dir_iter(path)
+  }
+}
+
+impl ProjFSRead for ZipArchive {
+  fn get_metadata(&self, path: &ProjPath, _version: VersionInfo) -> std::io::Result<FileBasicInfo> {
+    self.index.metadata(path)
+  }
+
+  fn read(&self, path: &ProjPath, _version: VersionInfo, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    let (info, entry) = self.index.file(path)?;
+    check_range(info.file_size, offset, buf.len())?;
+    match entry.method {
+      STORED => {
+        let file = File::open(&self.path)?;
+        read_exact_at(&file, self.data_offset(&file, entry)? + offset, buf)
+      },
+      DEFLATED if info.file_size as usize <= self.cache_size => {
+        let data = match self.cached(entry.header_offset) {
+          Some(data) => data,
+          None => {
+            let mut data = Vec::with_capacity(info.file_size as usize);
+            self.inflate(entry)?.take(info.file_size).read_to_end(&mut data)?;
+            let mut crc = flate2::Crc::new();
+            crc.update(&data);
+            if data.len() as u64 != info.file_size || crc.sum() != entry.crc {
+              return Err(invalid("corrupt entry"))
+            }
+            let data = Arc::new(data);
+            self.insert_cache(entry.header_offset, data.clone());
+            data
+          },
+        };
+        buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+        Ok(())
+      },
+      DEFLATED => self.checkpoints.read(entry.header_offset, offset, buf, 0, || Ok(Box::new(self.inflate(entry)?))),
+      _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "zip: encrypted entry or unsupported compression method")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Write;
+  use crate::provider::testing::*;
+
+  /// Minimal zip writer, entries are (name, data, deflate).
+  fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for &(name, data, deflate) in entries {
+      let mut crc = flate2::Crc::new();
+      crc.update(data);
+      let stored = if deflate {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+      } else {
+        data.to_vec()
+      };
+      let method = if deflate { DEFLATED } else { STORED };
+      // 2021-03-04 05:06:08
+      let (time, date) = (5u16 << 11 | 6 << 5 | 4, 41u16 << 9 | 3 << 5 | 4);
+      let offset = out.len() as u32;
+      let mut fields = Vec::new();
+      for v in &[20u16, 0x800, method, time, date] {
+        fields.extend_from_slice(&v.to_le_bytes());
+      }
+      for v in &[crc.sum(), stored.len() as u32, data.len() as u32] {
+        fields.extend_from_slice(&v.to_le_bytes());
+      }
+      out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+      out.extend_from_slice(&fields);
+      out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+      out.extend_from_slice(&0u16.to_le_bytes());
+      out.extend_from_slice(name.as_bytes());
+      out.extend_from_slice(&stored);
+      central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+      central.extend_from_slice(&20u16.to_le_bytes());
+      central.extend_from_slice(&fields);
+      for v in &[name.len() as u16, 0, 0, 0, 0] {
+        central.extend_from_slice(&v.to_le_bytes());
+      }
+      central.extend_from_slice(&0u32.to_le_bytes());
+      central.extend_from_slice(&offset.to_le_bytes());
+      central.extend_from_slice(name.as_bytes());
+    }
+    let offset = out.len() as u32;
+    out.extend_from_slice(&central);
+    out.extend_from_slice(&EOCD.to_le_bytes());
+    for v in &[0u16, 0, entries.len() as u16, entries.len() as u16] {
+      out.extend_from_slice(&v.to_le_bytes());
+    }
+    out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+  }
+
+  fn archive(dir: &TempDir) -> ZipArchive {
+    let digits = b"0123456789".repeat(1000);
+    dir.write("a.zip", &zip(&[
+      ("docs/", b"", false),
+      ("docs/readme.txt", b"stored content", false),
+      ("src/main.rs", b"fn main() {}", true),
+      ("data/digits", &digits, true),
+      ("../evil", b"x", false),
+    ]));
+    ZipArchive::open(dir.path().join("a.zip")).unwrap()
+  }
+
+  fn check_digits(fs: &ZipArchive) {
+    let path = ProjPathBuf::from("data\\digits");
+    for &offset in &[10, 20, 5000, 7, 9990] {
+      let mut buf = [0; 10];
+      fs.read(&path, VersionInfo::NONE, offset, &mut buf).unwrap();
+      let expected: Vec<u8> = (offset..offset + 10).map(|i| b'0' + (i % 10) as u8).collect();
+      assert_eq!(&buf[..], &expected[..], "offset {}", offset);
+    }
+  }
+
+  #[test]
+  fn central_directory_as_tree() {
+    let dir = TempDir::new();
+    let fs = archive(&dir);
+    assert_eq!(list(&fs, "").unwrap(), vec!["data", "docs", "src"]);
+    assert_eq!(list(&fs, "docs").unwrap(), vec!["readme.txt"]);
+    let info = fs.get_metadata(&ProjPathBuf::from("src\\main.rs"), VersionInfo::NONE).unwrap();
+    assert_eq!(info.file_size, 12);
+    assert_eq!(info.writed, unix_filetime(1_614_834_368));
+  }
+
+  #[test]
+  fn stored_and_deflated_reads() {
+    let dir = TempDir::new();
+    let fs = archive(&dir);
+    assert_eq!(read_all(&fs, "docs\\readme.txt").unwrap(), b"stored content");
+    assert_eq!(read_all(&fs, "SRC\\MAIN.RS").unwrap(), b"fn main() {}");
+    check_digits(&fs);
+    assert_eq!(fs.cache.lock().unwrap().len(), 2);
+    let e = fs.read(&ProjPathBuf::from("src\\main.rs"), VersionInfo::NONE, 10, &mut [0; 3]).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn entries_larger_than_cache() {
+    let dir = TempDir::new();
+    let fs = archive(&dir).with_cache_size(100);
+    check_digits(&fs);
+    assert!(fs.cache.lock().unwrap().is_empty());
+    // one decompressor for the forward reads, another one since going back to offset 7
+    assert_eq!(fs.checkpoints.len(), 2);
+    assert_eq!(read_all(&fs, "src\\main.rs").unwrap(), b"fn main() {}");
+  }
+
+  #[test]
+  fn corrupt_end_record() {
+    let dir = TempDir::new();
+    let data = zip(&[("a", b"a", false)]);
+    let end = data.len() - 22;
+    for (at, value) in [(12, 0xFFFF_FFF0u32), (16, data.len() as u32)] {
+      let mut data = data.clone();
+      data[end + at..end + at + 4].copy_from_slice(&value.to_le_bytes());
+      dir.write("corrupt.zip", &data);
+      let e = ZipArchive::open(dir.path().join("corrupt.zip")).err().unwrap();
+      assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+  }
+
+  #[test]
+  fn entry_names() {
+    assert!(projected_path("../evil").is_none());
+    assert!(projected_path("C:/evil").is_none());
+    assert_eq!(projected_path("./a//b\\c").unwrap(), ProjPathBuf::from("a\\b\\c"));
+    assert_eq!(dos_filetime(0x21, 0).0, unix_filetime(315_532_800));
+  }
+}
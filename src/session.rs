@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use crate::Guid;
+
+/// Limits applied to the directory enumeration sessions kept by an instance.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+  /// Sessions untouched for longer than this are dropped,
+  /// which covers enumerations whose `EndDirectoryEnumeration` never arrives.
+  pub idle_timeout: Option<Duration>,
+  /// Maximum number of live sessions, further enumerations fail with `ResourceBusy` until one ends or expires.
+  pub max_sessions: Option<usize>,
+}
+
+impl Default for SessionConfig {
+  fn default() -> Self {
+    Self {
+      idle_timeout: Some(Duration::from_secs(300)),
+      max_sessions: Some(4096),
+    }
+  }
+}
+
+/// Snapshot of a live enumeration session.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+  pub id: Guid,
+  pub path: PathBuf,
+  pub started: Instant,
+  pub last_used: Instant,
+  /// Number of `GetDirectoryEnumeration` callbacks served so far.
+  pub calls: usize,
+}
+
+struct Entry<S> {
+  info: SessionInfo,
+  state: S,
+}
+
+/// Directory enumeration sessions keyed by the enumeration GUID ProjFS hands out.
+///
+/// Each session holds the provider's [`ProjFS::Session`](crate::ProjFS::Session) between
+/// `StartDirectoryEnumeration` and `EndDirectoryEnumeration`.
+pub struct Sessions<S> {
+  map: DashMap<Guid, Arc<Mutex<Entry<S>>>>,
+  config: SessionConfig,
+  /// When idle sessions were last swept, held while a session is admitted so the cap holds.
+  swept: Mutex<Instant>,
+}
+
+impl<S> Default for Sessions<S> {
+  fn default() -> Self {
+    Self::new(SessionConfig::default())
+  }
+}
+
+impl<S> Sessions<S> {
+  pub fn new(config: SessionConfig) -> Self {
+    Self { map: DashMap::new(), config, swept: Mutex::new(Instant::now()) }
+  }
+
+  pub fn config(&self) -> &SessionConfig {
+    &self.config
+  }
+
+  /// Registers a new session, replacing any previous one with the same `id`.
+  ///
+  /// Expired sessions are swept first, and if the instance is still at `max_sessions` the new session is refused,
+  /// live sessions are never dropped to make room as their enumerations could not go on.
+  pub fn insert(&self, id: Guid, path: PathBuf, state: S) -> std::io::Result<()> {
+    let mut swept = self.swept.lock().unwrap_or_else(|e| e.into_inner());
+    self.sweep_locked(&mut swept);
+    if let Some(max) = self.config.max_sessions {
+      if self.map.len() >= max.max(1) && !self.map.contains_key(&id) {
+        return Err(std::io::Error::new(std::io::ErrorKind::ResourceBusy, "too many directory enumerations in progress"))
+      }
+    }
+    let now = Instant::now();
+    let info = SessionInfo { id, path, started: now, last_used: now, calls: 0 };
+    self.map.insert(id, Arc::new(Mutex::new(Entry { info, state })));
+    Ok(())
+  }
+
+  /// Runs `f` on the state of session `id`, returns `None` if there is no such session.
+  ///
+  /// The map itself is not locked while `f` runs, so other sessions make progress.
+  /// Idle sessions are swept along once `idle_timeout` passed since the last sweep.
+  pub fn with<R, F: FnOnce(&mut S) -> R>(&self, id: &Guid, f: F) -> Option<R> {
+    if let (Some(timeout), Ok(mut swept)) = (self.config.idle_timeout, self.swept.try_lock()) {
+      if swept.elapsed() >= timeout {
+        self.sweep_locked(&mut swept);
+      }
+    }
+    let entry = self.map.get(id)?.value().clone();
+    let mut entry = entry.lock().unwrap_or_else(|e| e.into_inner());
+    entry.info.last_used = Instant::now();
+    entry.info.calls += 1;
+    Some(f(&mut entry.state))
+  }
+
+  /// Drops session `id`, returns whether it was live.
+  pub fn remove(&self, id: &Guid) -> bool {
+    self.map.remove(id).is_some()
+  }
+
+  /// Drops every session idle for longer than `idle_timeout`, returns how many were dropped.
+  pub fn sweep(&self) -> usize {
+    let mut swept = self.swept.lock().unwrap_or_else(|e| e.into_inner());
+    self.sweep_locked(&mut swept)
+  }
+
+  fn sweep_locked(&self, swept: &mut Instant) -> usize {
+    let timeout = match self.config.idle_timeout {
+      Some(timeout) => timeout,
+      None => return 0,
+    };
+    let before = self.map.len();
+    let now = Instant::now();
+    *swept = now;
+    self.map.retain(|_, entry| match entry.try_lock() {
+      Ok(entry) => now.duration_since(entry.info.last_used) <= timeout,
+      // busy sessions are in use right now
+      Err(_) => true,
+    });
+    before.saturating_sub(self.map.len())
+  }
+
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+
+  pub fn contains(&self, id: &Guid) -> bool {
+    self.map.contains_key(id)
+  }
+
+  pub fn info(&self, id: &Guid) -> Option<SessionInfo> {
+    let entry = self.map.get(id)?.value().clone();
+    let entry = entry.lock().unwrap_or_else(|e| e.into_inner());
+    Some(entry.info.clone())
+  }
+
+  /// Snapshot of all live sessions, sessions busy in a callback are skipped.
+  pub fn infos(&self) -> Vec<SessionInfo> {
+    self.map.iter().filter_map(|entry| {
+      entry.value().try_lock().ok().map(|entry| entry.info.clone())
+    }).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sessions(idle_timeout: Option<Duration>, max_sessions: Option<usize>) -> Sessions<usize> {
+    Sessions::new(SessionConfig { idle_timeout, max_sessions })
+  }
+
+  #[test]
+  fn refuses_sessions_over_the_cap() {
+    let sessions = sessions(None, Some(2));
+    let ids: Vec<_> = (0..3).map(|_| Guid::new_v4()).collect();
+    sessions.insert(ids[0], PathBuf::from("a"), 0).unwrap();
+    sessions.insert(ids[1], PathBuf::from("b"), 1).unwrap();
+    let e = sessions.insert(ids[2], PathBuf::from("c"), 2).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::ResourceBusy);
+    // live sessions are kept, whether used lately or not
+    assert_eq!(sessions.with(&ids[0], |i| *i), Some(0));
+    assert_eq!(sessions.with(&ids[1], |i| *i), Some(1));
+    // restarting a session does not count twice
+    sessions.insert(ids[1], PathBuf::from("b"), 3).unwrap();
+    assert!(sessions.remove(&ids[0]));
+    sessions.insert(ids[2], PathBuf::from("c"), 2).unwrap();
+    assert_eq!(sessions.len(), 2);
+  }
+
+  #[test]
+  fn idle_sessions_expire() {
+    let sessions = sessions(Some(Duration::from_millis(20)), Some(1));
+    let (a, b) = (Guid::new_v4(), Guid::new_v4());
+    sessions.insert(a, PathBuf::from("a"), 0).unwrap();
+    assert!(sessions.insert(b, PathBuf::from("b"), 1).is_err());
+    std::thread::sleep(Duration::from_millis(30));
+    // the expired session makes room
+    sessions.insert(b, PathBuf::from("b"), 1).unwrap();
+    assert_eq!(sessions.with(&a, |i| *i), None);
+    assert_eq!(sessions.info(&b).unwrap().path, PathBuf::from("b"));
+    // lookups sweep too, sessions whose end never arrives go without new ones coming
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(sessions.with(&a, |i| *i), None);
+    assert!(sessions.is_empty());
+  }
+
+  #[test]
+  fn cap_holds_across_threads() {
+    let sessions = sessions(None, Some(4));
+    let sessions = &sessions;
+    let admitted = std::thread::scope(|scope| {
+      let inserts: Vec<_> = (0..32).map(|i| scope.spawn(move || sessions.insert(Guid::new_v4(), PathBuf::from("dir"), i).is_ok())).collect();
+      inserts.into_iter().map(|i| i.join().unwrap()).filter(|&admitted| admitted).count()
+    });
+    assert_eq!(admitted, 4);
+    assert_eq!(sessions.len(), 4);
+  }
+}
@@ -0,0 +1,265 @@
+use std::path::Path;
+use crate::*;
+
+pub fn guid_from_raw(guid: sys::GUID) -> Guid {
+  Guid::from_fields(guid.Data1, guid.Data2, guid.Data3, &guid.Data4).expect("guid data4 len")
+}
+
+pub fn guid_to_raw(guid: Guid) -> sys::GUID {
+  let fields = guid.as_fields();
+  sys::GUID {
+    Data1: fields.0,
+    Data2: fields.1,
+    Data3: fields.2,
+    Data4: fields.3.clone(),
+  }
+}
+
+pub fn io_error_to_raw(e: std::io::Error) -> sys::HRESULT {
+  use std::io::ErrorKind::*;
+  if let Some(i) = e.raw_os_error() {
+    return hresult_from_win32(i)
+  }
+  match e.kind() {
+    WouldBlock => hresult_from_win32(sys::IO_ERROR_IO_PENDING as i32),
+    NotFound => hresult_from_win32(sys::IO_ERROR_FILE_NOT_FOUND as i32),
+    InvalidData => hresult_from_win32(ERROR_INVALID_DATA),
+    ResourceBusy => hresult_from_win32(ERROR_BUSY),
+    TimedOut => hresult_from_win32(ERROR_TIMEOUT),
+    _ => -1,
+  }
+}
+
+/// Runs a callback body, reporting a panic as `E_UNEXPECTED` rather than unwinding into ProjFS.
+fn guarded(f: impl FnOnce() -> sys::HRESULT) -> sys::HRESULT {
+  std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(E_UNEXPECTED)
+}
+
+impl Into<sys::PRJ_FILE_BASIC_INFO> for &FileBasicInfo {
+  fn into(self) -> sys::PRJ_FILE_BASIC_INFO {
+    sys::PRJ_FILE_BASIC_INFO {
+      IsDirectory: if self.is_dir { 1 } else { 0 },
+      ChangeTime: self.changed.into(),
+      CreationTime: self.created.into(),
+      LastAccessTime: self.accessed.into(),
+      LastWriteTime: self.writed.into(),
+      FileSize: self.file_size as i64,
+      FileAttributes: self.attrs,
+    }
+  }
+}
+
+struct AlignedBuffer(*mut std::ffi::c_void, usize);
+impl AlignedBuffer {
+  pub fn new(context: sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, len: usize) -> Self {
+    let raw = unsafe { sys::PrjAllocateAlignedBuffer(context, len as u64) };
+    Self(raw, len)
+  }
+  pub fn as_slice_mut(&mut self) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(self.0 as *mut _, self.1) }
+  }
+}
+impl Drop for AlignedBuffer {
+  fn drop(&mut self) {
+    unsafe { sys::PrjFreeAlignedBuffer(self.0) }
+  }
+}
+
+impl DirEntryBuffer for DirHandle {
+  fn fill(&mut self, entry: &FileBasicInfo) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    let mut basic_info = entry.into();
+    let file_name: Vec<u16> = entry.file_name.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let hr = unsafe { sys::PrjFillDirEntryBuffer(file_name.as_ptr(), &mut basic_info, *self) };
+    if hr == 0 {
+      Ok(())
+    } else if hr == hresult_from_win32(ERROR_INSUFFICIENT_BUFFER) {
+      Err(std::io::Error::from_raw_os_error(ERROR_INSUFFICIENT_BUFFER))
+    } else {
+      Err(std::io::Error::from_raw_os_error(hr))
+    }
+  }
+}
+
+mod helper {
+  #![allow(non_snake_case)]
+  use crate::sys::*;
+  use super::*;
+  pub trait RawProjFS: ProjFS + Sized {
+    unsafe extern "C" fn StartDirectoryEnumerationCallback(arg1: *const PRJ_CALLBACK_DATA, arg2: *const GUID) -> HRESULT {
+      guarded(|| {
+        let data = arg1.as_ref().unwrap();
+        let context = (data.InstanceContext as *mut Context<Self>).as_ref().unwrap();
+        let id = guid_from_raw(*arg2);
+        let result = context.this.start_dir_enum(id, ProjPath::from_ptr(data.FilePathName), VersionInfo::from_ptr(data.VersionInfo))
+          .and_then(|session| context.sessions.insert(id, ProjPath::from_ptr(data.FilePathName).to_path_buf(), session));
+        match result {
+          Ok(()) => 0,
+          Err(e) => io_error_to_raw(e)
+        }
+        // ERROR_FILE_NOT_FOUND
+      })
+    }
+    unsafe extern "C" fn EndDirectoryEnumerationCallback(arg1: *const PRJ_CALLBACK_DATA, arg2: *const GUID) -> HRESULT {
+      guarded(|| {
+        let data = arg1.as_ref().unwrap();
+        let context = (data.InstanceContext as *mut Context<Self>).as_ref().unwrap();
+        let id = guid_from_raw(*arg2);
+        context.sessions.remove(&id);
+        let result = context.this.end_dir_enum(id, VersionInfo::from_ptr(data.VersionInfo));
+        match result {
+          Ok(()) => 0,
+          Err(e) => io_error_to_raw(e)
+        }
+      })
+    }
+    unsafe extern "C" fn GetDirectoryEnumerationCallback(
+      arg1: *const PRJ_CALLBACK_DATA,
+      arg2: *const GUID,
+      arg3: PCWSTR,
+      arg4: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+    ) -> HRESULT {
+      guarded(|| {
+        let data = arg1.as_ref().unwrap();
+        let context = (data.InstanceContext as *mut Context<Self>).as_ref().unwrap();
+        let id = guid_from_raw(*arg2);
+        let mut handle = arg4;
+        let result = context.sessions.with(&id, |session| context.this.get_dir_enum(
+          session,
+          id,
+          ProjPath::from_ptr(data.FilePathName),
+          CallbackDataFlags::from_bits_truncate(data.Flags),
+          VersionInfo::from_ptr(data.VersionInfo),
+          if arg3 == std::ptr::null() { None } else { Some(ProjPath::from_ptr(arg3)) },
+          &mut handle
+        )).unwrap_or_else(|| Err(std::io::Error::from_raw_os_error(ERROR_INVALID_PARAMETER)));
+        match result {
+          Ok(()) => 0,
+          Err(e) => io_error_to_raw(e)
+        }
+        // ERROR_INSUFFICIENT_BUFFER
+      })
+    }
+    unsafe extern "C" fn GetPlaceholderInfoCallback(arg1: *const PRJ_CALLBACK_DATA) -> HRESULT {
+      guarded(|| {
+        let data = arg1.as_ref().unwrap();
+        let this = &(data.InstanceContext as *mut Context<Self>).as_ref().unwrap().this;
+        match this.get_metadata(ProjPath::from_ptr(data.FilePathName), VersionInfo::from_ptr(data.VersionInfo)) {
+          Ok(result) => {
+            let mut placeholder_info: sys::PRJ_PLACEHOLDER_INFO = std::mem::zeroed();
+            placeholder_info.FileBasicInfo = (&result).into();
+            if let Some(content_id) = &result.content_id {
+              placeholder_info.VersionInfo = crate::version_info(content_id);
+            }
+            PrjWritePlaceholderInfo(data.NamespaceVirtualizationContext, data.FilePathName, &placeholder_info, std::mem::size_of_val(&placeholder_info) as u32)
+          },
+          Err(e) => io_error_to_raw(e),
+        }
+        // ERROR_FILE_NOT_FOUND
+      })
+    }
+    unsafe extern "C" fn GetFileDataCallback(arg1: *const PRJ_CALLBACK_DATA, arg2: UINT64, arg3: UINT32) -> HRESULT {
+      guarded(|| {
+        let data = arg1.as_ref().unwrap();
+        let this = &(data.InstanceContext as *mut Context<Self>).as_ref().unwrap().this;
+        let mut buf = AlignedBuffer::new(data.NamespaceVirtualizationContext, arg3 as usize);
+        let result = crate::with_data_stream(guid_from_raw(data.DataStreamId), || this.read(ProjPath::from_ptr(data.FilePathName), VersionInfo::from_ptr(data.VersionInfo), arg2, buf.as_slice_mut()));
+        match result {
+          Ok(()) => {
+            sys::PrjWriteFileData(data.NamespaceVirtualizationContext, &data.DataStreamId, buf.0, arg2, arg3)
+          },
+          Err(e) => io_error_to_raw(e)
+        }
+        // S_OK, ERROR_IO_PENDING
+      })
+    }
+    // unsafe extern "C" fn QueryFileNameCallback(arg1: *const PRJ_CALLBACK_DATA) -> HRESULT; // ERROR_FILE_NOT_FOUND
+    // unsafe extern "C" fn NotificationCallback(
+    //   arg1: *const PRJ_CALLBACK_DATA,
+    //   arg2: BOOLEAN,
+    //   arg3: PRJ_NOTIFICATION,
+    //   arg4: PCWSTR,
+    //   arg5: *mut PRJ_NOTIFICATION_PARAMETERS,
+    // ) -> HRESULT;
+    // unsafe extern "C" fn CancelCommandCallback(arg1: *const PRJ_CALLBACK_DATA);
+  }
+  impl<T: ProjFS + Sync> RawProjFS for T { }
+}
+
+fn trait_to_table<T: helper::RawProjFS>() -> sys::PRJ_CALLBACKS {
+  let cb = sys::PRJ_CALLBACKS {
+    StartDirectoryEnumerationCallback: Some(T::StartDirectoryEnumerationCallback),
+    EndDirectoryEnumerationCallback: Some(T::EndDirectoryEnumerationCallback),
+    GetDirectoryEnumerationCallback: Some(T::GetDirectoryEnumerationCallback),
+    GetPlaceholderInfoCallback: Some(T::GetPlaceholderInfoCallback),
+    GetFileDataCallback: Some(T::GetFileDataCallback),
+    QueryFileNameCallback: None, //Some(T::QueryFileNameCallback),
+    NotificationCallback: None, //Some(T::NotificationCallback),
+    CancelCommandCallback: None, //Some(T::CancelCommandCallback),
+  };
+  cb
+}
+
+struct Context<T: ProjFS> {
+  this: Box<T>,
+  sessions: Sessions<T::Session>,
+}
+
+pub struct Instance<T: ProjFS> {
+  raw: sys::PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+  context: *mut Context<T>,
+  cb: sys::PRJ_CALLBACKS,
+}
+
+impl<T: ProjFS> Instance<T> {
+  /// Live directory enumeration sessions of this instance.
+  pub fn sessions(&self) -> &Sessions<T::Session> {
+    unsafe { &(*self.context).sessions }
+  }
+}
+
+pub fn start_proj_virtualization<P: AsRef<Path>, T: ProjFS + Sync>(path: P, this: Box<T>) -> Result<Instance<T>, sys::HRESULT> {
+  start_proj_virtualization_with(path, this, SessionConfig::default())
+}
+
+pub fn start_proj_virtualization_with<P: AsRef<Path>, T: ProjFS + Sync>(path: P, this: Box<T>, config: SessionConfig) -> Result<Instance<T>, sys::HRESULT> {
+  use std::os::windows::prelude::*;
+  let mut instance = Instance {
+    raw: std::ptr::null_mut(),
+    context: Box::leak(Box::new(Context { this, sessions: Sessions::new(config) })),
+    cb: trait_to_table::<T>()
+  };
+  let path = path.as_ref().canonicalize().unwrap();
+  let path_str: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+  let result = unsafe {
+    // let id = uuid::Uuid::new_v5(uuid::Uuid::NAMESPACE_URL, std::slice::from_raw_parts(path_str.as_ptr(), path_str.len()*2));
+    let id = uuid::Uuid::new_v4();
+    sys::PrjMarkDirectoryAsPlaceholder(
+      path_str.as_ptr(),
+      std::ptr::null(),
+      std::ptr::null(),
+      &guid_to_raw(id),
+    );
+    sys::PrjStartVirtualizing(
+      path_str.as_ptr(),
+      &instance.cb,
+      instance.context as *const std::ffi::c_void,
+      std::ptr::null(),
+      &mut instance.raw
+    )
+  };
+  if result == 0 {
+    Ok(instance)
+  } else {
+    Err(result)
+  }
+}
+
+impl<T: ProjFS> Drop for Instance<T> {
+  fn drop(&mut self) {
+    if self.raw != std::ptr::null_mut() {
+      unsafe { sys::PrjStopVirtualizing(self.raw) }
+    }
+    drop(unsafe { Box::from_raw(self.context) });
+  }
+}
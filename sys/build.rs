@@ -55,7 +55,9 @@ fn gen_bindings() { }
 fn main() {
   // Tell cargo to tell rustc to link the system bzip2
   // shared library.
-  println!("cargo:rustc-link-lib=ProjectedFSLib");
+  if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
+    println!("cargo:rustc-link-lib=ProjectedFSLib");
+  }
   if let Some(arch) = target_arch() {
     let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     println!("cargo:rustc-link-search={}/lib/{}", manifest_dir, arch);